@@ -0,0 +1,14 @@
+-- Superseded votes, moved out of vote_history by `y compact-votes`.
+-- vote_history keeps every vote the views still depend on, so nothing reads
+-- from this table. `vote_rowid` is the rowid the vote had in vote_history. It is
+-- not unique: SQLite can reuse the rowid of a deleted vote.
+create table vote_history_archive (
+      vote_rowid integer   not null
+    , user_id    integer   not null references users (id)
+    , tag_id     integer   not null references tags (id)
+    , post_id    integer   not null references posts (id)
+    , note_id    integer   references posts (id)
+    , direction  integer   not null
+    , created    TIMESTAMP not null
+    , archived   TIMESTAMP not null default CURRENT_TIMESTAMP
+);
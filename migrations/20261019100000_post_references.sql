@@ -0,0 +1,8 @@
+-- `#post:123` references in the content of a post, see src/lib/references.rs
+create table post_references (
+      post_id            integer not null references posts (id)
+    , referenced_post_id integer not null references posts (id)
+    , primary key (post_id, referenced_post_id)
+);
+
+create index post_references_referenced_post_id on post_references (referenced_post_id);
@@ -0,0 +1,4 @@
+-- users.secret becomes the SHA-256 hash of the secret (64 hex digits). The
+-- existing plaintext secrets are hashed on startup, see `hash_plaintext_secrets`
+-- in src/db_setup.rs, since SQLite has no SHA-256 function.
+alter table users rename column secret to secret_hash;
@@ -0,0 +1,19 @@
+-- One row per signed in device. The session cookie holds the token, only its
+-- SHA-256 hash is stored. Revoking a session deletes its row.
+create table sessions (
+      id         integer   not null primary key -- rowid
+    , user_id    integer   not null references users (id)
+    , token_hash text      not null unique
+    , created    TIMESTAMP not null default CURRENT_TIMESTAMP
+    , last_used  TIMESTAMP not null default CURRENT_TIMESTAMP
+);
+
+create index sessions_user_id on sessions (user_id);
+
+-- Short-lived, single-use links (and QR codes) from the options page that sign
+-- in another device.
+create table device_links (
+      token_hash text      not null primary key
+    , user_id    integer   not null references users (id)
+    , expires    TIMESTAMP not null
+);
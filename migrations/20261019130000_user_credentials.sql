@@ -0,0 +1,6 @@
+-- Optional username and password. Usernames are stored lowercase, passwords as
+-- argon2 PHC strings. Anonymous users have neither.
+alter table users add column username text;
+alter table users add column password_hash text;
+
+create unique index users_username on users (username);
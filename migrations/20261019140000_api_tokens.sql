@@ -0,0 +1,14 @@
+-- Bearer tokens for the API, created on the options page. Only the SHA-256 hash
+-- of the token is stored. `scopes` is a comma separated subset of read,vote,post.
+-- Tokens without `expires` don't expire.
+create table api_tokens (
+      id         integer   not null primary key -- rowid
+    , user_id    integer   not null references users (id)
+    , name       text      not null
+    , token_hash text      not null unique
+    , scopes     text      not null
+    , created    TIMESTAMP not null default CURRENT_TIMESTAMP
+    , expires    TIMESTAMP
+);
+
+create index api_tokens_user_id on api_tokens (user_id);
@@ -0,0 +1,3 @@
+-- Optional name shown on posts and notes. Users without one are shown with a
+-- pseudonym generated from their id, see src/lib/pseudonym.rs.
+alter table users add column display_name text;
@@ -0,0 +1,17 @@
+-- Tags and users that a user follows. Their posts make up the personalized
+-- home feed.
+create table tag_follows (
+      user_id integer   not null references users (id)
+    , tag_id  integer   not null references tags (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, tag_id)
+);
+
+create table user_follows (
+      user_id          integer   not null references users (id)
+    , followed_user_id integer   not null references users (id)
+    , created          TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, followed_user_id)
+);
+
+create index user_follows_followed_user_id on user_follows (followed_user_id);
@@ -0,0 +1,16 @@
+-- In-app notifications, see src/notifications.rs. `kind` is one of reply,
+-- top_note and own_top_note. `note_id` is the reply or note the notification is
+-- about, `post_id` the post it belongs to. Unread while `read_at` is null.
+create table notifications (
+      id      integer   not null primary key -- rowid
+    , user_id integer   not null references users (id)
+    , kind    text      not null
+    , tag_id  integer   not null references tags (id)
+    , post_id integer   not null references posts (id)
+    , note_id integer   not null references posts (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , read_at TIMESTAMP
+);
+
+-- users are notified of each event once
+create unique index notifications_event on notifications (user_id, kind, post_id, note_id);
@@ -0,0 +1,9 @@
+-- Private bookmarks of posts. `tag_id` is the tag the post was viewed in, for
+-- the link on the saved posts page.
+create table bookmarks (
+      user_id integer   not null references users (id)
+    , post_id integer   not null references posts (id)
+    , tag_id  integer   not null references tags (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, post_id)
+);
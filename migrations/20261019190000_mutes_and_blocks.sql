@@ -0,0 +1,17 @@
+-- Tags a user muted and users they blocked. Their posts are hidden from that
+-- user, see src/content_filter.rs.
+create table tag_mutes (
+      user_id integer   not null references users (id)
+    , tag_id  integer   not null references tags (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, tag_id)
+);
+
+create table user_blocks (
+      user_id         integer   not null references users (id)
+    , blocked_user_id integer   not null references users (id)
+    , created         TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, blocked_user_id)
+);
+
+create index user_blocks_blocked_user_id on user_blocks (blocked_user_id);
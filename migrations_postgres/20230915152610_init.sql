@@ -0,0 +1,170 @@
+-- Postgres port of migrations/20230915152610_init.sql
+-- SQLite's implicit rowid is an explicit `id` column here, and the "bare column in an
+-- aggregate" trick of the SQLite views is expressed with `distinct on`.
+
+create table users (
+    id      bigserial not null primary key
+  , secret  text      not null unique
+  , created timestamp not null default current_timestamp
+);
+
+create table tags (
+    id  bigserial not null primary key
+  , tag text      not null
+  , unique (tag)
+);
+
+-- if a question is a top-level question -> no parent and question_id = id
+-- if a question is a reply -> parent is the id of the post it replies to
+-- -- if it reuses a question -> that question is its question_id
+-- -- if it doesn't reuse a question -> question_id = id
+create table posts (
+      id          bigserial primary key
+    , parent_id   bigint    references posts (id)
+    , content     text      not null
+    , question_id bigint    references posts (id)
+    , author_id   bigint    not null references users (id)
+    , created     timestamp not null default current_timestamp
+);
+
+create table vote_history (
+      id        bigserial not null primary key
+    , user_id   bigint    not null references users (id)
+    , tag_id    bigint    not null references tags (id)
+    , post_id   bigint    not null references posts (id)
+    , note_id   bigint    references posts (id)
+    , direction bigint    not null
+    , created   timestamp not null default current_timestamp
+);
+
+create index posts_parent_id on posts (parent_id);
+create index vote_history_tag_id_post_id on vote_history (tag_id, post_id);
+create index vote_history_user_id_tag_id_post_id on vote_history (user_id, tag_id, post_id);
+
+
+-- The current (latest) votes for all users on all posts
+-- If the user has cleared their vote, no row is returned.
+create view current_vote as
+with latest as (
+    select distinct on (user_id, tag_id, post_id)
+        user_id
+      , tag_id
+      , post_id
+      , direction
+      , created
+    from vote_history
+    order by user_id, tag_id, post_id, created desc, id desc
+)
+-- The latest vote might be zero, so in that case we don't return a record for this user and post
+select *
+from latest
+where direction != 0;
+
+
+-- current_tally counts the latest votes, regardless of whether they are informed or not.
+create view current_tally as
+select
+    tag_id
+  , post_id
+  , sum(
+      case direction
+        when 1 then 1
+        else 0
+      end
+    ) as upvotes
+  , count(*) as votes
+from current_vote
+group by tag_id, post_id;
+
+
+create view current_informed_tally as
+with current_informed_votes as (
+    select distinct on (user_id, tag_id, post_id, note_id)
+        user_id
+      , tag_id
+      , post_id
+      , note_id
+      , direction
+      , created
+    from vote_history
+    where note_id is not null
+    order by user_id, tag_id, post_id, note_id, created desc, id desc
+)
+, informed_tally as (
+  select
+      tag_id
+    , post_id
+    , note_id
+    , sum(
+      case
+        when direction = 1 then 1
+        else 0
+      end
+    ) as upvotes
+    , count(*) as votes
+  from current_informed_votes
+  -- The latest vote might be zero, so in that case we don't return a record for this user and post
+  where direction != 0
+  group by tag_id, post_id, note_id
+)
+, first_votes_on_notes as (
+  select
+        user_id
+      , tag_id
+      , post_id
+      , note_id
+      , min(id) as first_vote_on_this_note_id
+  from vote_history
+  where note_id is not null
+  group by user_id, tag_id, post_id, note_id
+)
+, votes_before_note as (
+    select
+        params.tag_id
+      , params.post_id
+      , params.note_id
+      , vote_history.id
+      , vote_history.user_id
+      , vote_history.direction
+      , vote_history.created
+      , params.upvotes as upvotes_given_shown_this_note
+      , params.votes as votes_given_shown_this_note
+    from
+       informed_tally params
+       join vote_history on (
+           vote_history.tag_id = params.tag_id
+       and vote_history.post_id = params.post_id
+       )
+    left outer join first_votes_on_notes on (
+           first_votes_on_notes.tag_id = params.tag_id
+       and first_votes_on_notes.post_id = params.post_id
+       and first_votes_on_notes.note_id = params.note_id
+       and first_votes_on_notes.user_id = vote_history.user_id
+    )
+    where first_vote_on_this_note_id is null or vote_history.id < first_vote_on_this_note_id
+)
+, last_votes_before_note as (
+    select distinct on (tag_id, post_id, note_id, user_id)
+        *
+    from votes_before_note
+    order by tag_id, post_id, note_id, user_id, created desc, id desc
+)
+select
+    tag_id
+  , post_id
+  , note_id
+  , sum(
+    case direction
+      when 1 then 1
+      else 0
+    end
+  ) as upvotes_given_not_shown_this_note
+  , count(*) as votes_given_not_shown_this_note
+
+  , upvotes_given_shown_this_note
+  , votes_given_shown_this_note
+from last_votes_before_note
+group by tag_id, post_id, note_id, upvotes_given_shown_this_note, votes_given_shown_this_note;
+
+
+insert into tags (id, tag) values (0, 'global');
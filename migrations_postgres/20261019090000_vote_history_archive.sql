@@ -0,0 +1,12 @@
+-- Postgres port of migrations/20261019090000_vote_history_archive.sql
+-- `vote_id` is the id the vote had in vote_history.
+create table vote_history_archive (
+      vote_id   bigint    not null primary key
+    , user_id   bigint    not null references users (id)
+    , tag_id    bigint    not null references tags (id)
+    , post_id   bigint    not null references posts (id)
+    , note_id   bigint    references posts (id)
+    , direction bigint    not null
+    , created   timestamp not null
+    , archived  timestamp not null default current_timestamp
+);
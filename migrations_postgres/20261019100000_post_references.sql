@@ -0,0 +1,8 @@
+-- Postgres port of migrations/20261019100000_post_references.sql
+create table post_references (
+      post_id            bigint not null references posts (id)
+    , referenced_post_id bigint not null references posts (id)
+    , primary key (post_id, referenced_post_id)
+);
+
+create index post_references_referenced_post_id on post_references (referenced_post_id);
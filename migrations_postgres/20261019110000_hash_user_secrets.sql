@@ -0,0 +1,6 @@
+-- Postgres port of migrations/20261019110000_hash_user_secrets.sql
+-- Postgres can hash the existing secrets right away.
+alter table users rename column secret to secret_hash;
+update users
+set secret_hash = encode(sha256(convert_to(secret_hash, 'UTF8')), 'hex')
+where length(secret_hash) != 64;
@@ -0,0 +1,16 @@
+-- Postgres port of migrations/20261019120000_sessions.sql
+create table sessions (
+      id         bigserial not null primary key
+    , user_id    bigint    not null references users (id)
+    , token_hash text      not null unique
+    , created    timestamp not null default current_timestamp
+    , last_used  timestamp not null default current_timestamp
+);
+
+create index sessions_user_id on sessions (user_id);
+
+create table device_links (
+      token_hash text      not null primary key
+    , user_id    bigint    not null references users (id)
+    , expires    timestamp not null
+);
@@ -0,0 +1,5 @@
+-- Postgres port of migrations/20261019130000_user_credentials.sql
+alter table users add column username text;
+alter table users add column password_hash text;
+
+create unique index users_username on users (username);
@@ -0,0 +1,12 @@
+-- Postgres port of migrations/20261019140000_api_tokens.sql
+create table api_tokens (
+      id         bigserial not null primary key
+    , user_id    bigint    not null references users (id)
+    , name       text      not null
+    , token_hash text      not null unique
+    , scopes     text      not null
+    , created    timestamp not null default current_timestamp
+    , expires    timestamp
+);
+
+create index api_tokens_user_id on api_tokens (user_id);
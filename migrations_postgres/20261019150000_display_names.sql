@@ -0,0 +1,2 @@
+-- Postgres port of migrations/20261019150000_display_names.sql
+alter table users add column display_name text;
@@ -0,0 +1,16 @@
+-- Postgres port of migrations/20261019160000_follows.sql
+create table tag_follows (
+      user_id bigint    not null references users (id)
+    , tag_id  bigint    not null references tags (id)
+    , created timestamp not null default current_timestamp
+    , primary key (user_id, tag_id)
+);
+
+create table user_follows (
+      user_id          bigint    not null references users (id)
+    , followed_user_id bigint    not null references users (id)
+    , created          timestamp not null default current_timestamp
+    , primary key (user_id, followed_user_id)
+);
+
+create index user_follows_followed_user_id on user_follows (followed_user_id);
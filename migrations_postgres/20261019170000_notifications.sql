@@ -0,0 +1,13 @@
+-- Postgres port of migrations/20261019170000_notifications.sql
+create table notifications (
+      id      bigserial not null primary key
+    , user_id bigint    not null references users (id)
+    , kind    text      not null
+    , tag_id  bigint    not null references tags (id)
+    , post_id bigint    not null references posts (id)
+    , note_id bigint    not null references posts (id)
+    , created timestamp not null default current_timestamp
+    , read_at timestamp
+);
+
+create unique index notifications_event on notifications (user_id, kind, post_id, note_id);
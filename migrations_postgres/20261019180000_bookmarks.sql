@@ -0,0 +1,8 @@
+-- Postgres port of migrations/20261019180000_bookmarks.sql
+create table bookmarks (
+      user_id bigint    not null references users (id)
+    , post_id bigint    not null references posts (id)
+    , tag_id  bigint    not null references tags (id)
+    , created timestamp not null default current_timestamp
+    , primary key (user_id, post_id)
+);
@@ -0,0 +1,16 @@
+-- Postgres port of migrations/20261019190000_mutes_and_blocks.sql
+create table tag_mutes (
+      user_id bigint    not null references users (id)
+    , tag_id  bigint    not null references tags (id)
+    , created timestamp not null default current_timestamp
+    , primary key (user_id, tag_id)
+);
+
+create table user_blocks (
+      user_id         bigint    not null references users (id)
+    , blocked_user_id bigint    not null references users (id)
+    , created         timestamp not null default current_timestamp
+    , primary key (user_id, blocked_user_id)
+);
+
+create index user_blocks_blocked_user_id on user_blocks (blocked_user_id);
@@ -1,3 +1,8 @@
+CREATE INDEX api_tokens_user_id on api_tokens (user_id);
+CREATE INDEX post_references_referenced_post_id on post_references (referenced_post_id);
+CREATE INDEX sessions_user_id on sessions (user_id);
+CREATE INDEX user_blocks_blocked_user_id on user_blocks (blocked_user_id);
+CREATE INDEX user_follows_followed_user_id on user_follows (followed_user_id);
 CREATE TABLE _sqlx_migrations (
     version BIGINT PRIMARY KEY,
     description TEXT NOT NULL,
@@ -6,33 +11,116 @@ CREATE TABLE _sqlx_migrations (
     checksum BLOB NOT NULL,
     execution_time BIGINT NOT NULL
 );
+CREATE TABLE api_tokens (
+      id         integer   not null primary key -- rowid
+    , user_id    integer   not null references users (id)
+    , name       text      not null
+    , token_hash text      not null unique
+    , scopes     text      not null
+    , created    TIMESTAMP not null default CURRENT_TIMESTAMP
+    , expires    TIMESTAMP
+);
+CREATE TABLE bookmarks (
+      user_id integer   not null references users (id)
+    , post_id integer   not null references posts (id)
+    , tag_id  integer   not null references tags (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, post_id)
+);
+CREATE TABLE device_links (
+      token_hash text      not null primary key
+    , user_id    integer   not null references users (id)
+    , expires    TIMESTAMP not null
+);
+CREATE TABLE notifications (
+      id      integer   not null primary key -- rowid
+    , user_id integer   not null references users (id)
+    , kind    text      not null
+    , tag_id  integer   not null references tags (id)
+    , post_id integer   not null references posts (id)
+    , note_id integer   not null references posts (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , read_at TIMESTAMP
+);
+CREATE TABLE post_references (
+      post_id            integer not null references posts (id)
+    , referenced_post_id integer not null references posts (id)
+    , primary key (post_id, referenced_post_id)
+);
 CREATE TABLE posts (
-    id integer primary key -- row id
-    , content text not null
-    , parent_id integer references posts (id) on delete cascade on update cascade -- nullable
-    , question_id integer references posts (id) on delete cascade on update cascade -- nullable
-        -- if a question is a top-level question -> no parent and question_id = id
-        -- if a question is a reply -> parent is the id of the post it replies to
-        -- -- if it reuses a question -> that question is its question_id
-        -- -- if it doesn't reuse a question -> question_id = id
-    , created TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
+      id          integer   primary key -- row id
+    , parent_id   integer   references posts (id)
+    , content     text      not null
+    , question_id integer   references posts (id)
+    , author_id   integer   not null references users (id)
+    , created     TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
 );
-CREATE TABLE users (
-  id integer not null primary key, -- rowid
-  secret text not null unique,
-  created TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
+CREATE TABLE sessions (
+      id         integer   not null primary key -- rowid
+    , user_id    integer   not null references users (id)
+    , token_hash text      not null unique
+    , created    TIMESTAMP not null default CURRENT_TIMESTAMP
+    , last_used  TIMESTAMP not null default CURRENT_TIMESTAMP
+);
+CREATE TABLE tag_follows (
+      user_id integer   not null references users (id)
+    , tag_id  integer   not null references tags (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, tag_id)
+);
+CREATE TABLE tag_mutes (
+      user_id integer   not null references users (id)
+    , tag_id  integer   not null references tags (id)
+    , created TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, tag_id)
 );
+CREATE TABLE tags (
+    id integer not null primary key
+  , tag text not null
+  , unique (tag)
+);
+CREATE TABLE user_blocks (
+      user_id         integer   not null references users (id)
+    , blocked_user_id integer   not null references users (id)
+    , created         TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, blocked_user_id)
+);
+CREATE TABLE user_follows (
+      user_id          integer   not null references users (id)
+    , followed_user_id integer   not null references users (id)
+    , created          TIMESTAMP not null default CURRENT_TIMESTAMP
+    , primary key (user_id, followed_user_id)
+);
+CREATE TABLE users (
+    id      integer   not null primary key -- rowid
+  , secret_hash  text      not null unique
+  , created TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
+, username text, password_hash text, display_name text);
 CREATE TABLE vote_history (
-    user_id not null references users (id) on delete cascade on update cascade
-    , post_id not null references posts (id) on delete cascade on update cascade
-    , note_id references posts (id) on delete cascade on update cascade
+      user_id   not null references users (id)
+    , tag_id    not null references tags (id) -- TODO rename
+    , post_id   not null references posts (id)
+    , note_id   references posts (id)
     , direction integer not null
-    , created TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
+    , created   TIMESTAMP not null DEFAULT CURRENT_TIMESTAMP
 );
-CREATE VIEW current_informed_tally as 
+CREATE TABLE vote_history_archive (
+      vote_rowid integer   not null
+    , user_id    integer   not null references users (id)
+    , tag_id     integer   not null references tags (id)
+    , post_id    integer   not null references posts (id)
+    , note_id    integer   references posts (id)
+    , direction  integer   not null
+    , created    TIMESTAMP not null
+    , archived   TIMESTAMP not null default CURRENT_TIMESTAMP
+);
+CREATE UNIQUE INDEX notifications_event on notifications (user_id, kind, post_id, note_id);
+CREATE UNIQUE INDEX users_username on users (username);
+CREATE VIEW current_informed_tally as
 with current_informed_votes as (
     SELECT
-      user_id
+        user_id
+      , tag_id
       , post_id
       , note_id
 
@@ -42,37 +130,49 @@ with current_informed_votes as (
       , max(created) AS created
     FROM vote_history
     where note_id is not null
-    GROUP BY 1,2, 3
-
+    GROUP BY 
+        user_id
+      , tag_id
+      , post_id
+      , note_id
 )
 , informed_tally as (
   select 
-    post_id
+      tag_id
+    , post_id
     , note_id
-    , sum(case when direction = 1 then 1 else 0 end) as upvotes
+    , sum(
+      case
+        when direction = 1 then 1
+        else 0
+      end
+    ) as upvotes
     , count(*) as votes
   from current_informed_votes
   -- The latest vote might be zero, so in that case we don't return a record for this user and post
   where direction != 0
-  group by 1, 2 
+  group by tag_id, post_id, note_id
 ),  
 first_votes_on_notes as (
   SELECT 
         user_id
+        , tag_id
         , post_id
         , note_id
         -- , direction
         , min(rowid) first_vote_on_this_note_rowid
   FROM vote_history
   WHERE note_id is not null
-  GROUP BY 1, 2, 3
+  GROUP BY user_id, tag_id, post_id, note_id
 )
 , votes_before_note as (
     select
-      params.post_id as p_post_id
+      params.tag_id as p_tag_id
+      , params.post_id as p_post_id
       , params.note_id as p_note_id
-      , first_votes_on_notes.post_id as f_post_id 
-      , first_votes_on_notes.post_id as f_note_id 
+      -- , first_votes_on_notes.tag_id as f_tag_id
+      -- , first_votes_on_notes.post_id as f_post_id
+      -- , first_votes_on_notes.note_id as f_note_id
       , first_votes_on_notes.first_vote_on_this_note_rowid
       , vote_history.rowid
       , vote_history.*
@@ -86,16 +186,18 @@ first_votes_on_notes as (
       , params.votes as votes_given_shown_this_note
     FROM 
        informed_tally params
-       join vote_history using (post_id)
+       join vote_history using (tag_id, post_id)
     LEFT OUTER JOIN first_votes_on_notes on (
-       first_votes_on_notes.post_id = params.post_id
+           first_votes_on_notes.tag_id = params.tag_id
+       and first_votes_on_notes.post_id = params.post_id
        and first_votes_on_notes.note_id = params.note_id
        and first_votes_on_notes.user_id = vote_history.user_id
     )
 )
 , last_votes_before_note as (
     select
-        p_post_id as post_id
+        p_tag_id as tag_id
+        , p_post_id as post_id
         , p_note_id as note_id
         , user_id
         , direction
@@ -104,78 +206,59 @@ first_votes_on_notes as (
         , votes_given_shown_this_note
         , max(created)
     from  votes_before_note
-    where 
-    before_note 
-    group by 1, 2, 3
+    where
+    before_note
+    group by p_tag_id, p_post_id, p_note_id, user_id
 )
 select
-  post_id
+    tag_id
+  , post_id
   , note_id
   , sum(
-    case when direction == 1
-    then 1 
-    else 0 end 
+    case direction
+      when 1 then 1
+      else 0
+    end 
   ) as upvotes_given_not_shown_this_note
   , count(*) as votes_given_not_shown_this_note
 
   , upvotes_given_shown_this_note
   , votes_given_shown_this_note
 from last_votes_before_note
-group by 1,2
-/* current_informed_tally(post_id,note_id,upvotes_given_not_shown_this_note,votes_given_not_shown_this_note,upvotes_given_shown_this_note,votes_given_shown_this_note) */;
+group by tag_id, post_id, note_id
+/* current_informed_tally(tag_id,post_id,note_id,upvotes_given_not_shown_this_note,votes_given_not_shown_this_note,upvotes_given_shown_this_note,votes_given_shown_this_note) */;
 CREATE VIEW current_tally as
 select
-  post_id
-  , sum(case when direction = 1 then 1 else 0 end) as upvotes
+    tag_id
+  , post_id
+  , sum(
+      case direction
+        when 1 then 1
+        else 0
+      end
+    ) as upvotes
   , count(*) as votes
   -- , sum(case when note_id is null and direction = 1 then 1 else 0 end) as upvotes_given_not_seen_any_note
   -- , sum(case when note_id is null then 1 else 0 end) as votes_given_not_seen_any_note
-from current_vote 
-group by 1
-/* current_tally(post_id,upvotes,votes) */;
+from current_vote
+group by tag_id, post_id
+/* current_tally(tag_id,post_id,upvotes,votes) */;
 CREATE VIEW current_vote as
 with latest as (
     SELECT
-      user_id
+        user_id
+      , tag_id
       , post_id
-      -- TODO: Check whether this is reliable behavior. From what I can tell, direction will be the direction from teh record
-      -- corresponding to max(created), that it, it will be the value of the user's latest vote
+
+      -- NOTE: direction will be the value of direction pulled from the same row that has max(created)
+      -- https://www.sqlite.org/lang_select.html#bareagg
       , direction
       , max(created) AS created
     FROM vote_history
-    GROUP BY 1,2
-
--- The latest vote might be zero, so in that case we don't return a record for this user and post
-) select * from latest where direction != 0
-/* current_vote(user_id,post_id,direction,created) */;
-CREATE VIEW probabilities_given_note as
-with parameters as (
-    select 
-        .85 as prior 
-        , 2 as priorWeight
-
+    GROUP BY user_id, post_id, tag_id
 )
-, given_not_shown_this_note as (
-    select 
-        *
-        , (cast(upvotes_given_not_shown_this_note + prior*priorWeight as float)) / (cast(votes_given_not_shown_this_note + priorWeight as float)) as p_given_not_shown_this_note
-    from current_informed_tally join parameters
-)
-, given_shown_this_note as (
-    select 
-        post_id
-        , note_id 
-        , upvotes_given_not_shown_this_note 
-        , votes_given_not_shown_this_note 
-        , p_given_not_shown_this_note 
-        , upvotes_given_shown_this_note 
-        , votes_given_shown_this_note  
-        , ( upvotes_given_shown_this_note + p_given_not_shown_this_note * priorWeight) / (cast(votes_given_shown_this_note + priorWeight as float)) as p_given_shown_this_note
-    from given_not_shown_this_note
-)
-select 
-    * 
-    -- , max("p(A=1|vA,sB)")
-    from given_shown_this_note
-    -- group by post_id
-/* probabilities_given_note(post_id,note_id,upvotes_given_not_shown_this_note,votes_given_not_shown_this_note,p_given_not_shown_this_note,upvotes_given_shown_this_note,votes_given_shown_this_note,p_given_shown_this_note) */;
+-- The latest vote might be zero, so in that case we don't return a record for this user and post
+select *
+from latest
+where direction != 0
+/* current_vote(user_id,tag_id,post_id,direction,created) */;
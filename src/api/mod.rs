@@ -1,125 +1,221 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
 use crate::constants::GLOBAL_TAG;
 
 use axum::{
     extract::{self, Path},
-    headers::{authorization::Bearer, Authorization},
-    Extension, Json, TypedHeader,
+    Extension, Json,
 };
 
 use common::{
     auth,
-    structs_api::{ApiCreatePost, ApiFrontpage, ApiPost, ApiPostPage, ApiVote},
+    axum_extractors::{AccountScope, ApiUser, OptionalApiUser, PostScope, ReadScope, VoteScope},
+    repository::{normalize_tag, normalize_tags, DynRepository},
+    structs_api::{
+        ApiBookmark, ApiBookmarks, ApiCreatePost, ApiFeed, ApiFeedPost, ApiFrontpage,
+        ApiNotifications, ApiPost, ApiPostPage, ApiSetBookmark, ApiTagVotes, ApiUserProfile,
+        ApiVote,
+    },
 };
-use sqlx::SqlitePool;
 
-use crate::{db, error::AppError};
+use crate::{
+    content_filter::ContentFilter,
+    error::AppError,
+    notifications::{notify_reply, TopNotes, NOTIFICATIONS_LIMIT},
+    pages::frontpage::load_home_feed,
+    pages::user::profile::load_profile,
+    probabilities,
+    validation::{validate_new_post, validate_post_exists, validate_tag, validate_vote},
+};
 
-pub async fn create_user(Extension(pool): Extension<SqlitePool>) -> Result<String, AppError> {
-    let user = auth::create_user(&pool).await?;
-    Ok(user.secret)
+pub async fn create_user(Extension(repo): Extension<DynRepository>) -> Result<String, AppError> {
+    let (_user, secret) = auth::create_user(repo.as_ref()).await?;
+    Ok(secret)
 }
 
-
+/// With a token, leaves out posts by users its user blocked
 pub async fn frontpage(
-    Extension(pool): Extension<SqlitePool>,
+    Extension(repo): Extension<DynRepository>,
+    OptionalApiUser { user, .. }: OptionalApiUser<ReadScope>,
 ) -> Result<Json<ApiFrontpage>, AppError> {
     let tag = GLOBAL_TAG;
-    let posts = db::get_posts_for_tag(tag, &pool).await?;
+    let filter = ContentFilter::load(user.as_ref(), repo.as_ref()).await?;
+    let posts = filter.posts(repo.get_posts_for_tag(tag).await?);
     Ok(Json(ApiFrontpage {
-        posts: posts
+        posts: posts.iter().map(ApiPost::from_post).collect(),
+    }))
+}
+
+pub async fn feed(
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<ReadScope>,
+) -> Result<Json<ApiFeed>, AppError> {
+    let filter = ContentFilter::load(Some(&user), repo.as_ref()).await?;
+    let feed = load_home_feed(Some(&user), &filter, repo.as_ref()).await?;
+    Ok(Json(ApiFeed {
+        personalized: feed.personalized,
+        posts: feed
+            .posts
             .iter()
-            .map(|post| ApiPost {
-                id: post.id,
-                content: post.content.clone(),
+            .map(|(tag, post)| ApiFeedPost {
+                tag: tag.clone(),
+                post: ApiPost::from_post(post),
             })
             .collect(),
     }))
 }
 
+/// With a token, also returns whether its user saved the post, and leaves out what its user
+/// blocked
 pub async fn view_post(
     Path(post_id): Path<i64>,
-    Extension(pool): Extension<SqlitePool>,
-) -> Result<Json<Option<ApiPostPage>>, AppError> {
-    let post = db::get_post(post_id, &pool).await?;
+    Extension(repo): Extension<DynRepository>,
+    OptionalApiUser { user, .. }: OptionalApiUser<ReadScope>,
+) -> Result<Json<ApiPostPage>, AppError> {
+    let Some(post) = repo.get_post(post_id).await? else {
+        return Err(AppError::NotFound(format!("Post {post_id} does not exist")));
+    };
     let tag = GLOBAL_TAG;
-    Ok(Json(match post {
-        Some(post) => {
-            let parent_context = db::get_transitive_parents(&post, &pool).await?;
-            let top_note = db::get_top_note(tag, post_id, &pool).await?;
-            let replies = db::get_replies(tag, post_id, &pool).await?;
-            Some(ApiPostPage {
-                parent_context: parent_context
-                    .iter()
-                    .rev()
-                    .map(|post| ApiPost {
-                        id: post.id,
-                        content: post.content.clone(),
-                    })
-                    .collect(),
-                post: ApiPost {
-                    id: post.id,
-                    content: post.content.clone(),
-                },
-                note: top_note.map(|note| ApiPost {
-                    id: note.id,
-                    content: note.content.clone(),
-                }),
-                replies: replies
-                    .iter()
-                    .map(|post| ApiPost {
-                        id: post.id,
-                        content: post.content.clone(),
-                    })
-                    .collect(),
-            })
-        }
+    let filter = ContentFilter::load(user.as_ref(), repo.as_ref()).await?;
+    let bookmarked = match &user {
+        Some(user) => Some(repo.get_bookmark(user.id, post_id).await?.is_some()),
         None => None,
+    };
+    let parent_context = filter.posts(repo.get_transitive_parents(&post).await?);
+    let top_note = probabilities::get_top_note(tag, post_id, repo.as_ref()).await?;
+    let note_blocked = top_note
+        .as_ref()
+        .is_some_and(|note| filter.is_blocked(note));
+    let top_note = top_note.filter(|_| !note_blocked);
+    let replies = filter.posts(repo.get_replies(tag, post_id).await?);
+    Ok(Json(ApiPostPage {
+        parent_context: parent_context
+            .iter()
+            .rev()
+            .map(ApiPost::from_post)
+            .collect(),
+        post: ApiPost::from_post(&post),
+        note: top_note.as_ref().map(ApiPost::from_post),
+        note_blocked,
+        replies: replies.iter().map(ApiPost::from_post).collect(),
+        bookmarked,
+    }))
+}
+
+pub async fn user_profile(
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<ReadScope>,
+) -> Result<Json<ApiUserProfile>, AppError> {
+    let profile = load_profile(&user, repo.as_ref()).await?;
+    Ok(Json(ApiUserProfile {
+        name: profile.name,
+        username: profile.username,
+        posts: profile.posts.iter().map(ApiPost::from_post).collect(),
+        top_notes: profile.top_notes.iter().map(ApiPost::from_post).collect(),
+        votes_per_tag: profile
+            .votes_per_tag
+            .into_iter()
+            .map(|(tag, votes)| ApiTagVotes { tag, votes })
+            .collect(),
+        votes_changed_after_note: profile.votes_changed_after_note,
+    }))
+}
+
+/// Does not mark the notifications as read, see [mark_notifications_read]
+pub async fn notifications(
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<ReadScope>,
+) -> Result<Json<ApiNotifications>, AppError> {
+    Ok(Json(ApiNotifications {
+        unread: repo.count_unread_notifications(user.id).await?,
+        notifications: repo.get_notifications(user.id, NOTIFICATIONS_LIMIT).await?,
     }))
 }
 
+pub async fn mark_notifications_read(
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<AccountScope>,
+) -> Result<(), AppError> {
+    repo.mark_notifications_read(user.id).await?;
+    Ok(())
+}
+
+pub async fn bookmarks(
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<ReadScope>,
+) -> Result<Json<ApiBookmarks>, AppError> {
+    let mut bookmarks = vec![];
+    for bookmark in repo.get_bookmarks(user.id).await? {
+        if let Some(post) = repo.get_post(bookmark.post_id).await? {
+            bookmarks.push(ApiBookmark::from_bookmark(bookmark, &post));
+        }
+    }
+    Ok(Json(ApiBookmarks { bookmarks }))
+}
+
+pub async fn set_bookmark(
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<AccountScope>,
+    extract::Json(payload): extract::Json<ApiSetBookmark>,
+) -> Result<(), AppError> {
+    if payload.bookmarked {
+        let mut tag = normalize_tag(payload.tag.trim_start_matches('#'));
+        if tag.is_empty() {
+            tag = GLOBAL_TAG.to_string();
+        }
+        validate_tag(&tag)?;
+        validate_post_exists("post_id", payload.post_id, repo.as_ref()).await?;
+        repo.bookmark_post(user.id, &tag, payload.post_id).await?;
+    } else {
+        repo.remove_bookmark(user.id, payload.post_id).await?;
+    }
+    Ok(())
+}
+
 // curl -v http://127.0.0.1:8000/api/v0/vote -d '{"post_id": 2, "note_id": 17, "direction": "Down"}' -H "Authorization: Bearer xxxxxxxxx" -H "Content-Type: application/json"
 pub async fn vote(
-    Extension(pool): Extension<SqlitePool>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<VoteScope>,
     extract::Json(payload): extract::Json<ApiVote>,
 ) -> Result<(), AppError> {
-    // TODO: is it possible to get user from baerer token using axum middleware?
-    let secret = bearer.token();
-    let user = auth::user_from_secret(secret, &pool)
-        .await?
-        .ok_or(anyhow!("Unauthorized"))?; // TODO: return proper HTTP header, by sending a
-
-    // TODO: better http status code if post/note doesn't exist
-    db::vote(
+    let mut tag = normalize_tag(payload.tag.trim_start_matches('#'));
+    if tag.is_empty() {
+        tag = GLOBAL_TAG.to_string();
+    }
+    validate_vote(&tag, payload.post_id, payload.note_id, repo.as_ref()).await?;
+
+    let top_notes = TopNotes::load(&tag, payload.post_id, repo.as_ref()).await;
+    repo.vote(
         user.id,
-        payload.tag.as_str(),
+        &tag,
         payload.post_id,
         payload.note_id,
         payload.direction.to_direction(),
-        &pool,
     )
     .await?;
+    top_notes.notify_changes(repo.as_ref()).await;
 
     Ok(())
 }
 
 pub async fn create_post(
-    Extension(pool): Extension<SqlitePool>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Extension(repo): Extension<DynRepository>,
+    ApiUser { user, .. }: ApiUser<PostScope>,
     extract::Json(payload): extract::Json<ApiCreatePost>,
 ) -> Result<(), AppError> {
-    // TODO: is it possible to get user from baerer token using axum middleware?
-    let secret = bearer.token();
-    let user = auth::user_from_secret(secret, &pool)
-        .await?
-        .ok_or(anyhow!("Unauthorized"))?; // TODO: return proper HTTP header, by sending a
+    let mut tags = normalize_tags(&payload.tags);
+    if tags.is_empty() {
+        tags.push(GLOBAL_TAG.to_string());
+    }
 
-    let tag = GLOBAL_TAG;
+    validate_new_post(&payload.content, &tags, payload.parent_id, repo.as_ref()).await?;
 
-    // TODO: better http status code if post/note doesn't exist
-    db::create_post(tag, payload.parent_id, &payload.content, user.id, &pool).await?;
+    let post_id = repo
+        .create_post(&tags, payload.parent_id, &payload.content, user.id)
+        .await?;
+    if let Some(parent_id) = payload.parent_id {
+        notify_reply(&tags[0], post_id, parent_id, user.id, repo.as_ref()).await;
+    }
 
     Ok(())
 }
@@ -0,0 +1,449 @@
+//! Online backups and restores of the SQLite database
+
+use std::ffi::{CStr, CString, OsString};
+use std::path::{Path, PathBuf};
+use std::ptr::{self, NonNull};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use libsqlite3_sys as ffi;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteLockingMode};
+use sqlx::{ConnectOptions, Connection, SqlitePool};
+use tracing::{error, info};
+
+use crate::command_line_args::{BackupArgs, DatabaseArgs};
+use crate::db_setup::{Database, SQLITE_MIGRATOR};
+
+const BACKUP_PREFIX: &str = "y-backup-";
+const BACKUP_SUFFIX: &str = ".sqlite";
+
+/// How often a backup step is retried while the source database is locked
+const BUSY_RETRIES: u32 = 100;
+
+/// Writes a snapshot to `output`, or to a new timestamped file in the backup directory. In
+/// the latter case, backups beyond the retention count are deleted afterwards.
+pub async fn backup_database(
+    database: &Database,
+    args: &BackupArgs,
+    output: Option<&Path>,
+) -> Result<PathBuf> {
+    let pool = database.sqlite_pool()?;
+
+    let destination = match output {
+        Some(output) => output.to_path_buf(),
+        None => {
+            std::fs::create_dir_all(&args.backup_dir)
+                .with_context(|| format!("Unable to create {}", args.backup_dir.display()))?;
+            let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+            args.backup_dir
+                .join(format!("{BACKUP_PREFIX}{timestamp}{BACKUP_SUFFIX}"))
+        }
+    };
+
+    write_snapshot(pool, &destination).await?;
+
+    if output.is_none() {
+        prune_backups(&args.backup_dir, args.backup_retention)?;
+    }
+
+    Ok(destination)
+}
+
+/// Background task for the server, writes a backup every `interval_minutes`
+pub async fn periodic_backups(database: Database, args: BackupArgs, interval_minutes: u64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(interval_minutes * 60));
+    // the first tick completes immediately, don't back up on every restart
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        match backup_database(&database, &args, None).await {
+            Ok(path) => info!("Wrote backup to {}", path.display()),
+            Err(err) => error!("Backup failed: {err:#}"),
+        }
+    }
+}
+
+async fn write_snapshot(pool: &SqlitePool, destination: &Path) -> Result<()> {
+    // write to a temporary file first, so a crash never leaves a truncated backup behind
+    let partial = with_suffix(destination, ".partial");
+
+    let mut connection = pool.acquire().await?;
+    let mut handle = connection.lock_handle().await?;
+    let source = handle.as_raw_handle();
+    tokio::task::block_in_place(|| online_backup(source, &partial))?;
+
+    std::fs::rename(&partial, destination)
+        .with_context(|| format!("Unable to move backup to {}", destination.display()))?;
+
+    Ok(())
+}
+
+/// Copies the database behind `source` with SQLite's online backup API:
+/// https://www.sqlite.org/backup.html
+fn online_backup(source: NonNull<ffi::sqlite3>, destination: &Path) -> Result<()> {
+    let path = CString::new(
+        destination
+            .to_str()
+            .ok_or(anyhow!("Invalid backup path: {}", destination.display()))?,
+    )?;
+    let main = CString::new("main")?;
+
+    // SAFETY: `source` is a live connection that we hold the lock for. `target` and `backup`
+    // are only used between their creation and the matching close/finish call below.
+    unsafe {
+        let mut target: *mut ffi::sqlite3 = ptr::null_mut();
+        let rc = ffi::sqlite3_open_v2(
+            path.as_ptr(),
+            &mut target,
+            ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE,
+            ptr::null(),
+        );
+        if rc != ffi::SQLITE_OK {
+            ffi::sqlite3_close(target);
+            bail!("Unable to open {}: {}", destination.display(), errstr(rc));
+        }
+
+        let backup =
+            ffi::sqlite3_backup_init(target, main.as_ptr(), source.as_ptr(), main.as_ptr());
+        if backup.is_null() {
+            let message = CStr::from_ptr(ffi::sqlite3_errmsg(target))
+                .to_string_lossy()
+                .into_owned();
+            ffi::sqlite3_close(target);
+            bail!("Unable to start backup: {message}");
+        }
+
+        let mut rc = ffi::sqlite3_backup_step(backup, -1);
+        let mut retries = 0;
+        while (rc == ffi::SQLITE_BUSY || rc == ffi::SQLITE_LOCKED) && retries < BUSY_RETRIES {
+            std::thread::sleep(Duration::from_millis(100));
+            rc = ffi::sqlite3_backup_step(backup, -1);
+            retries += 1;
+        }
+
+        ffi::sqlite3_backup_finish(backup);
+        let close_rc = ffi::sqlite3_close(target);
+
+        if rc != ffi::SQLITE_DONE {
+            bail!("Backup failed: {}", errstr(rc));
+        }
+        if close_rc != ffi::SQLITE_OK {
+            bail!("Unable to close backup: {}", errstr(close_rc));
+        }
+    }
+
+    Ok(())
+}
+
+fn errstr(rc: i32) -> String {
+    // SAFETY: sqlite3_errstr returns a pointer to a static string
+    unsafe { CStr::from_ptr(ffi::sqlite3_errstr(rc)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Deletes the oldest backups, keeping at least one
+fn prune_backups(backup_dir: &Path, retention: usize) -> Result<()> {
+    let mut backups: Vec<PathBuf> = std::fs::read_dir(backup_dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX)
+                })
+        })
+        .collect();
+    // the timestamps in the file names sort chronologically
+    backups.sort();
+
+    let excess = backups.len().saturating_sub(retention.max(1));
+    for path in backups.iter().take(excess) {
+        std::fs::remove_file(path)
+            .with_context(|| format!("Unable to delete old backup {}", path.display()))?;
+        info!("Deleted old backup {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Replaces the database file with `file`, after checking that the snapshot is intact and
+/// that its schema is known to this build. The previous database is kept next to it with a
+/// timestamped `.before-restore-` suffix.
+///
+/// The server must be stopped first: it would keep writing to the previous database file.
+/// Restoring fails while another process has the database open.
+pub async fn restore(args: &DatabaseArgs, file: &Path) -> Result<()> {
+    let database_path = sqlite_path(&args.database_url)?;
+
+    let mut snapshot = SqliteConnectOptions::new()
+        .filename(file)
+        .read_only(true)
+        .connect()
+        .await
+        .with_context(|| format!("Unable to open {}", file.display()))?;
+
+    let integrity: String = sqlx::query_scalar("pragma integrity_check")
+        .fetch_one(&mut snapshot)
+        .await?;
+    if integrity != "ok" {
+        bail!("{} failed the integrity check: {integrity}", file.display());
+    }
+
+    let snapshot_version: Option<i64> =
+        sqlx::query_scalar("select max(version) from _sqlx_migrations where success")
+            .fetch_one(&mut snapshot)
+            .await
+            .with_context(|| format!("{} has no migration history", file.display()))?;
+    snapshot.close().await?;
+
+    let latest_version = SQLITE_MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max();
+    match (snapshot_version, latest_version) {
+        (None, _) => bail!("{} has no applied migrations", file.display()),
+        (Some(snapshot_version), Some(latest_version)) if snapshot_version > latest_version => {
+            bail!(
+                "{} is at migration {snapshot_version}, which is newer than the latest migration \
+                 known to this build ({latest_version})",
+                file.display()
+            )
+        }
+        (Some(snapshot_version), Some(latest_version)) if snapshot_version < latest_version => {
+            println!(
+                "{} is at migration {snapshot_version}, the latest migration is {latest_version}. \
+                 It will be migrated on the next start with embedded migrations, or by `y \
+                 migrate run`.",
+                file.display()
+            )
+        }
+        _ => {}
+    }
+
+    if database_path.exists() {
+        ensure_not_in_use(&database_path).await?;
+    }
+
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    let previous = with_suffix(&database_path, &format!(".before-restore-{timestamp}"));
+    for suffix in ["", "-wal", "-shm"] {
+        let previous = with_suffix(&previous, suffix);
+        if previous.exists() {
+            bail!(
+                "{} already exists, not overwriting it. Try again.",
+                previous.display()
+            );
+        }
+    }
+
+    // copy next to the database first, so the final swap is a rename on the same filesystem
+    let staging = with_suffix(&database_path, ".restoring");
+    std::fs::copy(file, &staging)
+        .with_context(|| format!("Unable to copy {} to {}", file.display(), staging.display()))?;
+
+    for suffix in ["", "-wal", "-shm"] {
+        let current = with_suffix(&database_path, suffix);
+        if current.exists() {
+            std::fs::rename(&current, with_suffix(&previous, suffix))?;
+        }
+    }
+    std::fs::rename(&staging, &database_path)?;
+
+    println!(
+        "Restored {} from {}. The previous database was moved to {}.",
+        database_path.display(),
+        file.display(),
+        previous.display()
+    );
+
+    Ok(())
+}
+
+/// Fails if another connection has the database open, like the pool of a running server. In
+/// exclusive locking mode, SQLite only grants a WAL database to a connection while no other
+/// connection uses it. Closing the connection also checkpoints the WAL into the database file.
+async fn ensure_not_in_use(database_path: &Path) -> Result<()> {
+    let in_use = || {
+        format!(
+            "{} is in use. Stop the server before restoring.",
+            database_path.display()
+        )
+    };
+
+    let mut connection = SqliteConnectOptions::new()
+        .filename(database_path)
+        .journal_mode(SqliteJournalMode::Wal)
+        .locking_mode(SqliteLockingMode::Exclusive)
+        // a process that just closed the database may still be releasing its lock
+        .busy_timeout(Duration::from_secs(1))
+        .connect()
+        .await
+        .with_context(in_use)?;
+    sqlx::query("begin exclusive")
+        .execute(&mut connection)
+        .await
+        .with_context(in_use)?;
+    sqlx::query("select count(*) from sqlite_master")
+        .execute(&mut connection)
+        .await
+        .with_context(in_use)?;
+    sqlx::query("commit").execute(&mut connection).await?;
+    connection.close().await?;
+
+    Ok(())
+}
+
+fn sqlite_path(database_url: &str) -> Result<PathBuf> {
+    let path = database_url
+        .strip_prefix("sqlite://")
+        .or_else(|| database_url.strip_prefix("sqlite:"))
+        .ok_or(anyhow!(
+            "Restore is only supported for sqlite: database URLs"
+        ))?;
+    let path = path.split('?').next().unwrap_or_default();
+    if path.is_empty() || path == ":memory:" {
+        bail!("Cannot restore into an in-memory database");
+    }
+
+    Ok(PathBuf::from(path))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path: OsString = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    /// An empty directory for the files of one test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("y-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn open(path: &Path) -> SqlitePool {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        // a single connection, so the tests know which one is open
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .unwrap()
+    }
+
+    async fn count_posts(pool: &SqlitePool) -> i64 {
+        sqlx::query_scalar("select count(*) from posts")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    /// Files next to the database that keep previous databases
+    fn previous_databases(dir: &Path) -> Vec<PathBuf> {
+        let mut previous: Vec<PathBuf> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_str().unwrap();
+                name.contains(".before-restore-") && name.ends_with('Z')
+            })
+            .collect();
+        previous.sort();
+        previous
+    }
+
+    // the online backup blocks in place, which needs the multi-threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restore_brings_back_the_backup() {
+        let dir = test_dir("restore");
+        let database_path = dir.join("y.sqlite");
+        let args = DatabaseArgs {
+            database_url: format!("sqlite:{}", database_path.display()),
+        };
+        let backup_args = BackupArgs {
+            backup_dir: dir.join("backups"),
+            backup_retention: 7,
+            backup_interval_minutes: None,
+        };
+
+        let pool = open(&database_path).await;
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let posts = count_posts(&pool).await;
+        assert!(posts > 0);
+        let database = Database::Sqlite(pool.clone());
+        let snapshot = backup_database(&database, &backup_args, None)
+            .await
+            .unwrap();
+
+        // closing the pool does not wait for a connection that is still on its way back to it,
+        // so the last connection is closed by hand
+        let mut connection = pool.acquire().await.unwrap();
+        sqlx::query("delete from vote_history")
+            .execute(&mut *connection)
+            .await
+            .unwrap();
+        sqlx::query("delete from posts")
+            .execute(&mut *connection)
+            .await
+            .unwrap();
+        connection.close().await.unwrap();
+        pool.close().await;
+
+        restore(&args, &snapshot).await.unwrap();
+        restore(&args, &snapshot).await.unwrap();
+
+        let pool = open(&database_path).await;
+        assert_eq!(count_posts(&pool).await, posts);
+        pool.close().await;
+
+        // the first restore kept the changed database, the second one the restored one
+        let previous = previous_databases(&dir);
+        assert_eq!(previous.len(), 2, "{previous:?}");
+        let first = open(&previous[0]).await;
+        assert_eq!(count_posts(&first).await, 0);
+        first.close().await;
+        let second = open(&previous[1]).await;
+        assert_eq!(count_posts(&second).await, posts);
+        second.close().await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restore_refuses_a_database_in_use() {
+        let dir = test_dir("restore-in-use");
+        let database_path = dir.join("y.sqlite");
+        let args = DatabaseArgs {
+            database_url: format!("sqlite:{}", database_path.display()),
+        };
+
+        let pool = open(&database_path).await;
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let snapshot = dir.join("snapshot.sqlite");
+        write_snapshot(&pool, &snapshot).await.unwrap();
+
+        // like the pool of a running server
+        let connection = pool.acquire().await.unwrap();
+        let error = restore(&args, &snapshot).await.unwrap_err();
+        assert!(error.to_string().contains("Stop the server"), "{error:#}");
+        assert!(previous_databases(&dir).is_empty());
+        drop(connection);
+        assert!(count_posts(&pool).await > 0);
+
+        pool.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,10 @@
 use anyhow::Result;
+use chatgpt::prelude::*;
 use chatgpt::{
     prelude::{ChatGPT, ModelConfigurationBuilder},
     types::CompletionResponse,
 };
 use common::structs_api::ApiFrontpage;
-use chatgpt::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -22,10 +22,7 @@ async fn main() -> Result<()> {
     let prompt = simulation_prompt(&persona, frontpage).await;
     println!("{}", prompt);
 
-
-
-
-// ... within your conversation, before sending first message
+    // ... within your conversation, before sending first message
     let client = ChatGPT::new_with_config(
         openai_api_key,
         ModelConfigurationBuilder::default()
@@ -39,16 +36,10 @@ async fn main() -> Result<()> {
     // note that you need to call the function when adding it
     // conversation.add_function(explain_intent())?;
     conversation.add_function(view_post())?;
-    let response = conversation
-        .send_message_functions(prompt)
-        .await?;
+    let response = conversation.send_message_functions(prompt).await?;
 
     println!("{}", response.message().content);
 
-
-
-
-
     // generate 5 personas:
     // for _ in 0..5 {
     //     let persona = generate_persona(&openai_api_key).await?;
@@ -57,9 +48,8 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-
 /// Give an explanation for what and why you are doing something
-/// 
+///
 /// * explanation - brief explanation
 #[gpt_function]
 async fn explain_intent(explanation: String) {
@@ -67,7 +57,7 @@ async fn explain_intent(explanation: String) {
 }
 
 /// Click a post to view it's details, including discussion
-/// 
+///
 /// * explanation - explanation of why you are viewing this post
 /// * post_id - id of the post to view
 #[gpt_function]
@@ -75,10 +65,10 @@ async fn view_post(explanation: String, post_id: String) {
     println!("Viewing post. {explanation} post_id: {post_id}");
 }
 
-
 pub async fn simulation_prompt(persona: &str, frontpage: ApiFrontpage) -> String {
     let frontpage_json = serde_json::to_string_pretty(&frontpage).unwrap();
-    format!(r#"
+    format!(
+        r#"
     You are the following persona:
     ```
     {persona}
@@ -95,7 +85,8 @@ pub async fn simulation_prompt(persona: &str, frontpage: ApiFrontpage) -> String
 
     Now call a function.
 
-    "#)
+    "#
+    )
 }
 
 pub async fn get_frontpage(service_url: &str) -> Result<ApiFrontpage> {
@@ -1,4 +1,6 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Clone, Debug)]
 pub struct DatabaseArgs {
@@ -7,10 +9,112 @@ pub struct DatabaseArgs {
     pub database_url: String,
 }
 
+#[derive(Parser, Clone, Debug)]
+pub struct BackupArgs {
+    /// Directory that backups are written to
+    #[arg(long, env, default_value = "backups")]
+    pub backup_dir: PathBuf,
+
+    /// Number of backups to keep in the backup directory. Older ones are deleted.
+    #[arg(long, env, default_value_t = 7)]
+    pub backup_retention: usize,
+
+    /// While the server is running, write a backup every N minutes
+    #[arg(long, env)]
+    pub backup_interval_minutes: Option<u64>,
+}
+
+#[derive(Parser, Clone, Debug)]
+pub struct RateLimitArgs {
+    /// Votes per minute and user. 0 turns this and the IP limit for votes off.
+    #[arg(long, env, default_value_t = 60)]
+    pub rate_limit_votes_per_minute: u32,
+
+    /// Posts per minute and user. 0 turns this and the IP limit for posts off.
+    #[arg(long, env, default_value_t = 5)]
+    pub rate_limit_posts_per_minute: u32,
+
+    /// New accounts per hour and IP, via the API or by voting without a session. 0 turns it off.
+    #[arg(long, env, default_value_t = 30)]
+    pub rate_limit_accounts_per_hour: u32,
+
+    /// An IP may vote and post this many times as often as a user, since users can share an IP
+    #[arg(long, env, default_value_t = 10)]
+    pub rate_limit_ip_factor: u32,
+
+    /// Header in which a reverse proxy passes the client IP, e.g. Fly-Client-IP. Without it,
+    /// the address of the connection is used.
+    #[arg(long, env)]
+    pub client_ip_header: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Write a consistent snapshot of the running database
+    Backup {
+        /// Write the snapshot to this file instead of the backup directory
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Replace the database with a snapshot. The server must not be running, restoring fails
+    /// while the database is in use. The previous database is kept next to it.
+    Restore {
+        /// Snapshot written by the backup command
+        file: PathBuf,
+    },
+    /// Move superseded votes from vote_history to vote_history_archive. Verifies that the
+    /// views are unchanged and rolls back otherwise.
+    CompactVotes {
+        /// Only archive votes older than this many days
+        #[arg(long, default_value_t = 30)]
+        horizon_days: u32,
+
+        /// Report what would be archived without changing the database
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move the posts and votes of one user to another, as the /merge page does. Where both voted
+    /// on the same post, the vote history of the user who voted last is kept, the other one is
+    /// archived.
+    MergeUsers {
+        /// Id of the user whose posts and votes are moved
+        #[arg(long)]
+        from: i64,
+
+        /// Id of the user who receives them
+        #[arg(long)]
+        into: i64,
+    },
+    /// Run or inspect the database migrations
+    Migrate {
+        #[command(subcommand)]
+        command: MigrateCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MigrateCommand {
+    /// Apply the migrations of this build. Use this instead of `sqlx migrate run`, which leaves
+    /// the secrets of existing SQLite users in plaintext until the server starts.
+    Run,
+    /// Compare the applied migrations and the schema with this build
+    Status,
+}
+
 /// Program options to be read via clap
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct CommandLineArgs {
     #[command(flatten)]
     pub database: DatabaseArgs,
+
+    #[command(flatten)]
+    pub backup: BackupArgs,
+
+    #[command(flatten)]
+    pub rate_limit: RateLimitArgs,
+
+    /// Runs the http server if no command is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
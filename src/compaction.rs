@@ -0,0 +1,423 @@
+//! Compaction of `vote_history`
+//!
+//! The views only depend on a few votes per user and post: the latest vote, the first and the
+//! latest vote on each note, and the last vote before the user first saw each note. Every other
+//! vote is superseded. Compaction moves superseded votes older than a horizon to
+//! `vote_history_archive`, so the views have fewer rows to scan.
+//!
+//! The votes to keep are selected with the same aggregates the views use, so compaction does not
+//! change any view. Each run checks this: the views are copied before compacting and compared
+//! afterwards, and the transaction is rolled back if anything differs.
+
+use anyhow::{bail, Result};
+use chrono::{Duration, Utc};
+#[cfg(feature = "postgres")]
+use sqlx::PgPool;
+use sqlx::SqlitePool;
+
+use crate::db_setup::Database;
+
+/// Views that must have the same contents before and after compaction
+const VERIFIED_VIEWS: [&str; 3] = ["current_vote", "current_tally", "current_informed_tally"];
+
+/// rowids of the votes that the views depend on
+const SQLITE_VOTES_TO_KEEP: &str = r#"
+    with latest_votes as (
+        select rowid as vote_rowid, max(created)
+        from vote_history
+        group by user_id, tag_id, post_id
+    )
+    , latest_informed_votes as (
+        select rowid as vote_rowid, max(created)
+        from vote_history
+        where note_id is not null
+        group by user_id, tag_id, post_id, note_id
+    )
+    , first_votes_on_notes as (
+        select
+              user_id
+            , tag_id
+            , post_id
+            , note_id
+            , min(rowid) as first_vote_on_this_note_rowid
+        from vote_history
+        where note_id is not null
+        group by user_id, tag_id, post_id, note_id
+    )
+    , last_votes_before_note as (
+        select vote_history.rowid as vote_rowid, max(vote_history.created)
+        from first_votes_on_notes
+        join vote_history using (user_id, tag_id, post_id)
+        where vote_history.rowid < first_vote_on_this_note_rowid
+        group by user_id, tag_id, post_id, first_votes_on_notes.note_id
+    )
+    select vote_rowid from latest_votes
+    union select vote_rowid from latest_informed_votes
+    union select first_vote_on_this_note_rowid from first_votes_on_notes
+    union select vote_rowid from last_votes_before_note
+"#;
+
+/// ids of the votes that the views depend on
+#[cfg(feature = "postgres")]
+const POSTGRES_VOTES_TO_KEEP: &str = r#"
+    with latest_votes as (
+        select distinct on (user_id, tag_id, post_id) id
+        from vote_history
+        order by user_id, tag_id, post_id, created desc, id desc
+    )
+    , latest_informed_votes as (
+        select distinct on (user_id, tag_id, post_id, note_id) id
+        from vote_history
+        where note_id is not null
+        order by user_id, tag_id, post_id, note_id, created desc, id desc
+    )
+    , first_votes_on_notes as (
+        select
+              user_id
+            , tag_id
+            , post_id
+            , note_id
+            , min(id) as first_vote_on_this_note_id
+        from vote_history
+        where note_id is not null
+        group by user_id, tag_id, post_id, note_id
+    )
+    , last_votes_before_note as (
+        select distinct on (f.user_id, f.tag_id, f.post_id, f.note_id) v.id
+        from first_votes_on_notes f
+        join vote_history v using (user_id, tag_id, post_id)
+        where v.id < f.first_vote_on_this_note_id
+        order by f.user_id, f.tag_id, f.post_id, f.note_id, v.created desc, v.id desc
+    )
+    select id from latest_votes
+    union select id from latest_informed_votes
+    union select first_vote_on_this_note_id from first_votes_on_notes
+    union select id from last_votes_before_note
+"#;
+
+#[derive(Debug)]
+pub struct CompactionReport {
+    /// Votes moved to `vote_history_archive`
+    pub archived: u64,
+    /// Votes left in `vote_history`
+    pub remaining: i64,
+}
+
+/// Archives superseded votes older than `horizon_days`. With `dry_run`, the compaction is
+/// verified and then rolled back.
+pub async fn compact_vote_history(
+    database: &Database,
+    horizon_days: u32,
+    dry_run: bool,
+) -> Result<CompactionReport> {
+    // computed once, so archiving and deleting see the same votes
+    let cutoff = (Utc::now() - Duration::days(horizon_days.into()))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string();
+
+    match database {
+        Database::Sqlite(pool) => compact_sqlite(pool, &cutoff, dry_run).await,
+        #[cfg(feature = "postgres")]
+        Database::Postgres(pool) => compact_postgres(pool, &cutoff, dry_run).await,
+    }
+}
+
+async fn compact_sqlite(
+    pool: &SqlitePool,
+    cutoff: &str,
+    dry_run: bool,
+) -> Result<CompactionReport> {
+    let mut tx = pool.begin().await?;
+
+    // a write takes the database lock, so no votes come in between the snapshot and the check
+    sqlx::query("delete from vote_history_archive where false")
+        .execute(&mut *tx)
+        .await?;
+
+    for view in VERIFIED_VIEWS {
+        sqlx::query(&format!(
+            "create temp table before_{view} as select * from {view}"
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let archived = sqlx::query(&format!(
+        r#"
+            insert into vote_history_archive (
+                  vote_rowid
+                , user_id
+                , tag_id
+                , post_id
+                , note_id
+                , direction
+                , created
+            )
+            select rowid, user_id, tag_id, post_id, note_id, direction, created
+            from vote_history
+            where created < ?
+            and rowid not in ({SQLITE_VOTES_TO_KEEP})
+        "#
+    ))
+    .bind(cutoff)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    let deleted = sqlx::query(&format!(
+        r#"
+            delete from vote_history
+            where created < ?
+            and rowid not in ({SQLITE_VOTES_TO_KEEP})
+        "#
+    ))
+    .bind(cutoff)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if deleted != archived {
+        bail!("Archived {archived} votes, but deleted {deleted}. Rolled back.");
+    }
+
+    for view in VERIFIED_VIEWS {
+        let differences: i64 = sqlx::query_scalar(&difference_query(view))
+            .fetch_one(&mut *tx)
+            .await?;
+        if differences > 0 {
+            bail!("Compaction would change {differences} rows of {view}. Rolled back.");
+        }
+        // temp tables outlive the transaction on this pooled connection
+        sqlx::query(&format!("drop table temp.before_{view}"))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let remaining: i64 = sqlx::query_scalar("select count(*) from vote_history")
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+
+    Ok(CompactionReport {
+        archived,
+        remaining,
+    })
+}
+
+#[cfg(feature = "postgres")]
+async fn compact_postgres(pool: &PgPool, cutoff: &str, dry_run: bool) -> Result<CompactionReport> {
+    let mut tx = pool.begin().await?;
+
+    // reads continue, votes wait until the compaction is done
+    sqlx::query("lock table vote_history in exclusive mode")
+        .execute(&mut *tx)
+        .await?;
+
+    for view in VERIFIED_VIEWS {
+        sqlx::query(&format!(
+            "create temp table before_{view} on commit drop as select * from {view}"
+        ))
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let archived = sqlx::query(&format!(
+        r#"
+            insert into vote_history_archive (
+                  vote_id
+                , user_id
+                , tag_id
+                , post_id
+                , note_id
+                , direction
+                , created
+            )
+            select id, user_id, tag_id, post_id, note_id, direction, created
+            from vote_history
+            where created < $1::timestamp
+            and id not in ({POSTGRES_VOTES_TO_KEEP})
+        "#
+    ))
+    .bind(cutoff)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    let deleted = sqlx::query(&format!(
+        r#"
+            delete from vote_history
+            where created < $1::timestamp
+            and id not in ({POSTGRES_VOTES_TO_KEEP})
+        "#
+    ))
+    .bind(cutoff)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if deleted != archived {
+        bail!("Archived {archived} votes, but deleted {deleted}. Rolled back.");
+    }
+
+    for view in VERIFIED_VIEWS {
+        let differences: i64 = sqlx::query_scalar(&difference_query(view))
+            .fetch_one(&mut *tx)
+            .await?;
+        if differences > 0 {
+            bail!("Compaction would change {differences} rows of {view}. Rolled back.");
+        }
+    }
+
+    let remaining: i64 = sqlx::query_scalar("select count(*) from vote_history")
+        .fetch_one(&mut *tx)
+        .await?;
+
+    if dry_run {
+        tx.rollback().await?;
+    } else {
+        tx.commit().await?;
+    }
+
+    Ok(CompactionReport {
+        archived,
+        remaining,
+    })
+}
+
+/// Counts the rows that are only in the snapshot of `view` or only in `view` itself
+fn difference_query(view: &str) -> String {
+    format!(
+        r#"
+            select
+                (select count(*) from (
+                    select * from before_{view} except select * from {view}
+                ) as removed)
+              + (select count(*) from (
+                    select * from {view} except select * from before_{view}
+                ) as added)
+        "#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    /// With the seed data of the first migration
+    async fn migrated_database() -> SqlitePool {
+        // every connection to `sqlite::memory:` opens a separate database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+        pool
+    }
+
+    /// Rows of `view` as text, sorted, so they can be compared without a snapshot table
+    async fn view_rows(pool: &SqlitePool, view: &str) -> Vec<String> {
+        let columns: Vec<String> =
+            sqlx::query_scalar(&format!("select name from pragma_table_info('{view}')"))
+                .fetch_all(pool)
+                .await
+                .unwrap();
+        let row = columns
+            .iter()
+            .map(|column| format!("quote({column})"))
+            .collect::<Vec<_>>()
+            .join(" || ',' || ");
+        sqlx::query_scalar(&format!("select {row} from {view} order by 1"))
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn compaction_keeps_the_views() {
+        let pool = migrated_database().await;
+
+        // on top of the seed data: changed votes without a note, before and after notes
+        sqlx::query(
+            r#"
+                insert into vote_history (tag_id, post_id, note_id, user_id, direction) values
+                      (0, 4, null, 101, 1)
+                    , (0, 4, null, 101, -1)
+                    , (0, 4, 5, 101, 1)
+                    , (0, 4, 5, 101, 0)
+                    , (0, 6, null, 101, -1)
+                    , (0, 6, null, 101, 1)
+                    , (0, 6, 7, 101, -1)
+                    , (0, 6, 7, 100, -1)
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        // a year ago, one vote per second, except for the last vote, which is recent
+        sqlx::query(
+            r#"
+                update vote_history
+                set created = datetime('now', '-1 year', '+' || rowid || ' seconds')
+                where rowid < (select max(rowid) from vote_history)
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut before = Vec::new();
+        for view in VERIFIED_VIEWS {
+            before.push(view_rows(&pool, view).await);
+        }
+        let votes: i64 = sqlx::query_scalar("select count(*) from vote_history")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let database = Database::Sqlite(pool.clone());
+        let report = compact_vote_history(&database, 30, false).await.unwrap();
+
+        assert!(report.archived > 0);
+        assert_eq!(report.remaining + report.archived as i64, votes);
+        let archive: i64 = sqlx::query_scalar("select count(*) from vote_history_archive")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(archive, report.archived as i64);
+        for (view, rows) in VERIFIED_VIEWS.iter().zip(before) {
+            assert_eq!(view_rows(&pool, view).await, rows, "{view} changed");
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_archives_nothing() {
+        let pool = migrated_database().await;
+        sqlx::query(
+            r#"
+                update vote_history
+                set created = datetime('now', '-1 year', '+' || rowid || ' seconds')
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let database = Database::Sqlite(pool.clone());
+        let report = compact_vote_history(&database, 30, true).await.unwrap();
+
+        assert!(report.archived > 0);
+        let archive: i64 = sqlx::query_scalar("select count(*) from vote_history_archive")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(archive, 0);
+    }
+}
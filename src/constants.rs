@@ -0,0 +1,4 @@
+//! Constants shared by the pages and the API
+
+/// Tag that every post is in, shown on the front page to visitors without account
+pub const GLOBAL_TAG: &str = "global";
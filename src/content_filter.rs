@@ -0,0 +1,76 @@
+//! Hiding the posts of blocked users and muted tags
+//!
+//! Posts by blocked users are left out of feeds, thread context and reply lists. A top note by a
+//! blocked user is not replaced by the next best note, because that would misrepresent which note
+//! the votes favor. Pages show a placeholder instead, and the API sets `note_blocked`. Muted tags
+//! are left out of tag suggestions, and the home feed leaves out posts tallied in any muted tag,
+//! not only in the tag they are shown with.
+
+use anyhow::Result;
+use common::repository::Repository;
+use common::structs::{Post, User};
+
+/// What a user muted and blocked. Hides nothing for visitors without account.
+#[derive(Debug, Clone, Default)]
+pub struct ContentFilter {
+    muted_tags: Vec<String>,
+    blocked_user_ids: Vec<i64>,
+}
+
+impl ContentFilter {
+    pub async fn load(user: Option<&User>, repo: &dyn Repository) -> Result<ContentFilter> {
+        Ok(match user {
+            Some(user) => ContentFilter {
+                muted_tags: repo.get_muted_tags(user.id).await?,
+                blocked_user_ids: repo.get_blocked_user_ids(user.id).await?,
+            },
+            None => ContentFilter::default(),
+        })
+    }
+
+    pub fn is_blocked(&self, post: &Post) -> bool {
+        self.blocked_user_ids.contains(&post.author_id)
+    }
+
+    pub fn is_muted(&self, tag: &str) -> bool {
+        self.muted_tags.iter().any(|muted| muted == tag)
+    }
+
+    /// Leaves out posts by blocked users
+    pub fn posts(&self, posts: Vec<Post>) -> Vec<Post> {
+        posts
+            .into_iter()
+            .filter(|post| !self.is_blocked(post))
+            .collect()
+    }
+
+    /// Whether `post` is tallied in a muted tag
+    pub async fn is_muted_post(&self, post: &Post, repo: &dyn Repository) -> Result<bool> {
+        if self.muted_tags.is_empty() {
+            return Ok(false);
+        }
+        let tags = repo.get_post_tags(post.id).await?;
+        Ok(tags.iter().any(|tag| self.is_muted(tag)))
+    }
+
+    /// Leaves out posts by blocked users and in muted tags, for feeds of `(tag, post)`
+    pub async fn tagged_posts(
+        &self,
+        posts: Vec<(String, Post)>,
+        repo: &dyn Repository,
+    ) -> Result<Vec<(String, Post)>> {
+        let mut kept = vec![];
+        for (tag, post) in posts {
+            if self.is_blocked(&post) || self.is_muted_post(&post, repo).await? {
+                continue;
+            }
+            kept.push((tag, post));
+        }
+        Ok(kept)
+    }
+
+    /// Leaves out muted tags
+    pub fn tags(&self, tags: Vec<String>) -> Vec<String> {
+        tags.into_iter().filter(|tag| !self.is_muted(tag)).collect()
+    }
+}
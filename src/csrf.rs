@@ -0,0 +1,163 @@
+//! CSRF protection for the htmx forms, with a double-submit token
+//!
+//! Every page sets a random token in the `csrf` cookie and sends the same token with each htmx
+//! request in the [CSRF_HEADER] header, see `pages::base_template`. Other sites can make a
+//! browser send the cookie, but can neither read it nor set the header, so [verify_csrf] rejects
+//! their requests. `/api/v0` uses bearer tokens instead of cookies and is not covered.
+
+use axum::{
+    http::{Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use tower_cookies::cookie::time::Duration;
+use tower_cookies::cookie::SameSite;
+use tower_cookies::{Cookie, Cookies};
+
+use crate::error::AppError;
+
+const CSRF_COOKIE: &str = "csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+const TOKEN_LENGTH: usize = 32;
+
+/// The token of this browser, a new one if it has none yet
+pub fn csrf_token(cookies: &Cookies) -> String {
+    if let Some(cookie) = cookies.get(CSRF_COOKIE) {
+        if cookie.value().len() == TOKEN_LENGTH {
+            return cookie.value().to_string();
+        }
+    }
+
+    let token: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+    let cookie = Cookie::build(CSRF_COOKIE, token.clone())
+        .path("/")
+        .max_age(Duration::days(365))
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .finish();
+    cookies.add(cookie);
+    token
+}
+
+/// Compares in constant time, so that the timing does not reveal how much of a guess is right
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (x, y)| difference | (x ^ y))
+            == 0
+}
+
+/// Middleware that rejects requests with side effects if the header does not match the cookie.
+/// Must run inside of the cookie layer.
+pub async fn verify_csrf<B>(cookies: Cookies, request: Request<B>, next: Next<B>) -> Response {
+    let safe_method = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if safe_method {
+        return next.run(request).await;
+    }
+
+    let header = request
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let valid = match (header, cookies.get(CSRF_COOKIE)) {
+        (Some(header), Some(cookie)) => tokens_match(header, cookie.value()),
+        _ => false,
+    };
+    if valid {
+        return next.run(request).await;
+    }
+
+    AppError::Forbidden("This page has expired, please reload it and try again.".to_string())
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::any, Router};
+    use http::{header::COOKIE, StatusCode};
+    use tower::ServiceExt;
+    use tower_cookies::CookieManagerLayer;
+
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdefghijklmnopqrstuv";
+
+    /// The layers of `http_server`, with the API nested outside of the CSRF check
+    fn app() -> Router {
+        let api = Router::new().route("/vote", any(|| async { "api" }));
+        Router::new()
+            .route("/vote", any(|| async { "page" }))
+            .layer(middleware::from_fn(verify_csrf))
+            .layer(CookieManagerLayer::new())
+            .nest("/api/v0", api)
+    }
+
+    async fn status(
+        method: Method,
+        uri: &str,
+        cookie: Option<&str>,
+        header: Option<&str>,
+    ) -> StatusCode {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = cookie {
+            request = request.header(COOKIE, format!("{CSRF_COOKIE}={token}"));
+        }
+        if let Some(token) = header {
+            request = request.header(CSRF_HEADER, token);
+        }
+        let request = request.body(Body::empty()).unwrap();
+        app().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn post_with_matching_token_passes() {
+        let status = status(Method::POST, "/vote", Some(TOKEN), Some(TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn post_without_matching_token_is_forbidden() {
+        let other_token = "vutsrqponmlkjihgfedcba9876543210";
+        for (cookie, header) in [
+            (Some(TOKEN), None),
+            (Some(TOKEN), Some(other_token)),
+            (Some(TOKEN), Some(&TOKEN[..16])),
+            (None, Some(TOKEN)),
+            (None, None),
+        ] {
+            assert_eq!(
+                status(Method::POST, "/vote", cookie, header).await,
+                StatusCode::FORBIDDEN,
+                "cookie {cookie:?}, header {header:?}",
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn safe_methods_pass() {
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS] {
+            assert_eq!(
+                status(method.clone(), "/vote", None, None).await,
+                StatusCode::OK,
+                "{method}",
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn api_is_not_covered() {
+        let status = status(Method::POST, "/api/v0/vote", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
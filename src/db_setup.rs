@@ -1,12 +1,148 @@
+#[cfg(feature = "postgres")]
+use anyhow::anyhow;
+#[cfg(feature = "embed_migrations")]
+use anyhow::Context;
+use anyhow::Result;
+use common::auth::hash_secret;
+#[cfg(feature = "postgres")]
+use common::repository::postgres::PostgresRepository;
+use common::repository::{sqlite::SqliteRepository, DynRepository};
+#[cfg(feature = "postgres")]
+use sqlx::{migrate::MigrateDatabase, postgres::PgPoolOptions, PgPool, Postgres};
 use sqlx::{
+    migrate::Migrator,
     sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
     SqlitePool,
 };
 use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::command_line_args::DatabaseArgs;
+use crate::schema::verify_schema;
 
-pub async fn setup_database(args: &DatabaseArgs) -> SqlitePool {
+pub static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+#[cfg(feature = "postgres")]
+pub static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations_postgres");
+
+/// Connection pool of the backend selected by the database URL
+#[derive(Clone)]
+pub enum Database {
+    Sqlite(SqlitePool),
+    #[cfg(feature = "postgres")]
+    Postgres(PgPool),
+}
+
+impl Database {
+    pub fn repository(&self) -> DynRepository {
+        match self {
+            Database::Sqlite(pool) => Arc::new(SqliteRepository::new(pool.clone())),
+            #[cfg(feature = "postgres")]
+            Database::Postgres(pool) => Arc::new(PostgresRepository::new(pool.clone())),
+        }
+    }
+
+    /// Migrations embedded in this build for the backend
+    pub fn migrator(&self) -> &'static Migrator {
+        match self {
+            Database::Sqlite(_) => &SQLITE_MIGRATOR,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(_) => &POSTGRES_MIGRATOR,
+        }
+    }
+
+    /// Runs the embedded migrations and, for SQLite, hashes the secrets that
+    /// migrations/20261019110000_hash_user_secrets.sql leaves in plaintext, in one step. This is
+    /// what `y migrate run` and servers built with `embed_migrations` do.
+    pub async fn migrate(&self) -> Result<()> {
+        match self {
+            Database::Sqlite(pool) => {
+                self.migrator().run(pool).await?;
+                hash_plaintext_secrets(pool).await?;
+            }
+            #[cfg(feature = "postgres")]
+            Database::Postgres(pool) => self.migrator().run(pool).await?,
+        }
+        Ok(())
+    }
+
+    /// The SQLite pool, for operations that only exist for SQLite databases
+    pub fn sqlite_pool(&self) -> Result<&SqlitePool> {
+        match self {
+            Database::Sqlite(pool) => Ok(pool),
+            #[cfg(feature = "postgres")]
+            Database::Postgres(_) => Err(anyhow!("Only supported for SQLite databases")),
+        }
+    }
+}
+
+fn is_postgres_url(database_url: &str) -> bool {
+    database_url.starts_with("postgres://") || database_url.starts_with("postgresql://")
+}
+
+/// Connects to the database and runs the embedded migrations (with the `embed_migrations`
+/// feature). Fails with a report if the schema does not match this build afterwards.
+pub async fn setup_database(args: &DatabaseArgs) -> Result<Database> {
+    let database = connect_database(args).await;
+
+    #[cfg(feature = "embed_migrations")]
+    {
+        println!("Running database migrations...");
+        database.migrate().await.context("Unable to migrate")?;
+        println!("Finished migrating database.");
+    }
+
+    verify_schema(&database).await?;
+    // for databases migrated with `sqlx migrate run` instead of `y migrate run`
+    if let Ok(pool) = database.sqlite_pool() {
+        hash_plaintext_secrets(pool).await?;
+    }
+
+    Ok(database)
+}
+
+/// Hashes the secrets that were stored in plaintext before
+/// migrations/20261019110000_hash_user_secrets.sql, which only renames the column because SQLite
+/// has no SHA-256 function. The Postgres migration hashes them itself. Hashes are 64 hex digits,
+/// generated secrets are 16 characters long.
+async fn hash_plaintext_secrets(pool: &SqlitePool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let plaintext: Vec<(i64, String)> =
+        sqlx::query_as("select id, secret_hash from users where length(secret_hash) != 64")
+            .fetch_all(&mut *tx)
+            .await?;
+    for (id, secret) in plaintext.iter() {
+        sqlx::query("update users set secret_hash = ? where id = ?")
+            .bind(hash_secret(secret))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    if !plaintext.is_empty() {
+        println!("Hashed {} plaintext user secrets.", plaintext.len());
+    }
+    Ok(())
+}
+
+/// Connects to `postgres://` URLs with Postgres (requires the `postgres` feature) and to
+/// everything else with SQLite, without migrating or checking the schema
+pub async fn connect_database(args: &DatabaseArgs) -> Database {
+    if is_postgres_url(&args.database_url) {
+        #[cfg(feature = "postgres")]
+        {
+            return Database::Postgres(connect_postgres(args).await);
+        }
+        #[cfg(not(feature = "postgres"))]
+        {
+            panic!("Postgres database URL given, but y was built without the `postgres` feature");
+        }
+    }
+
+    Database::Sqlite(connect_sqlite(args).await)
+}
+
+async fn connect_sqlite(args: &DatabaseArgs) -> SqlitePool {
     // high performance sqlite insert example: https://kerkour.com/high-performance-rust-with-sqlite
 
     // if embed_migrations is enabled, we create the database if it doesn't exist
@@ -26,16 +162,6 @@ pub async fn setup_database(args: &DatabaseArgs) -> SqlitePool {
         .await
         .unwrap();
 
-    #[cfg(feature = "embed_migrations")]
-    {
-        println!("Running database migrations...");
-        sqlx::migrate!("./migrations")
-            .run(&sqlite_pool)
-            .await
-            .expect("Unable to migrate");
-        println!("Finished migrating database.");
-    }
-
     for option in [
         "pragma temp_store = memory;",
         "pragma mmap_size = 30000000000;",
@@ -49,3 +175,54 @@ pub async fn setup_database(args: &DatabaseArgs) -> SqlitePool {
 
     sqlite_pool
 }
+
+#[cfg(feature = "postgres")]
+async fn connect_postgres(args: &DatabaseArgs) -> PgPool {
+    // if embed_migrations is enabled, we create the database if it doesn't exist
+    if cfg!(feature = "embed_migrations")
+        && !Postgres::database_exists(&args.database_url).await.unwrap()
+    {
+        Postgres::create_database(&args.database_url)
+            .await
+            .expect("Unable to create database");
+    }
+
+    PgPoolOptions::new()
+        .max_connections(32)
+        .acquire_timeout(std::time::Duration::from_secs(30))
+        .connect(&args.database_url)
+        .await
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn migrate_leaves_no_plaintext_secrets() {
+        // every connection to `sqlite::memory:` opens a separate database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let database = Database::Sqlite(pool.clone());
+
+        database.migrate().await.unwrap();
+
+        // the first migration seeds users 100 and 101 with the secrets secret100 and secret101
+        let secret_hashes: Vec<(i64, String)> =
+            sqlx::query_as("select id, secret_hash from users order by id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(
+            secret_hashes,
+            vec![
+                (100, hash_secret("secret100")),
+                (101, hash_secret("secret101")),
+            ]
+        );
+    }
+}
@@ -1,39 +1,88 @@
+//! Errors as HTML for the site
+//!
+//! [AppError] is defined in `common::error` and renders as JSON, which is what `/api/v0`
+//! returns. On the site, the [error_pages] middleware replaces that with HTML: the message inline
+//! in the `.form-errors` element of htmx forms, and an error page otherwise.
+
 use axum::{
+    extract::Extension,
+    middleware::Next,
     response::{IntoResponse, Response},
-    Json,
 };
-use http::StatusCode;
-use serde_json::json;
-
-// https://github.com/tokio-rs/axum/blob/main/examples/anyhow-error-response/src/main.rs
-pub struct AppError(pub anyhow::Error);
-
-// Tell axum how to convert `AppError` into a response.
-// https://github.com/tokio-rs/axum/discussions/713
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AppError(inner) => {
-                tracing::debug!("stacktrace: {}", inner.backtrace());
-                (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
-            }
-        };
+use common::{auth, error::ErrorDetails, repository::DynRepository};
+use http::Request;
+use maud::{html, Markup};
+use tower_cookies::Cookies;
+
+use crate::pages::base_template::BaseTemplate;
+use crate::pages::user::options::warning_dialog;
+
+pub use common::error::AppError;
+
+/// Renderings of an [AppError] for the site
+pub trait ErrorHtml {
+    /// The error as htmx swaps it into the `.form-errors` element of the submitted form
+    fn form_response(&self) -> Response;
+
+    /// The error as a page of the site
+    fn page_response(&self, base: BaseTemplate) -> Response;
+}
+
+impl ErrorHtml for AppError {
+    fn form_response(&self) -> Response {
+        let markup = error_fragment(&self.message(), self.field());
+        self.with_headers((self.status(), markup).into_response())
+    }
 
-        let body = Json(json!({
-            "error": error_message,
-        }));
+    fn page_response(&self, base: BaseTemplate) -> Response {
+        let title = match self.status().canonical_reason() {
+            Some(reason) => reason.to_string(),
+            None => "Error".to_string(),
+        };
+        let content = html! {
+            (warning_dialog(&self.message(), None))
+            a href="/" class="text-blue-500 hover:text-blue-700" { "Back to 𝕐" }
+        };
+        let markup = base.title(&title).content(content).render();
+        self.with_headers((self.status(), markup).into_response())
+    }
+}
 
-        (status, body).into_response()
+/// An error message in a form, see [ErrorHtml::form_response]
+pub fn error_fragment(message: &str, field: Option<&str>) -> Markup {
+    html! {
+        p class="mt-2 text-sm text-red-600 dark:text-red-400" data-field=[field] {
+            (message)
+        }
     }
 }
 
-// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
-// `Result<_, AppError>`. That way you don't need to do that manually.
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        AppError(err.into())
+/// Middleware for the site, see the module documentation. Requests by htmx that are not boosted
+/// come from forms and buttons, everything else navigates to a page.
+pub async fn error_pages<B>(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let headers = request.headers().clone();
+    let response = next.run(request).await;
+    let Some(ErrorDetails(error)) = response.extensions().get::<ErrorDetails>().cloned() else {
+        return response;
+    };
+
+    let from_form = headers.contains_key("HX-Request") && !headers.contains_key("HX-Boosted");
+    if from_form {
+        return error.form_response();
+    }
+
+    let user = auth::user_from_cookies(&cookies, repo.as_ref())
+        .await
+        .ok()
+        .flatten();
+    match BaseTemplate::load(user, cookies, headers, repo.as_ref()).await {
+        Ok(base) => error.page_response(base),
+        // rendering the page failed as well, so stay with the JSON
+        Err(_) => response,
     }
 }
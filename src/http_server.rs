@@ -1,24 +1,58 @@
 use std::net::SocketAddr;
 
 use crate::api;
+use crate::command_line_args::RateLimitArgs;
+use crate::csrf::verify_csrf;
+use crate::error::{error_pages, AppError};
 use crate::http_static::static_handler;
 use crate::pages::{
     self, communities::community_frontpage, create_post::create_post, positions::positions,
     view_post::view_post, vote::tag_handler, vote::vote_handler,
 };
+use crate::rate_limit::{rate_limit, RateLimiter};
 use anyhow::Result;
 use axum::{
-    routing::{get, post},
+    middleware,
+    routing::{any, get, post},
     Extension, Router,
 };
+use common::repository::DynRepository;
 use http::StatusCode;
-use pages::{frontpage::frontpage, user::options::options};
-use sqlx::SqlitePool;
+use pages::{
+    block::{block_user, mute_tag},
+    follow::{follow_tag, follow_user},
+    frontpage::frontpage,
+    user::{
+        account::{delete_account, export},
+        bookmarks::{bookmark, bookmarks},
+        login::{login, login_page, logout, logout_page},
+        merge::{merge, merge_confirmation},
+        notifications::notifications,
+        options::{
+            create_api_token, options, revoke_api_token, revoke_session, rotate_secret,
+            set_credentials, set_display_name, show_device_link,
+        },
+        profile::{profile, public_profile},
+    },
+};
 use tower_cookies::CookieManagerLayer;
 use tower_http::{compression::CompressionLayer, trace::TraceLayer};
 use tracing::info;
 
-pub async fn start_http_server(sqlite_pool: SqlitePool) -> Result<()> {
+pub async fn start_http_server(repo: DynRepository, rate_limits: RateLimitArgs) -> Result<()> {
+    let app = router(repo, rate_limits);
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
+    info!("Http server listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+
+    Ok(())
+}
+
+/// The site and `/api/v0` with all layers. Requests need [axum::extract::ConnectInfo].
+fn router(repo: DynRepository, rate_limits: RateLimitArgs) -> Router {
     let mut app = Router::new();
 
     app = app
@@ -29,38 +63,451 @@ pub async fn start_http_server(sqlite_pool: SqlitePool) -> Result<()> {
         .route("/vote", post(vote_handler))
         .route("/tag/", post(tag_handler))
         .route("/positions", get(positions))
-        .route("/options", get(options));
+        .route("/user", get(profile))
+        .route("/user/:user_id", get(public_profile))
+        .route("/follow/tag/:tag", post(follow_tag))
+        .route("/follow/user/:user_id", post(follow_user))
+        .route("/mute/tag/:tag", post(mute_tag))
+        .route("/block/user/:user_id", post(block_user))
+        .route("/notifications", get(notifications))
+        .route("/bookmarks", get(bookmarks))
+        .route("/bookmark/:post_id", post(bookmark))
+        .route("/options", get(options))
+        .route("/options/sessions/:session_id/revoke", post(revoke_session))
+        .route("/options/device_link", post(show_device_link))
+        .route("/options/rotate_secret", post(rotate_secret))
+        .route("/options/credentials", post(set_credentials))
+        .route("/options/display_name", post(set_display_name))
+        .route("/options/api_tokens", post(create_api_token))
+        .route(
+            "/options/api_tokens/:token_id/revoke",
+            post(revoke_api_token),
+        )
+        .route("/options/export", get(export))
+        .route("/options/delete_account", post(delete_account))
+        .route("/login", get(login_page).post(login))
+        .route("/logout", get(logout_page).post(logout))
+        .route("/merge/:secret", get(merge_confirmation).post(merge));
+
+    // shared by the site and the API, so that they take from the same buckets
+    let rate_limiter = RateLimiter::new(rate_limits, repo.clone());
 
     let apiv0 = Router::new()
         .route("/user/create", post(api::create_user))
+        .route("/user", get(api::user_profile))
         .route("/frontpage", get(api::frontpage))
+        .route("/feed", get(api::feed))
+        .route("/notifications", get(api::notifications))
+        .route("/notifications/read", post(api::mark_notifications_read))
+        .route("/bookmarks", get(api::bookmarks).post(api::set_bookmark))
         .route("/view_post/:post_id", get(api::view_post))
         .route("/create_post", post(api::create_post))
         .route("/vote", post(api::vote))
-        .layer(Extension(sqlite_pool.clone()));
+        // a fallback would lose against the static files of the site
+        .route("/*path", any(not_found))
+        .layer(middleware::from_fn_with_state(
+            rate_limiter.clone(),
+            rate_limit,
+        ))
+        .layer(Extension(repo.clone()));
 
     app = app
         .route("/healthy", get(handler_healthy))
         .route("/*file", get(static_handler))
+        // before the layers, so that error_pages renders it
+        .fallback(not_found)
+        // inside of the cookie layer, and the API nested below is not covered
+        .layer(middleware::from_fn(verify_csrf))
+        // runs before the CSRF check, and inside of error_pages, which renders its 429s
+        .layer(middleware::from_fn_with_state(rate_limiter, rate_limit))
+        // outside of the CSRF check and rate limits to render their errors, inside of the cookie
+        // and repository layers that it needs
+        .layer(middleware::from_fn(error_pages))
         .layer(TraceLayer::new_for_http())
-        .layer(Extension(sqlite_pool.to_owned()))
+        .layer(Extension(repo.to_owned()))
         .layer(CookieManagerLayer::new())
-        .layer(CompressionLayer::new())
-        .fallback_service(get(not_found));
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
-    info!("Http server listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.nest("/api/v0", apiv0).into_make_service())
-        .await?;
+        .layer(CompressionLayer::new());
 
-    Ok(())
+    app.nest("/api/v0", apiv0)
 }
 
 async fn handler_healthy() -> StatusCode {
     StatusCode::OK
 }
 
-async fn not_found() -> StatusCode {
-    StatusCode::NOT_FOUND
+async fn not_found() -> AppError {
+    AppError::page_not_found()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::{Body, HttpBody};
+    use axum::extract::ConnectInfo;
+    use common::auth::{create_api_token, hash_secret, user_from_credentials, user_from_link};
+    use common::repository::memory::MemoryRepository;
+    use common::repository::sqlite::SqliteRepository;
+    use common::repository::{Repository, DELETED_POST_CONTENT};
+    use common::structs::{ApiScope, User};
+    use http::{header, HeaderMap, Method, Request};
+    use sqlx::sqlite::SqlitePoolOptions;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::db_setup::SQLITE_MIGRATOR;
+
+    const CSRF_TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+    fn app(rate_limit_accounts_per_hour: u32) -> Router {
+        app_with(
+            Arc::new(MemoryRepository::new()),
+            rate_limit_accounts_per_hour,
+        )
+    }
+
+    fn app_with(repo: DynRepository, rate_limit_accounts_per_hour: u32) -> Router {
+        let rate_limits = RateLimitArgs {
+            rate_limit_votes_per_minute: 60,
+            rate_limit_posts_per_minute: 5,
+            rate_limit_accounts_per_hour,
+            rate_limit_ip_factor: 10,
+            client_ip_header: None,
+        };
+        router(repo, rate_limits)
+    }
+
+    /// A new user with a session, and the `Cookie` header of a browser signed in to it
+    async fn signed_in(repo: &dyn Repository) -> (User, String) {
+        let secret: u64 = rand::random();
+        let user = User {
+            id: repo
+                .create_user(&hash_secret(&secret.to_string()))
+                .await
+                .unwrap(),
+        };
+        let token = format!("session of {}", user.id);
+        repo.create_session(user.id, &hash_secret(&token))
+            .await
+            .unwrap();
+        (user, format!("session={token}; csrf={CSRF_TOKEN}"))
+    }
+
+    async fn get(
+        app: &Router,
+        uri: &str,
+        headers: &[(&str, &str)],
+    ) -> (StatusCode, HeaderMap, String) {
+        send(app, Method::GET, uri, headers, "").await
+    }
+
+    /// Submits an htmx form of a page
+    async fn post_form(
+        app: &Router,
+        uri: &str,
+        cookie: &str,
+        form: &str,
+    ) -> (StatusCode, HeaderMap, String) {
+        let headers = [
+            ("Host", "localhost"),
+            ("Cookie", cookie),
+            ("X-CSRF-Token", CSRF_TOKEN),
+            ("HX-Request", "true"),
+            ("Content-Type", "application/x-www-form-urlencoded"),
+        ];
+        send(app, Method::POST, uri, &headers, form).await
+    }
+
+    async fn send(
+        app: &Router,
+        method: Method,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let mut request = request.body(Body::from(body.to_string())).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([192, 0, 2, 1], 1234))));
+
+        let mut response = app.clone().oneshot(request).await.unwrap();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.body_mut().data().await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        (
+            response.status(),
+            response.headers().clone(),
+            String::from_utf8(body).unwrap(),
+        )
+    }
+
+    fn is_html_page(headers: &HeaderMap, body: &str) -> bool {
+        headers[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html")
+            && body.starts_with("<!DOCTYPE html>")
+    }
+
+    #[tokio::test]
+    async fn unknown_pages_get_an_error_page() {
+        let (status, headers, body) = get(&app(30), "/no/such/page", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(is_html_page(&headers, &body), "{body}");
+        assert!(body.contains("This page does not exist."), "{body}");
+    }
+
+    #[tokio::test]
+    async fn unknown_api_paths_get_json() {
+        let (status, _, body) = get(&app(30), "/api/v0/no_such_endpoint", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["code"], "not_found");
+    }
+
+    #[tokio::test]
+    async fn api_without_token_is_unauthorized() {
+        let (status, headers, body) = get(&app(30), "/api/v0/user", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[header::WWW_AUTHENTICATE], "Bearer");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["code"], "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn site_without_session_gets_no_bearer_challenge() {
+        let app = app(30);
+        let (status, headers, body) = get(&app, "/options/export", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(is_html_page(&headers, &body), "{body}");
+        assert!(!headers.contains_key(header::WWW_AUTHENTICATE));
+
+        let htmx = [("HX-Request", "true")];
+        let (status, headers, _) = get(&app, "/options/export", &htmx).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!headers.contains_key(header::WWW_AUTHENTICATE));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_navigations_get_a_page_and_forms_a_fragment() {
+        // /positions creates an account for requests without a session
+        let app = app(1);
+        let (status, _, _) = get(&app, "/positions?tag=global&post_id=1", &[]).await;
+        assert_ne!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let (status, headers, body) = get(&app, "/positions?tag=global&post_id=1", &[]).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(headers.contains_key(header::RETRY_AFTER));
+        assert!(is_html_page(&headers, &body), "{body}");
+
+        let htmx = [("HX-Request", "true")];
+        let (status, headers, body) = get(&app, "/positions?tag=global&post_id=1", &htmx).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(headers.contains_key(header::RETRY_AFTER));
+        assert!(body.starts_with("<p"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn api_is_rate_limited_with_json() {
+        let app = app(1);
+        let (status, _, _) = send(&app, Method::POST, "/api/v0/user/create", &[], "").await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _, body) = send(&app, Method::POST, "/api/v0/user/create", &[], "").await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["code"], "rate_limited");
+    }
+
+    /// The token of the device link in a rendered QR code section
+    fn device_link_token(body: &str) -> &str {
+        let start = body.find("/merge/").expect("a device link") + "/merge/".len();
+        let end = start + body[start..].find('"').unwrap();
+        &body[start..end]
+    }
+
+    #[tokio::test]
+    async fn device_links_are_only_created_on_request() {
+        let repo: DynRepository = Arc::new(MemoryRepository::new());
+        let app = app_with(repo.clone(), 30);
+        let (user, cookie) = signed_in(repo.as_ref()).await;
+
+        let (status, _, body) = get(&app, "/options", &[("Cookie", &cookie)]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!body.contains("/merge/"), "{body}");
+
+        let (status, _, body) = post_form(&app, "/options/device_link", &cookie, "").await;
+        assert_eq!(status, StatusCode::OK);
+        let first = device_link_token(&body).to_string();
+        let link_user = user_from_link(&first, repo.as_ref()).await.unwrap();
+        assert_eq!(link_user.map(|link_user| link_user.id), Some(user.id));
+
+        // a new code replaces the previous one
+        let (_, _, body) = post_form(&app, "/options/device_link", &cookie, "").await;
+        let second = device_link_token(&body);
+        assert!(user_from_link(&first, repo.as_ref())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(user_from_link(second, repo.as_ref())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn changing_credentials_needs_the_current_password() {
+        let repo: DynRepository = Arc::new(MemoryRepository::new());
+        let app = app_with(repo.clone(), 30);
+        let (user, cookie) = signed_in(repo.as_ref()).await;
+        let other_session = hash_secret("other device");
+        repo.create_session(user.id, &other_session).await.unwrap();
+
+        // the first credentials need no password
+        let form = "username=alice&password=first+password";
+        let (status, _, _) = post_form(&app, "/options/credentials", &cookie, form).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(repo.use_session(&other_session).await.unwrap().is_none());
+
+        for form in [
+            "username=mallory&password=second+password",
+            "username=mallory&current_password=wrong+password&password=second+password",
+        ] {
+            let (status, _, body) = post_form(&app, "/options/credentials", &cookie, form).await;
+            assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+            assert!(body.contains("current_password"), "{body}");
+        }
+        assert_eq!(
+            repo.get_username(user.id).await.unwrap().as_deref(),
+            Some("alice")
+        );
+
+        repo.create_session(user.id, &other_session).await.unwrap();
+        let form = "username=alice2&current_password=first+password&password=second+password";
+        let (status, _, _) = post_form(&app, "/options/credentials", &cookie, form).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(repo.use_session(&other_session).await.unwrap().is_none());
+        let signed_in_user = user_from_credentials("alice2", "second password", repo.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(signed_in_user.map(|user| user.id), Some(user.id));
+    }
+
+    #[tokio::test]
+    async fn optional_api_auth_rejects_bad_tokens() {
+        let repo: DynRepository = Arc::new(MemoryRepository::new());
+        let app = app_with(repo.clone(), 30);
+        let (user, _) = signed_in(repo.as_ref()).await;
+        let (_, vote_token) =
+            create_api_token(&user, "bot", &[ApiScope::Vote], None, repo.as_ref())
+                .await
+                .unwrap();
+        let (_, read_token) =
+            create_api_token(&user, "bot", &[ApiScope::Read], None, repo.as_ref())
+                .await
+                .unwrap();
+
+        let (status, _, _) = get(&app, "/api/v0/frontpage", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        let read = format!("Bearer {read_token}");
+        let (status, _, _) = get(&app, "/api/v0/frontpage", &[("Authorization", &read)]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        for (authorization, expected) in [
+            ("Bearer no-such-token".to_string(), StatusCode::UNAUTHORIZED),
+            ("Basic dXNlcjpwYXNz".to_string(), StatusCode::UNAUTHORIZED),
+            (format!("Bearer {vote_token}"), StatusCode::FORBIDDEN),
+        ] {
+            let headers = [("Authorization", authorization.as_str())];
+            let (status, _, _) = get(&app, "/api/v0/frontpage", &headers).await;
+            assert_eq!(status, expected, "{authorization}");
+        }
+    }
+
+    #[tokio::test]
+    async fn export_downloads_the_data_of_the_user() {
+        let repo: DynRepository = Arc::new(MemoryRepository::new());
+        let app = app_with(repo.clone(), 30);
+        let (user, cookie) = signed_in(repo.as_ref()).await;
+        repo.create_post(&["test".to_string()], None, "my post", user.id)
+            .await
+            .unwrap();
+
+        let (_, _, options) = get(&app, "/options", &[("Cookie", &cookie)]).await;
+        assert!(
+            options.contains(r#"href="/options/export" hx-boost="false" download"#),
+            "{options}"
+        );
+
+        let (status, headers, body) = get(&app, "/options/export", &[("Cookie", &cookie)]).await;
+        assert_eq!(status, StatusCode::OK);
+        let disposition = headers[header::CONTENT_DISPOSITION].to_str().unwrap();
+        assert!(disposition.starts_with("attachment"), "{disposition}");
+        let export: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(export["user_id"], user.id);
+        assert_eq!(export["posts"][0]["content"], "my post");
+        assert_eq!(export["sessions"].as_array().unwrap().len(), 1);
+
+        let (status, _, _) = get(&app, "/options/export", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn deleting_the_account_signs_out_and_erases_posts() {
+        let repo: DynRepository = Arc::new(MemoryRepository::new());
+        let app = app_with(repo.clone(), 30);
+        let (user, cookie) = signed_in(repo.as_ref()).await;
+        let post_id = repo
+            .create_post(&["test".to_string()], None, "my post", user.id)
+            .await
+            .unwrap();
+
+        let (status, headers, _) =
+            post_form(&app, "/options/delete_account", &cookie, "erase_posts=true").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["HX-Location"], "/");
+
+        let post = repo.get_post(post_id).await.unwrap().unwrap();
+        assert_ne!(post.author_id, user.id);
+        assert_eq!(post.content, DELETED_POST_CONTENT);
+        assert!(!repo.user_exists(user.id).await.unwrap());
+        let (status, _, _) = get(&app, "/options/export", &[("Cookie", &cookie)]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn votes_succeed_when_top_notes_cannot_be_loaded() {
+        // every connection to `sqlite::memory:` opens a separate database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let repo: DynRepository = Arc::new(SqliteRepository::new(pool.clone()));
+        let app = app_with(repo.clone(), 30);
+        let (author, _) = signed_in(repo.as_ref()).await;
+        let (_, cookie) = signed_in(repo.as_ref()).await;
+        let post_id = repo
+            .create_post(&["test".to_string()], None, "a post", author.id)
+            .await
+            .unwrap();
+        // finding the top note reads this view
+        sqlx::query("drop view current_informed_tally")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let form = format!("tag=test&post_id={post_id}&direction=Down&state=Neutral");
+        let (status, _, _) = post_form(&app, "/vote", &cookie, &form).await;
+        assert_eq!(status, StatusCode::OK);
+        // the upvote of the author and the downvote
+        let tally = repo.current_tally("test", post_id).await.unwrap();
+        assert_eq!((tally.upvotes, tally.total), (1, 2));
+    }
 }
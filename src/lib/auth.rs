@@ -1,81 +1,380 @@
 //! Authentication & user management
+//!
+//! Every user has a secret, for the API and for signing in on another device via
+//! `/merge/{secret}`. Browsers get a session per device instead: the `session` cookie holds a
+//! random token, which can be revoked from the options page. On request, the options page also
+//! creates a short-lived device link, so that its QR code does not contain the secret. The database only
+//! stores SHA-256 hashes of secrets and tokens, see [hash_secret].
+//!
+//! Users can add a username and password to sign in without a device link. Passwords are hashed
+//! with argon2.
+//!
+//! Bots use API tokens instead of the secret. Each token has a subset of [ApiScope]s and an
+//! optional expiry, and can be revoked on the options page without affecting the account.
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use http::header::{AUTHORIZATION, COOKIE};
+use http::HeaderMap;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use sqlx::SqlitePool;
+use sha2::{Digest, Sha256};
 use tower_cookies::cookie::time::Duration;
+use tower_cookies::cookie::SameSite;
 use tower_cookies::{Cookie, Cookies};
 
-use crate::structs::User;
+use crate::repository::Repository;
+use crate::structs::{ApiScope, ApiToken, DeletionReport, Session, User};
 
-const COOKIE_MAX_AGE: Duration = Duration::days(10 * 365);
+const SESSION_COOKIE: &str = "session";
+/// Cookie of older versions, which held the secret itself. It is replaced by a session.
+const LEGACY_SECRET_COOKIE: &str = "secret";
+const COOKIE_MAX_AGE: Duration = Duration::days(365);
 
-/// If logged in with a secret, will return a [User]
-pub async fn user_from_cookies(cookies: &Cookies, pool: &SqlitePool) -> Result<Option<User>> {
-    Ok(match cookies.get("secret") {
-        Some(cookie) => user_from_secret(cookie.value(), pool).await?,
-        None => None,
-    })
+const SECRET_LENGTH: usize = 16;
+const TOKEN_LENGTH: usize = 32;
+/// Attempts to find a secret that is not in use yet
+const SECRET_GENERATION_ATTEMPTS: usize = 5;
+
+pub const DEVICE_LINK_VALID_MINUTES: i64 = 15;
+
+/// The session of this device, if signed in
+pub async fn session_from_cookies(
+    cookies: &Cookies,
+    repo: &dyn Repository,
+) -> Result<Option<Session>> {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE) {
+        if let Some(session) = repo.use_session(&hash_secret(cookie.value())).await? {
+            return Ok(Some(session));
+        }
+    }
+
+    if let Some(cookie) = cookies.get(LEGACY_SECRET_COOKIE) {
+        let secret = cookie.value().to_string();
+        cookies.remove(Cookie::build(LEGACY_SECRET_COOKIE, "").path("/").finish());
+        if let Some(user) = user_from_secret(&secret, repo).await? {
+            return Ok(Some(sign_in(&user, cookies, repo).await?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// If logged in with a session, will return a [User]
+pub async fn user_from_cookies(cookies: &Cookies, repo: &dyn Repository) -> Result<Option<User>> {
+    Ok(session_from_cookies(cookies, repo)
+        .await?
+        .map(|session| User {
+            id: session.user_id,
+        }))
+}
+
+/// The [User] of the session cookie, the legacy secret cookie or the bearer token in `headers`,
+/// for middleware that runs outside of the cookie layer. Unlike [user_from_cookies], it does not
+/// upgrade legacy cookies, that happens when the request reaches the cookie layer.
+pub async fn user_from_headers(headers: &HeaderMap, repo: &dyn Repository) -> Result<Option<User>> {
+    if let Some(token) = cookie_from_headers(headers, SESSION_COOKIE) {
+        if let Some(session) = repo.use_session(&hash_secret(&token)).await? {
+            return Ok(Some(User {
+                id: session.user_id,
+            }));
+        }
+    }
+
+    if let Some(secret) = cookie_from_headers(headers, LEGACY_SECRET_COOKIE) {
+        if let Some(user) = user_from_secret(&secret, repo).await? {
+            return Ok(Some(user));
+        }
+    }
+
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match bearer {
+        Some(token) => Ok(user_from_bearer(token, repo)
+            .await?
+            .map(|(user, _scopes)| user)),
+        None => Ok(None),
+    }
+}
+
+fn cookie_from_headers(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(|cookie| cookie.ok())
+        .find(|cookie| cookie.name() == name)
+        .map(|cookie| cookie.value().to_string())
 }
 
 /// returns [User] via secret
-pub async fn user_from_secret(secret: &str, pool: &SqlitePool) -> Result<Option<User>> {
-    Ok(sqlx::query_as!(
-        User,
-        "SELECT id, secret from users WHERE secret = ?",
-        secret
-    )
-    .fetch_optional(pool)
-    .await?)
+pub async fn user_from_secret(secret: &str, repo: &dyn Repository) -> Result<Option<User>> {
+    Ok(repo
+        .user_id_from_secret_hash(&hash_secret(secret))
+        .await?
+        .map(|id| User { id }))
+}
+
+/// Hex encoded SHA-256 hash of `secret`, as stored in `users.secret_hash`. Generated secrets are
+/// random enough that a salted, slow hash would not make them harder to guess, and a plain hash
+/// can be looked up with an index on every request.
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
 }
 
 /// returns logged in [User] or creates a new one and returns that
-pub async fn get_or_create_user(cookies: &Cookies, pool: &SqlitePool) -> Result<User> {
-    let existing_user: Option<User> = user_from_cookies(cookies, pool).await?;
+pub async fn get_or_create_user(cookies: &Cookies, repo: &dyn Repository) -> Result<User> {
+    let existing_user: Option<User> = user_from_cookies(cookies, repo).await?;
 
     Ok(match existing_user {
         Some(user) => user,
         None => {
-            let user = create_user(pool).await?;
-            let cookie = Cookie::build("secret", user.secret.to_owned())
-                .max_age(COOKIE_MAX_AGE)
-                .finish();
-            cookies.add(cookie);
-
+            let (user, _secret) = create_user(repo).await?;
+            sign_in(&user, cookies, repo).await?;
             user
         }
     })
 }
 
-/// Creates a new [User] inside the database and return it
-pub async fn create_user(pool: &SqlitePool) -> Result<User> {
-    let secret = generate_user_secret();
-    let user =
-        sqlx::query_as::<_, User>("INSERT INTO users (secret) VALUES (?) RETURNING id, secret")
-            .bind(secret)
-            .fetch_one(pool)
-            .await?;
+/// Creates a new [User] inside the database and returns it with its secret
+pub async fn create_user(repo: &dyn Repository) -> Result<(User, String)> {
+    let secret = generate_unused_secret(repo).await?;
+    let id = repo.create_user(&hash_secret(&secret)).await?;
+    Ok((User { id }, secret))
+}
+
+/// Starts a new session for this device. Other sessions of the device's previous user stay.
+pub async fn sign_in(user: &User, cookies: &Cookies, repo: &dyn Repository) -> Result<Session> {
+    let token = generate_token(TOKEN_LENGTH);
+    let session = repo.create_session(user.id, &hash_secret(&token)).await?;
+
+    // set the path explicitly, since the request may come from e.g. /merge
+    let cookie = Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .max_age(COOKIE_MAX_AGE)
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .finish();
+    cookies.add(cookie);
 
-    Ok(user)
+    Ok(session)
 }
 
-/// Changes the cookie containing the secret to a different value
-// pub fn change_auth_cookie(secret: &str, cookies: &Cookies) {
-//     if let Some(mut cookie) = cookies.get("secret") {
-//         // copy old cookie, but also set path, since it may come from e.g. /merge
-//         cookie.set_value(secret);
-//         cookie.set_path("/");
-//         cookie.set_max_age(COOKIE_MAX_AGE);
-//         cookies.add(cookie.into_owned());
-//     }
-// }
+/// Ends the session of this device
+pub async fn sign_out(cookies: &Cookies, repo: &dyn Repository) -> Result<()> {
+    if let Some(session) = session_from_cookies(cookies, repo).await? {
+        repo.delete_session(session.user_id, session.id).await?;
+    }
+    cookies.remove(Cookie::build(SESSION_COOKIE, "").path("/").finish());
+    Ok(())
+}
+
+/// Usernames are case-insensitive
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Adds or changes the username and password of `user`. Anonymous users keep their votes. The
+/// username must be normalized and not be taken by another user. Device links and all sessions
+/// except `current_session` stop working.
+pub async fn set_credentials(
+    user: &User,
+    current_session: &Session,
+    username: &str,
+    password: &str,
+    repo: &dyn Repository,
+) -> Result<()> {
+    let password = password.to_string();
+    // argon2 is slow on purpose, so it must not block the async runtime
+    let password_hash = tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|error| anyhow!("Unable to hash password: {error}"))
+    })
+    .await??;
+
+    repo.set_credentials(user.id, username, &password_hash)
+        .await?;
+    repo.delete_other_sessions(user.id, current_session.id)
+        .await?;
+    repo.delete_device_links(user.id).await
+}
 
-fn generate_user_secret() -> String {
-    // TODO: check if used already
+/// The [User] with this username, if the password matches
+pub async fn user_from_credentials(
+    username: &str,
+    password: &str,
+    repo: &dyn Repository,
+) -> Result<Option<User>> {
+    let Some((user_id, password_hash)) =
+        repo.get_credentials(&normalize_username(username)).await?
+    else {
+        return Ok(None);
+    };
+
+    let password = password.to_string();
+    let matches = tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .map_err(|error| anyhow!("Invalid password hash: {error}"))
+    })
+    .await??;
+
+    Ok(matches.then_some(User { id: user_id }))
+}
+
+/// Returns a token for `/merge/{token}` that signs in another device. It works once, for
+/// [DEVICE_LINK_VALID_MINUTES]. Earlier links of the user stop working.
+pub async fn create_device_link(user: &User, repo: &dyn Repository) -> Result<String> {
+    repo.delete_device_links(user.id).await?;
+    let token = generate_token(TOKEN_LENGTH);
+    repo.create_device_link(user.id, &hash_secret(&token), DEVICE_LINK_VALID_MINUTES)
+        .await?;
+    Ok(token)
+}
+
+/// The [User] of a device link or, for links that contain it, of a secret
+pub async fn user_from_link(secret: &str, repo: &dyn Repository) -> Result<Option<User>> {
+    match repo.device_link_user_id(&hash_secret(secret)).await? {
+        Some(id) => Ok(Some(User { id })),
+        None => user_from_secret(secret, repo).await,
+    }
+}
+
+/// Makes sure a device link is not used again
+pub async fn consume_link(secret: &str, repo: &dyn Repository) -> Result<()> {
+    repo.delete_device_link(&hash_secret(secret)).await
+}
+
+/// Replaces the secret of `user` and returns the new one. The old secret, device links and all
+/// sessions except `current_session` stop working.
+pub async fn rotate_secret(
+    user: &User,
+    current_session: &Session,
+    repo: &dyn Repository,
+) -> Result<String> {
+    let secret = generate_unused_secret(repo).await?;
+    repo.set_secret_hash(user.id, &hash_secret(&secret)).await?;
+    repo.delete_other_sessions(user.id, current_session.id)
+        .await?;
+    repo.delete_device_links(user.id).await?;
+    Ok(secret)
+}
+
+/// Creates an API token for `user` and returns it with the token itself, which is only stored as
+/// a hash. Tokens without `valid_days` don't expire.
+pub async fn create_api_token(
+    user: &User,
+    name: &str,
+    scopes: &[ApiScope],
+    valid_days: Option<i64>,
+    repo: &dyn Repository,
+) -> Result<(ApiToken, String)> {
+    let token = generate_token(TOKEN_LENGTH);
+    let api_token = repo
+        .create_api_token(
+            user.id,
+            name,
+            &hash_secret(&token),
+            &ApiScope::join(scopes),
+            valid_days,
+        )
+        .await?;
+    Ok((api_token, token))
+}
+
+/// The [User] of a bearer token and the scopes it grants. An API token grants its own scopes.
+/// Account secrets are still accepted for older bots and grant all of them.
+pub async fn user_from_bearer(
+    token: &str,
+    repo: &dyn Repository,
+) -> Result<Option<(User, Vec<ApiScope>)>> {
+    if let Some(api_token) = repo.api_token_from_hash(&hash_secret(token)).await? {
+        let user = User {
+            id: api_token.user_id,
+        };
+        return Ok(Some((user, api_token.scopes())));
+    }
+
+    Ok(user_from_secret(token, repo)
+        .await?
+        .map(|user| (user, ApiScope::ALL.to_vec())))
+}
+
+/// Deletes `user` and signs out this device. Posts and votes remain under a tombstone user, see
+/// [Repository::delete_user].
+pub async fn delete_account(
+    user: &User,
+    erase_posts: bool,
+    cookies: &Cookies,
+    repo: &dyn Repository,
+) -> Result<DeletionReport> {
+    // nobody knows the secret of the tombstone, so nobody can sign in to it
+    let tombstone_secret_hash = hash_secret(&generate_token(TOKEN_LENGTH));
+    let report = repo
+        .delete_user(user.id, &tombstone_secret_hash, erase_posts)
+        .await?;
+    cookies.remove(Cookie::build(SESSION_COOKIE, "").path("/").finish());
+    Ok(report)
+}
+
+async fn generate_unused_secret(repo: &dyn Repository) -> Result<String> {
+    for _ in 0..SECRET_GENERATION_ATTEMPTS {
+        let secret = generate_token(SECRET_LENGTH);
+        if repo
+            .user_id_from_secret_hash(&hash_secret(&secret))
+            .await?
+            .is_none()
+        {
+            return Ok(secret);
+        }
+    }
+    bail!("Unable to generate an unused secret")
+}
+
+fn generate_token(length: usize) -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
-        .take(16)
+        .take(length)
         .map(char::from)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+    use crate::repository::memory::MemoryRepository;
+
+    #[tokio::test]
+    async fn user_from_headers_reads_legacy_cookies() {
+        let repo = MemoryRepository::new();
+        let user_id = repo
+            .create_user(&hash_secret("legacysecret"))
+            .await
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_static("csrf=x; secret=legacysecret"),
+        );
+        let user = user_from_headers(&headers, &repo).await.unwrap();
+        assert_eq!(user.map(|user| user.id), Some(user_id));
+
+        headers.insert(COOKIE, HeaderValue::from_static("secret=wrong"));
+        assert!(user_from_headers(&headers, &repo).await.unwrap().is_none());
+    }
+}
@@ -1,36 +1,143 @@
+use std::marker::PhantomData;
+
 use anyhow::Result;
 use async_trait::async_trait;
 use axum::extract::FromRequestParts;
-use axum::http::StatusCode;
-use axum::Extension;
+use axum::headers::authorization::Bearer;
+use axum::headers::Authorization;
+use axum::{Extension, TypedHeader};
+use http::header::AUTHORIZATION;
 use http::request::Parts;
-use sqlx::SqlitePool;
 use tower_cookies::Cookies;
 
-use crate::auth::user_from_cookies;
-use crate::structs::User;
+use crate::auth::{user_from_bearer, user_from_cookies};
+use crate::error::AppError;
+use crate::repository::DynRepository;
+use crate::structs::{ApiScope, User};
 
 #[async_trait]
 impl<S> FromRequestParts<S> for User
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = AppError;
 
     async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
         use axum::RequestPartsExt;
-        let Extension(pool) = parts
-            .extract::<Extension<SqlitePool>>()
+        let Extension(repo) = parts
+            .extract::<Extension<DynRepository>>()
             .await
-            .expect("Unable to get sqlite connection");
+            .expect("Unable to get repository");
         let cookies = parts
             .extract::<Cookies>()
             .await
             .expect("Unable to get cookies");
 
-        match user_from_cookies(&cookies, &pool).await {
-            Ok(result) => result.ok_or((StatusCode::UNAUTHORIZED, "Unauthorized")),
-            Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")),
+        match user_from_cookies(&cookies, repo.as_ref()).await {
+            Ok(result) => result.ok_or_else(AppError::not_signed_in),
+            Err(error) => Err(AppError::Internal(error)),
         }
     }
 }
+
+/// The [ApiScope] an [ApiUser] needs
+pub trait RequiredScope {
+    const SCOPE: ApiScope;
+}
+
+pub struct ReadScope;
+pub struct VoteScope;
+pub struct PostScope;
+pub struct AccountScope;
+
+impl RequiredScope for ReadScope {
+    const SCOPE: ApiScope = ApiScope::Read;
+}
+
+impl RequiredScope for VoteScope {
+    const SCOPE: ApiScope = ApiScope::Vote;
+}
+
+impl RequiredScope for PostScope {
+    const SCOPE: ApiScope = ApiScope::Post;
+}
+
+impl RequiredScope for AccountScope {
+    const SCOPE: ApiScope = ApiScope::Account;
+}
+
+/// The [User] of the `Authorization: Bearer` header, if its token grants scope `S`, see
+/// [user_from_bearer]
+pub struct ApiUser<S> {
+    pub user: User,
+    scope: PhantomData<S>,
+}
+
+#[async_trait]
+impl<S, St> FromRequestParts<St> for ApiUser<S>
+where
+    S: RequiredScope,
+    St: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &St) -> Result<Self, Self::Rejection> {
+        use axum::RequestPartsExt;
+        let Extension(repo) = parts
+            .extract::<Extension<DynRepository>>()
+            .await
+            .expect("Unable to get repository");
+        let TypedHeader(Authorization(bearer)) = parts
+            .extract::<TypedHeader<Authorization<Bearer>>>()
+            .await
+            .map_err(|_| invalid_bearer_token())?;
+
+        match user_from_bearer(bearer.token(), repo.as_ref()).await {
+            Ok(Some((user, scopes))) if scopes.contains(&S::SCOPE) => Ok(ApiUser {
+                user,
+                scope: PhantomData,
+            }),
+            Ok(Some(_)) => Err(AppError::Forbidden(format!(
+                "This token lacks the {} scope",
+                S::SCOPE
+            ))),
+            Ok(None) => Err(invalid_bearer_token()),
+            Err(error) => Err(AppError::Internal(error)),
+        }
+    }
+}
+
+/// The [User] of the `Authorization: Bearer` header, or `None` without the header. Unlike
+/// `Option<ApiUser<S>>`, a token that is invalid or lacks scope `S` fails the request, so that
+/// clients learn about it instead of getting anonymous responses.
+pub struct OptionalApiUser<S> {
+    pub user: Option<User>,
+    scope: PhantomData<S>,
+}
+
+#[async_trait]
+impl<S, St> FromRequestParts<St> for OptionalApiUser<S>
+where
+    S: RequiredScope,
+    St: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &St) -> Result<Self, Self::Rejection> {
+        let user = if parts.headers.contains_key(AUTHORIZATION) {
+            let ApiUser { user, .. } = ApiUser::<S>::from_request_parts(parts, state).await?;
+            Some(user)
+        } else {
+            None
+        };
+        Ok(OptionalApiUser {
+            user,
+            scope: PhantomData,
+        })
+    }
+}
+
+/// No bearer token, or one that is unknown, revoked or expired
+fn invalid_bearer_token() -> AppError {
+    AppError::Unauthorized("Missing or invalid bearer token".to_string())
+}
@@ -0,0 +1,242 @@
+//! Errors of request handlers and extractors, with their HTTP status and a stable
+//! machine-readable code
+//!
+//! [AppError] renders as JSON, which is what `/api/v0` returns:
+//! `{"error": "<message>", "code": "<code>", "field": "<field>"}`, where `field` is only present
+//! for [ValidationError]s. The site replaces that with HTML, see `error::error_pages` in the
+//! server, which finds the error in the [ErrorDetails] of the response.
+
+use std::fmt;
+use std::sync::Arc;
+
+use axum::{
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use http::{HeaderValue, StatusCode};
+use serde_json::json;
+
+#[derive(Debug)]
+pub enum AppError {
+    /// The requested resource does not exist (404)
+    NotFound(String),
+    /// No or no valid session or bearer token (401)
+    Unauthorized(String),
+    /// Signed in, but not allowed (403)
+    Forbidden(String),
+    /// Invalid user input (400, 404, 409 or 422, see [ValidationError::status])
+    Validation(ValidationError),
+    /// The request contradicts the current state (409)
+    Conflict(String),
+    /// Too many requests (429)
+    RateLimited {
+        /// Seconds until the client may try again
+        retry_after: u64,
+    },
+    /// Everything else. Details are logged, but not shown. (500)
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    /// For pages and forms that need a session
+    pub fn not_signed_in() -> AppError {
+        AppError::Unauthorized("You are not signed in. Please reload the page.".to_string())
+    }
+
+    /// For URLs without a page or file
+    pub fn page_not_found() -> AppError {
+        AppError::NotFound("This page does not exist.".to_string())
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Validation(validation_error) => validation_error.status(),
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier for clients, which unlike the message doesn't change with the wording
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound(_) => "not_found",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::Validation(validation_error) => validation_error.code(),
+            AppError::Conflict(_) => "conflict",
+            AppError::RateLimited { .. } => "rate_limited",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    /// Shown to the user
+    pub fn message(&self) -> String {
+        match self {
+            AppError::NotFound(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::Conflict(message) => message.clone(),
+            AppError::Validation(validation_error) => validation_error.message().to_string(),
+            AppError::RateLimited { retry_after } => {
+                format!("Too many requests, try again in {retry_after} seconds")
+            }
+            AppError::Internal(_) => "Internal Server Error".to_string(),
+        }
+    }
+
+    /// The form or JSON field that failed validation
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            AppError::Validation(validation_error) => Some(validation_error.field()),
+            _ => None,
+        }
+    }
+
+    /// Adds the headers that belong to the status, for every rendering of the error. The
+    /// `WWW-Authenticate` challenge is only for the JSON of the API, see [AppError::into_response].
+    pub fn with_headers(&self, mut response: Response) -> Response {
+        if let AppError::RateLimited { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, (*retry_after).into());
+        }
+        response
+    }
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    /// The request contradicts itself (400)
+    BadRequest {
+        field: &'static str,
+        message: String,
+    },
+    /// A referenced post does not exist (404)
+    NotFound {
+        field: &'static str,
+        message: String,
+    },
+    /// A value is not acceptable (422)
+    Invalid {
+        field: &'static str,
+        message: String,
+    },
+    /// A value is already in use (409)
+    Conflict {
+        field: &'static str,
+        message: String,
+    },
+}
+
+impl ValidationError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ValidationError::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            ValidationError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ValidationError::Invalid { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ValidationError::Conflict { .. } => StatusCode::CONFLICT,
+        }
+    }
+
+    /// Same as the codes of [AppError] with the same status
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationError::BadRequest { .. } | ValidationError::Invalid { .. } => {
+                "validation_failed"
+            }
+            ValidationError::NotFound { .. } => "not_found",
+            ValidationError::Conflict { .. } => "conflict",
+        }
+    }
+
+    /// Name of the form or JSON field that failed the check
+    pub fn field(&self) -> &'static str {
+        match self {
+            ValidationError::BadRequest { field, .. }
+            | ValidationError::NotFound { field, .. }
+            | ValidationError::Invalid { field, .. }
+            | ValidationError::Conflict { field, .. } => field,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            ValidationError::BadRequest { message, .. }
+            | ValidationError::NotFound { message, .. }
+            | ValidationError::Invalid { message, .. }
+            | ValidationError::Conflict { message, .. } => message,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field(), self.message())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Carried by responses made from an [AppError], so that the site can render it as HTML
+#[derive(Clone)]
+pub struct ErrorDetails(pub Arc<AppError>);
+
+// Tell axum how to convert `AppError` into a response.
+// https://github.com/tokio-rs/axum/discussions/713
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if let AppError::Internal(inner) = &self {
+            tracing::error!("{inner:#}");
+            tracing::debug!("stacktrace: {}", inner.backtrace());
+        }
+
+        let mut body = json!({
+            "error": self.message(),
+            "code": self.code(),
+        });
+        if let Some(field) = self.field() {
+            body["field"] = json!(field);
+        }
+        let mut response = self.with_headers((self.status(), Json(body)).into_response());
+        // API clients authenticate with bearer tokens, the site with a session cookie
+        if let AppError::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+            .extensions_mut()
+            .insert(ErrorDetails(Arc::new(self)));
+        response
+    }
+}
+
+// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
+// `Result<_, AppError>`. That way you don't need to do that manually. Validation errors keep
+// their status, and unique constraints that fail because of a concurrent request are conflicts.
+impl<E> From<E> for AppError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        let err = err.into();
+        let err = match err.downcast::<ValidationError>() {
+            Ok(validation_error) => return AppError::Validation(validation_error),
+            Err(err) => err,
+        };
+        if let Some(sqlx::Error::Database(database_error)) = err.downcast_ref::<sqlx::Error>() {
+            if database_error.is_unique_violation() {
+                return AppError::Conflict(
+                    "This was changed at the same time, please reload the page and try again."
+                        .to_string(),
+                );
+            }
+        }
+        AppError::Internal(err)
+    }
+}
@@ -0,0 +1,217 @@
+//! Safe Markdown subset for post content
+//!
+//! Only paragraphs, line breaks, links, emphasis, lists, block quotes and code are rendered.
+//! The HTML is generated from an allowlist of parser events, everything else (including raw
+//! HTML) is escaped and shown as text. Headings are shown as bold paragraphs, images as links.
+
+use maud::{Markup, PreEscaped, Render};
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+use crate::references::{split_post_references, ContentSegment};
+
+/// Links with other schemes, like `javascript:`, are rendered as plain text
+const ALLOWED_URL_PREFIXES: [&str; 3] = ["https://", "http://", "mailto:"];
+
+/// Renders `content` as HTML. Without `links`, links are shown as their text, for content that
+/// is displayed inside a link itself. `post_reference` renders `#post:123` references and
+/// returns None to keep a reference as text.
+pub fn render_markdown(
+    content: &str,
+    links: bool,
+    post_reference: &dyn Fn(i64) -> Option<Markup>,
+) -> Markup {
+    let mut html = String::new();
+    let mut in_code_block = false;
+    // references are rendered as text inside links, links can't be nested
+    let mut in_link = false;
+
+    // no extensions: tables, footnotes, strikethrough and task lists stay plain text
+    for event in Parser::new_ext(content, Options::empty()) {
+        match event {
+            Event::Start(tag) => {
+                match tag {
+                    Tag::CodeBlock(_) => in_code_block = true,
+                    Tag::Link(..) | Tag::Image(..) => in_link = true,
+                    _ => {}
+                }
+                html.push_str(&start_tag(&tag, links));
+            }
+            Event::End(tag) => {
+                match tag {
+                    Tag::CodeBlock(_) => in_code_block = false,
+                    Tag::Link(..) | Tag::Image(..) => in_link = false,
+                    _ => {}
+                }
+                html.push_str(end_tag(&tag, links));
+            }
+            Event::Text(text) if in_code_block => text.render_to(&mut html),
+            Event::Text(text) => {
+                for segment in split_post_references(&text) {
+                    match segment {
+                        ContentSegment::Text(text) => text.render_to(&mut html),
+                        ContentSegment::PostReference(post_id) => {
+                            match post_reference(post_id).filter(|_| !in_link) {
+                                Some(markup) => markup.render_to(&mut html),
+                                None => format!("#post:{post_id}").render_to(&mut html),
+                            }
+                        }
+                    }
+                }
+            }
+            Event::Code(code) => {
+                html.push_str("<code>");
+                code.render_to(&mut html);
+                html.push_str("</code>");
+            }
+            Event::Html(raw) => raw.render_to(&mut html),
+            // users write plain text with line breaks more often than Markdown
+            Event::SoftBreak | Event::HardBreak => html.push_str("<br>"),
+            Event::Rule | Event::FootnoteReference(_) | Event::TaskListMarker(_) => {}
+        }
+    }
+
+    PreEscaped(html)
+}
+
+fn is_allowed_url(url: &str) -> bool {
+    let url = url.trim().to_lowercase();
+    ALLOWED_URL_PREFIXES
+        .iter()
+        .any(|prefix| url.starts_with(prefix))
+}
+
+fn start_tag(tag: &Tag, links: bool) -> String {
+    match tag {
+        Tag::Paragraph => "<p>".to_string(),
+        Tag::Heading(..) => "<p><strong>".to_string(),
+        Tag::BlockQuote => "<blockquote>".to_string(),
+        Tag::CodeBlock(_) => "<pre><code>".to_string(),
+        Tag::List(Some(start)) => format!("<ol start=\"{start}\">"),
+        Tag::List(None) => "<ul>".to_string(),
+        Tag::Item => "<li>".to_string(),
+        Tag::Emphasis => "<em>".to_string(),
+        Tag::Strong => "<strong>".to_string(),
+        Tag::Link(_, url, _) | Tag::Image(_, url, _) if links && is_allowed_url(url) => {
+            let mut html = "<a href=\"".to_string();
+            url.trim().render_to(&mut html);
+            html.push_str("\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">");
+            html
+        }
+        _ => String::new(),
+    }
+}
+
+fn end_tag(tag: &Tag, links: bool) -> &'static str {
+    match tag {
+        Tag::Paragraph => "</p>",
+        Tag::Heading(..) => "</strong></p>",
+        Tag::BlockQuote => "</blockquote>",
+        Tag::CodeBlock(_) => "</code></pre>",
+        Tag::List(Some(_)) => "</ol>",
+        Tag::List(None) => "</ul>",
+        Tag::Item => "</li>",
+        Tag::Emphasis => "</em>",
+        Tag::Strong => "</strong>",
+        Tag::Link(_, url, _) | Tag::Image(_, url, _) if links && is_allowed_url(url) => "</a>",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(content: &str) -> String {
+        render_markdown(content, true, &|_| None).into_string()
+    }
+
+    #[test]
+    fn renders_allowed_links() {
+        assert_eq!(
+            render("[y](https://example.com/?a=1&b=2)"),
+            "<p><a href=\"https://example.com/?a=1&amp;b=2\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">y</a></p>",
+        );
+        assert_eq!(
+            render_markdown("[y](https://example.com)", false, &|_| None).into_string(),
+            "<p>y</p>",
+        );
+    }
+
+    #[test]
+    fn drops_script_links() {
+        for content in [
+            "[click](javascript:alert(1))",
+            "[click](JavaScript:alert(1))",
+            "[click](data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==)",
+            "<javascript:alert(1)>",
+        ] {
+            let html = render(content);
+            assert!(!html.contains("<a"), "{content} rendered as {html}");
+            assert!(!html.contains("href"), "{content} rendered as {html}");
+        }
+        assert_eq!(render("[click](javascript:alert(1))"), "<p>click</p>");
+    }
+
+    #[test]
+    fn renders_images_as_links() {
+        assert_eq!(
+            render("![cat](https://example.com/cat.png)"),
+            "<p><a href=\"https://example.com/cat.png\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">cat</a></p>",
+        );
+        for content in [
+            "![cat](javascript:alert(1))",
+            "![cat](data:image/svg+xml;base64,PHN2Zz48L3N2Zz4=)",
+        ] {
+            let html = render(content);
+            assert_eq!(html, "<p>cat</p>", "{content}");
+        }
+    }
+
+    #[test]
+    fn escapes_inline_html() {
+        assert_eq!(
+            render("hi <script>alert(1)</script>"),
+            "<p>hi &lt;script&gt;alert(1)&lt;/script&gt;</p>",
+        );
+        assert_eq!(
+            render("<img src=x onerror=alert(1)>"),
+            "&lt;img src=x onerror=alert(1)&gt;",
+        );
+    }
+
+    #[test]
+    fn escapes_block_html() {
+        let html = render("<div onclick=\"alert(1)\">\n\nblock\n\n</div>");
+        assert!(!html.contains("<div"), "{html}");
+        assert!(
+            html.contains("&lt;div onclick=&quot;alert(1)&quot;&gt;"),
+            "{html}"
+        );
+    }
+
+    #[test]
+    fn escapes_link_urls_and_drops_titles() {
+        let html = render("[x](https://example.com/\"onmouseover=\"alert(1))");
+        assert!(!html.contains("\"onmouseover"), "{html}");
+        assert!(
+            html.contains("https://example.com/&quot;onmouseover=&quot;alert(1)"),
+            "{html}"
+        );
+
+        let html = render("[x](https://example.com \"\\\" onclick=\\\"alert(1)\")");
+        assert!(!html.contains("onclick"), "{html}");
+        assert!(!html.contains("title"), "{html}");
+    }
+
+    #[test]
+    fn escapes_link_text_and_code() {
+        assert_eq!(
+            render("`<b>` and\n\n    <i>"),
+            "<p><code>&lt;b&gt;</code> and</p><pre><code>&lt;i&gt;</code></pre>",
+        );
+        assert_eq!(
+            render("[<b>bold</b>](https://example.com)"),
+            "<p><a href=\"https://example.com\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">&lt;b&gt;bold&lt;/b&gt;</a></p>",
+        );
+    }
+}
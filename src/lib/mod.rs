@@ -1,4 +1,9 @@
 pub mod auth;
 pub mod axum_extractors;
+pub mod error;
+pub mod markdown;
+pub mod pseudonym;
+pub mod references;
+pub mod repository;
 pub mod structs;
 pub mod structs_api;
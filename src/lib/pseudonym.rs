@@ -0,0 +1,85 @@
+//! Names shown for authors
+//!
+//! Users can choose a display name on the options page. Everyone else appears under a pseudonym
+//! derived from their user id, like "Quiet Otter 1729". It stays the same across posts, so that
+//! a conversation can be followed, but says nothing about the account's credentials.
+
+use anyhow::Result;
+
+use crate::repository::Repository;
+
+const ADJECTIVES: [&str; 32] = [
+    "Amber", "Bold", "Brave", "Bright", "Calm", "Clever", "Cosmic", "Curious", "Daring", "Eager",
+    "Gentle", "Golden", "Happy", "Honest", "Humble", "Jolly", "Keen", "Kind", "Lively", "Lucky",
+    "Mellow", "Misty", "Nimble", "Patient", "Polite", "Quiet", "Rapid", "Silver", "Steady",
+    "Sunny", "Swift", "Witty",
+];
+
+const ANIMALS: [&str; 32] = [
+    "Badger", "Beaver", "Bison", "Crane", "Dolphin", "Falcon", "Ferret", "Finch", "Fox", "Gecko",
+    "Heron", "Ibis", "Koala", "Lemur", "Lynx", "Marten", "Moose", "Newt", "Ocelot", "Otter", "Owl",
+    "Panda", "Puffin", "Quail", "Raven", "Robin", "Seal", "Sparrow", "Tapir", "Walrus", "Wombat",
+    "Yak",
+];
+
+/// A bijection on 32 bits (lowbias32 by Chris Wellons), so that consecutive ids get unrelated
+/// pseudonyms, but no two ids below 2^32 get the same one
+fn mix(user_id: i64) -> u32 {
+    let mut z = user_id as u32;
+    z = (z ^ (z >> 16)).wrapping_mul(0x7feb_352d);
+    z = (z ^ (z >> 15)).wrapping_mul(0x846c_a68b);
+    z ^ (z >> 16)
+}
+
+/// The generated name of a user without display name. The two words take 10 bits of the mixed
+/// id and the number the remaining 22, so the name keeps all of them.
+pub fn pseudonym(user_id: i64) -> String {
+    let hash = mix(user_id);
+    let adjective = ADJECTIVES[(hash % ADJECTIVES.len() as u32) as usize];
+    let animal = ANIMALS[((hash >> 5) % ANIMALS.len() as u32) as usize];
+    let number = hash >> 10;
+    format!("{adjective} {animal} {number}")
+}
+
+/// Whether `name` looks like a generated pseudonym, which display names must not, so that
+/// nobody can pass as another user
+pub fn is_pseudonym(name: &str) -> bool {
+    let words: Vec<&str> = name.split_whitespace().collect();
+    match words[..] {
+        [adjective, animal, number] => {
+            ADJECTIVES
+                .iter()
+                .any(|candidate| candidate.eq_ignore_ascii_case(adjective))
+                && ANIMALS
+                    .iter()
+                    .any(|candidate| candidate.eq_ignore_ascii_case(animal))
+                && number.chars().all(|c| c.is_ascii_digit())
+        }
+        _ => false,
+    }
+}
+
+/// The display name of `user_id`, or its pseudonym
+pub async fn author_name(user_id: i64, repo: &dyn Repository) -> Result<String> {
+    Ok(repo
+        .get_display_name(user_id)
+        .await?
+        .unwrap_or_else(|| pseudonym(user_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn pseudonyms_are_distinct() {
+        let mut seen = HashSet::new();
+        for user_id in 1..=10_000 {
+            let name = pseudonym(user_id);
+            assert!(is_pseudonym(&name), "{name}");
+            assert!(seen.insert(name.clone()), "{name} is taken twice");
+        }
+    }
+}
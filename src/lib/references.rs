@@ -0,0 +1,50 @@
+//! `#post:123` references from one post to another
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static POST_REFERENCE: Lazy<Regex> = Lazy::new(|| Regex::new(r"#post:(\d+)").unwrap());
+
+/// A piece of post content, either plain text or a reference to another post
+#[derive(Debug, PartialEq)]
+pub enum ContentSegment<'a> {
+    Text(&'a str),
+    PostReference(i64),
+}
+
+/// Splits `content` at every `#post:123` reference
+pub fn split_post_references(content: &str) -> Vec<ContentSegment<'_>> {
+    let mut segments = vec![];
+    let mut text_start = 0;
+    for captures in POST_REFERENCE.captures_iter(content) {
+        let reference = captures.get(0).unwrap();
+        // ids that don't fit into an i64 stay plain text
+        let Ok(post_id) = captures[1].parse::<i64>() else {
+            continue;
+        };
+        if reference.start() > text_start {
+            segments.push(ContentSegment::Text(
+                &content[text_start..reference.start()],
+            ));
+        }
+        segments.push(ContentSegment::PostReference(post_id));
+        text_start = reference.end();
+    }
+    if text_start < content.len() {
+        segments.push(ContentSegment::Text(&content[text_start..]));
+    }
+    segments
+}
+
+/// Distinct ids of the posts referenced in `content`, in order of appearance
+pub fn parse_post_references(content: &str) -> Vec<i64> {
+    let mut post_ids: Vec<i64> = vec![];
+    for segment in split_post_references(content) {
+        if let ContentSegment::PostReference(post_id) = segment {
+            if !post_ids.contains(&post_id) {
+                post_ids.push(post_id);
+            }
+        }
+    }
+    post_ids
+}
@@ -0,0 +1,1688 @@
+//! A [Repository] that keeps all data in process memory
+//!
+//! Mirrors the semantics of the SQLite views (`current_vote`, `current_tally`,
+//! `current_informed_tally`), so pages and ranking can run without a database.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::references::parse_post_references;
+use crate::repository::{Repository, DELETED_POST_CONTENT};
+use crate::structs::{
+    ApiToken, Bookmark, DeletionReport, Direction, HistoricVote, InformedTally, MergeReport,
+    Notification, NotificationKind, Post, Session, Tally, EMPTY_TALLY,
+};
+
+#[derive(Debug, Clone)]
+struct VoteRecord {
+    rowid: i64,
+    user_id: i64,
+    tag_id: i64,
+    post_id: i64,
+    note_id: Option<i64>,
+    direction: i64,
+    created: NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+struct UserRecord {
+    id: i64,
+    secret_hash: String,
+    username: Option<String>,
+    password_hash: Option<String>,
+    display_name: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct SessionRecord {
+    session: Session,
+    token_hash: String,
+}
+
+#[derive(Debug, Clone)]
+struct DeviceLinkRecord {
+    token_hash: String,
+    user_id: i64,
+    expires: NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+struct ApiTokenRecord {
+    token: ApiToken,
+    token_hash: String,
+    expires: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone)]
+struct NotificationRecord {
+    user_id: i64,
+    notification: Notification,
+}
+
+#[derive(Default)]
+struct State {
+    users: Vec<UserRecord>,
+    /// like [State::last_session_id], deleted users leave gaps
+    last_user_id: i64,
+    sessions: Vec<SessionRecord>,
+    /// sessions are never reused, unlike [State::next_id] after deletes
+    last_session_id: i64,
+    device_links: Vec<DeviceLinkRecord>,
+    api_tokens: Vec<ApiTokenRecord>,
+    /// like [State::last_session_id]
+    last_api_token_id: i64,
+    posts: Vec<Post>,
+    tags: BTreeMap<i64, String>,
+    /// `(post_id, referenced_post_id)`
+    post_references: Vec<(i64, i64)>,
+    /// ordered by rowid, which doubles as the vote time
+    vote_history: Vec<VoteRecord>,
+    /// like [State::last_session_id], merging users removes votes
+    last_vote_rowid: i64,
+    /// `(user_id, tag_id)`, oldest first
+    tag_follows: Vec<(i64, i64)>,
+    /// `(user_id, followed_user_id)`, oldest first
+    user_follows: Vec<(i64, i64)>,
+    /// oldest first
+    notifications: Vec<NotificationRecord>,
+    /// like [State::last_session_id]
+    last_notification_id: i64,
+    /// `(user_id, bookmark)`, oldest first
+    bookmarks: Vec<(i64, Bookmark)>,
+    /// `(user_id, tag_id)`, oldest first
+    tag_mutes: Vec<(i64, i64)>,
+    /// `(user_id, blocked_user_id)`, oldest first
+    user_blocks: Vec<(i64, i64)>,
+}
+
+/// One row of the `current_informed_tally` view
+struct InformedTallyRow {
+    tag_id: i64,
+    post_id: i64,
+    note_id: i64,
+    given_not_shown_this_note: Tally,
+    given_shown_this_note: Tally,
+}
+
+impl State {
+    fn next_id<T>(items: &[T]) -> i64 {
+        items.len() as i64 + 1
+    }
+
+    fn tag_id(&self, tag: &str) -> Option<i64> {
+        self.tags
+            .iter()
+            .find(|(_, t)| t.as_str() == tag)
+            .map(|(id, _)| *id)
+    }
+
+    fn get_or_insert_tag_id(&mut self, tag: &str) -> i64 {
+        match self.tag_id(tag) {
+            Some(tag_id) => tag_id,
+            None => {
+                let tag_id = self.tags.keys().max().map_or(0, |max| max + 1);
+                self.tags.insert(tag_id, tag.to_string());
+                tag_id
+            }
+        }
+    }
+
+    fn post(&self, post_id: i64) -> Option<&Post> {
+        self.posts.iter().find(|post| post.id == post_id)
+    }
+
+    /// Latest non-neutral vote per `(user_id, tag_id, post_id)`
+    fn current_votes(&self) -> BTreeMap<(i64, i64, i64), i64> {
+        let mut latest: BTreeMap<(i64, i64, i64), i64> = BTreeMap::new();
+        for vote in self.vote_history.iter() {
+            latest.insert((vote.user_id, vote.tag_id, vote.post_id), vote.direction);
+        }
+        latest.retain(|_, direction| *direction != 0);
+        latest
+    }
+
+    /// Tally of current votes per `(tag_id, post_id)`
+    fn current_tallies(&self) -> BTreeMap<(i64, i64), Tally> {
+        let mut tallies: BTreeMap<(i64, i64), Tally> = BTreeMap::new();
+        for ((_, tag_id, post_id), direction) in self.current_votes() {
+            let tally = tallies.entry((tag_id, post_id)).or_insert(EMPTY_TALLY);
+            tally.total += 1;
+            if direction == 1 {
+                tally.upvotes += 1;
+            }
+        }
+        tallies
+    }
+
+    fn current_informed_tally(&self) -> Vec<InformedTallyRow> {
+        let mut latest_informed: BTreeMap<(i64, i64, i64, i64), i64> = BTreeMap::new();
+        let mut first_vote_on_note: HashMap<(i64, i64, i64, i64), i64> = HashMap::new();
+        for vote in self.vote_history.iter() {
+            if let Some(note_id) = vote.note_id {
+                let key = (vote.user_id, vote.tag_id, vote.post_id, note_id);
+                latest_informed.insert(key, vote.direction);
+                first_vote_on_note.entry(key).or_insert(vote.rowid);
+            }
+        }
+
+        let mut shown: BTreeMap<(i64, i64, i64), Tally> = BTreeMap::new();
+        for ((_, tag_id, post_id, note_id), direction) in latest_informed {
+            if direction == 0 {
+                continue;
+            }
+            let tally = shown
+                .entry((tag_id, post_id, note_id))
+                .or_insert(EMPTY_TALLY);
+            tally.total += 1;
+            if direction == 1 {
+                tally.upvotes += 1;
+            }
+        }
+
+        let mut rows = vec![];
+        for ((tag_id, post_id, note_id), given_shown_this_note) in shown {
+            // last vote of every user on the post before they were first shown the note
+            let mut last_vote_before_note: BTreeMap<i64, i64> = BTreeMap::new();
+            for vote in self
+                .vote_history
+                .iter()
+                .filter(|vote| vote.tag_id == tag_id && vote.post_id == post_id)
+            {
+                let before_note =
+                    match first_vote_on_note.get(&(vote.user_id, tag_id, post_id, note_id)) {
+                        None => true,
+                        Some(first_rowid) => vote.rowid < *first_rowid,
+                    };
+                if before_note {
+                    last_vote_before_note.insert(vote.user_id, vote.direction);
+                }
+            }
+            if last_vote_before_note.is_empty() {
+                continue;
+            }
+            rows.push(InformedTallyRow {
+                tag_id,
+                post_id,
+                note_id,
+                given_not_shown_this_note: Tally {
+                    upvotes: last_vote_before_note
+                        .values()
+                        .filter(|direction| **direction == 1)
+                        .count() as i64,
+                    total: last_vote_before_note.len() as i64,
+                },
+                given_shown_this_note,
+            });
+        }
+        rows
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: Mutex<State>,
+}
+
+/// Formats like SQLite's `CURRENT_TIMESTAMP`
+fn timestamp(time: NaiveDateTime) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("memory repository lock poisoned")
+    }
+}
+
+#[async_trait]
+impl Repository for MemoryRepository {
+    async fn create_post(
+        &self,
+        tags: &[String],
+        parent_id: Option<i64>,
+        content: &str,
+        author_id: i64,
+    ) -> Result<i64> {
+        if tags.is_empty() {
+            return Err(anyhow!("A post needs at least one tag"));
+        }
+
+        // a single lock makes the post and its votes appear at once
+        let mut state = self.state();
+        let created_post_id = State::next_id(&state.posts);
+        state.posts.push(Post {
+            id: created_post_id,
+            content: content.to_string(),
+            parent_id,
+            author_id,
+        });
+
+        for referenced_post_id in parse_post_references(content) {
+            if referenced_post_id != created_post_id && state.post(referenced_post_id).is_some() {
+                state
+                    .post_references
+                    .push((created_post_id, referenced_post_id));
+            }
+        }
+
+        for tag in tags {
+            let tag_id = state.get_or_insert_tag_id(tag);
+            state.last_vote_rowid += 1;
+            let rowid = state.last_vote_rowid;
+            state.vote_history.push(VoteRecord {
+                rowid,
+                user_id: author_id,
+                tag_id,
+                post_id: created_post_id,
+                note_id: None,
+                direction: Direction::Up as i64,
+                created: Utc::now().naive_utc(),
+            });
+        }
+
+        Ok(created_post_id)
+    }
+
+    async fn get_post(&self, post_id: i64) -> Result<Option<Post>> {
+        Ok(self.state().post(post_id).cloned())
+    }
+
+    async fn get_posts_for_tag(&self, tag: &str) -> Result<Vec<Post>> {
+        let state = self.state();
+        let Some(tag_id) = state.tag_id(tag) else {
+            return Ok(vec![]);
+        };
+        let tallies = state.current_tallies();
+
+        let mut posts: Vec<(i64, Post)> = state
+            .posts
+            .iter()
+            .filter(|post| post.parent_id.is_none())
+            .filter_map(|post| {
+                tallies
+                    .get(&(tag_id, post.id))
+                    .map(|t| (t.upvotes - (t.total - t.upvotes), post.clone()))
+            })
+            .collect();
+        posts.sort_by_key(|(score, _)| -score);
+
+        Ok(posts.into_iter().map(|(_, post)| post).collect())
+    }
+
+    async fn get_replies(&self, tag: &str, post_id: i64) -> Result<Vec<Post>> {
+        let state = self.state();
+        let Some(tag_id) = state.tag_id(tag) else {
+            return Ok(vec![]);
+        };
+        let tallies = state.current_tallies();
+
+        // Same order as `upvotes * (1 + log(upvotes / votes))` with integer division in SQLite:
+        // unanimous posts by upvotes, everything else (log(0) is null) last.
+        let mut replies: Vec<(Option<i64>, Post)> = state
+            .posts
+            .iter()
+            .filter(|post| post.parent_id == Some(post_id))
+            .filter_map(|post| {
+                tallies.get(&(tag_id, post.id)).map(|t| {
+                    let score = (t.upvotes == t.total).then_some(t.upvotes);
+                    (score, post.clone())
+                })
+            })
+            .collect();
+        replies.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        Ok(replies.into_iter().map(|(_, post)| post).collect())
+    }
+
+    async fn get_referenced_posts(&self, post_id: i64) -> Result<Vec<Post>> {
+        let state = self.state();
+        Ok(state
+            .post_references
+            .iter()
+            .filter(|(from, _)| *from == post_id)
+            .filter_map(|(_, to)| state.post(*to).cloned())
+            .collect())
+    }
+
+    async fn get_referencing_posts(&self, post_id: i64) -> Result<Vec<Post>> {
+        let state = self.state();
+        let mut posts: Vec<Post> = state
+            .post_references
+            .iter()
+            .filter(|(_, to)| *to == post_id)
+            .filter_map(|(from, _)| state.post(*from).cloned())
+            .collect();
+        posts.sort_by_key(|post| -post.id);
+        Ok(posts)
+    }
+
+    async fn get_posts_by_author(&self, user_id: i64) -> Result<Vec<Post>> {
+        let mut posts: Vec<Post> = self
+            .state()
+            .posts
+            .iter()
+            .filter(|post| post.author_id == user_id)
+            .cloned()
+            .collect();
+        posts.sort_by_key(|post| -post.id);
+        Ok(posts)
+    }
+
+    async fn vote(
+        &self,
+        user_id: i64,
+        tag: &str,
+        post_id: i64,
+        note_id: Option<i64>,
+        direction: Direction,
+    ) -> Result<()> {
+        let tag_id = self.get_or_insert_tag_id(tag).await?;
+        let direction = direction as i64;
+
+        let mut state = self.state();
+        let current_direction = state
+            .current_votes()
+            .get(&(user_id, tag_id, post_id))
+            .copied()
+            .unwrap_or(0);
+        if current_direction == direction {
+            return Ok(());
+        }
+
+        state.last_vote_rowid += 1;
+        let rowid = state.last_vote_rowid;
+        state.vote_history.push(VoteRecord {
+            rowid,
+            user_id,
+            tag_id,
+            post_id,
+            note_id,
+            direction,
+            created: Utc::now().naive_utc(),
+        });
+
+        Ok(())
+    }
+
+    async fn get_positions_for_post(&self, post_id: i64, user_id: i64) -> Result<Vec<(i64, i64)>> {
+        let state = self.state();
+
+        let mut ids = vec![];
+        let mut ancestor = state.post(post_id);
+        while let Some(post) = ancestor {
+            ids.push(post.id);
+            ancestor = post.parent_id.and_then(|parent_id| state.post(parent_id));
+        }
+        ids.extend(
+            state
+                .posts
+                .iter()
+                .filter(|post| post.parent_id == Some(post_id))
+                .map(|post| post.id),
+        );
+
+        let current_votes = state.current_votes();
+        Ok(ids
+            .iter()
+            .flat_map(|id| {
+                current_votes
+                    .iter()
+                    .filter(move |((vote_user_id, _, vote_post_id), _)| {
+                        *vote_user_id == user_id && vote_post_id == id
+                    })
+                    .map(|((_, _, vote_post_id), direction)| (*vote_post_id, *direction))
+            })
+            .collect())
+    }
+
+    async fn get_positions_for_tag(&self, tag: &str, user_id: i64) -> Result<Vec<(i64, i64)>> {
+        let state = self.state();
+        let Some(tag_id) = state.tag_id(tag) else {
+            return Ok(vec![]);
+        };
+
+        Ok(state
+            .current_votes()
+            .into_iter()
+            .filter(|((vote_user_id, vote_tag_id, post_id), _)| {
+                *vote_user_id == user_id
+                    && *vote_tag_id == tag_id
+                    && state
+                        .post(*post_id)
+                        .is_some_and(|post| post.parent_id.is_none())
+            })
+            .map(|((_, _, post_id), direction)| (post_id, direction))
+            .collect())
+    }
+
+    async fn get_voter_ids(&self, post_id: i64) -> Result<Vec<i64>> {
+        let mut user_ids: Vec<i64> = self
+            .state()
+            .current_votes()
+            .into_keys()
+            .filter(|(_, _, vote_post_id)| *vote_post_id == post_id)
+            .map(|(user_id, _, _)| user_id)
+            .collect();
+        user_ids.dedup();
+        Ok(user_ids)
+    }
+
+    async fn get_vote_counts_per_tag(&self, user_id: i64) -> Result<Vec<(String, i64)>> {
+        let state = self.state();
+        let mut counts: BTreeMap<String, i64> = BTreeMap::new();
+        for (_, tag_id, _) in state
+            .current_votes()
+            .into_keys()
+            .filter(|(vote_user_id, _, _)| *vote_user_id == user_id)
+        {
+            *counts.entry(state.tags[&tag_id].clone()).or_default() += 1;
+        }
+        let mut counts: Vec<(String, i64)> = counts.into_iter().collect();
+        counts.sort_by_key(|(_, votes)| -votes);
+        Ok(counts)
+    }
+
+    async fn count_votes_changed_after_note(&self, user_id: i64) -> Result<i64> {
+        let state = self.state();
+        let mut previous_direction: HashMap<(i64, i64), i64> = HashMap::new();
+        let mut changes = 0;
+        for vote in state
+            .vote_history
+            .iter()
+            .filter(|vote| vote.user_id == user_id)
+        {
+            let previous = previous_direction.insert((vote.tag_id, vote.post_id), vote.direction);
+            if vote.note_id.is_some() && previous.is_some_and(|previous| previous != vote.direction)
+            {
+                changes += 1;
+            }
+        }
+        Ok(changes)
+    }
+
+    async fn get_or_insert_tag_id(&self, tag: &str) -> Result<i64> {
+        Ok(self.state().get_or_insert_tag_id(tag))
+    }
+
+    async fn get_tag_id(&self, tag: &str) -> Result<Option<i64>> {
+        Ok(self.state().tag_id(tag))
+    }
+
+    async fn get_top_tags(&self, limit: i64) -> Result<Vec<String>> {
+        let state = self.state();
+
+        let mut post_counts: BTreeMap<i64, i64> = BTreeMap::new();
+        for (tag_id, _) in state.current_tallies().keys() {
+            *post_counts.entry(*tag_id).or_insert(0) += 1;
+        }
+        let mut post_counts: Vec<(i64, i64)> = post_counts.into_iter().collect();
+        post_counts.sort_by_key(|(_, count)| -count);
+
+        Ok(post_counts
+            .into_iter()
+            .take(limit.max(0) as usize)
+            .filter_map(|(tag_id, _)| state.tags.get(&tag_id).cloned())
+            .collect())
+    }
+
+    async fn get_post_tags(&self, post_id: i64) -> Result<Vec<String>> {
+        let state = self.state();
+        let mut tags: Vec<String> = state
+            .current_tallies()
+            .keys()
+            .filter(|(_, tallied_post_id)| *tallied_post_id == post_id)
+            .filter_map(|(tag_id, _)| state.tags.get(tag_id).cloned())
+            .collect();
+        tags.sort();
+        Ok(tags)
+    }
+
+    async fn create_user(&self, secret_hash: &str) -> Result<i64> {
+        let mut state = self.state();
+        if state
+            .users
+            .iter()
+            .any(|user| user.secret_hash == secret_hash)
+        {
+            return Err(anyhow!("UNIQUE constraint failed: users.secret_hash"));
+        }
+        state.last_user_id += 1;
+        let id = state.last_user_id;
+        state.users.push(UserRecord {
+            id,
+            secret_hash: secret_hash.to_string(),
+            username: None,
+            password_hash: None,
+            display_name: None,
+        });
+        Ok(id)
+    }
+
+    async fn user_id_from_secret_hash(&self, secret_hash: &str) -> Result<Option<i64>> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.secret_hash == secret_hash)
+            .map(|user| user.id))
+    }
+
+    async fn user_exists(&self, user_id: i64) -> Result<bool> {
+        Ok(self.state().users.iter().any(|user| user.id == user_id))
+    }
+
+    async fn count_votes(&self, user_id: i64) -> Result<i64> {
+        Ok(self
+            .state()
+            .current_votes()
+            .keys()
+            .filter(|(vote_user_id, _, _)| *vote_user_id == user_id)
+            .count() as i64)
+    }
+
+    async fn merge_users(&self, from_user_id: i64, into_user_id: i64) -> Result<MergeReport> {
+        if from_user_id == into_user_id {
+            return Err(anyhow!("Cannot merge user {from_user_id} into itself"));
+        }
+        let mut state = self.state();
+        for user_id in [from_user_id, into_user_id] {
+            if !state.users.iter().any(|user| user.id == user_id) {
+                return Err(anyhow!("User {user_id} does not exist"));
+            }
+        }
+
+        // last vote (rowid) per (user_id, tag_id, post_id) of both users
+        let mut latest: HashMap<(i64, i64, i64), i64> = HashMap::new();
+        for vote in state.vote_history.iter() {
+            if vote.user_id == from_user_id || vote.user_id == into_user_id {
+                latest.insert((vote.user_id, vote.tag_id, vote.post_id), vote.rowid);
+            }
+        }
+        let superseded = |vote: &VoteRecord| {
+            let other_user_id = if vote.user_id == from_user_id {
+                into_user_id
+            } else {
+                from_user_id
+            };
+            let own = latest.get(&(vote.user_id, vote.tag_id, vote.post_id));
+            let other = latest.get(&(other_user_id, vote.tag_id, vote.post_id));
+            matches!((own, other), (Some(own), Some(other)) if own < other)
+        };
+
+        // there is no archive in memory, superseded histories are dropped
+        let before = state.vote_history.len();
+        state.vote_history.retain(|vote| {
+            !((vote.user_id == from_user_id || vote.user_id == into_user_id) && superseded(vote))
+        });
+        let archived_votes = (before - state.vote_history.len()) as u64;
+
+        let mut votes = 0;
+        for vote in state.vote_history.iter_mut() {
+            if vote.user_id == from_user_id {
+                vote.user_id = into_user_id;
+                votes += 1;
+            }
+        }
+
+        let mut posts = 0;
+        for post in state.posts.iter_mut() {
+            if post.author_id == from_user_id {
+                post.author_id = into_user_id;
+                posts += 1;
+            }
+        }
+
+        Ok(MergeReport {
+            posts,
+            votes,
+            archived_votes,
+        })
+    }
+
+    async fn get_vote_history(&self, user_id: i64) -> Result<Vec<HistoricVote>> {
+        let state = self.state();
+        Ok(state
+            .vote_history
+            .iter()
+            .filter(|vote| vote.user_id == user_id)
+            .map(|vote| HistoricVote {
+                tag: state.tags[&vote.tag_id].clone(),
+                post_id: vote.post_id,
+                note_id: vote.note_id,
+                direction: vote.direction,
+                created: timestamp(vote.created),
+            })
+            .collect())
+    }
+
+    async fn delete_user(
+        &self,
+        user_id: i64,
+        tombstone_secret_hash: &str,
+        erase_posts: bool,
+    ) -> Result<DeletionReport> {
+        let mut state = self.state();
+        if !state.users.iter().any(|user| user.id == user_id) {
+            return Err(anyhow!("User {user_id} does not exist"));
+        }
+
+        state.last_user_id += 1;
+        let tombstone_user_id = state.last_user_id;
+        state.users.push(UserRecord {
+            id: tombstone_user_id,
+            secret_hash: tombstone_secret_hash.to_string(),
+            username: None,
+            password_hash: None,
+            display_name: None,
+        });
+
+        let mut votes = 0;
+        for vote in state.vote_history.iter_mut() {
+            if vote.user_id == user_id {
+                vote.user_id = tombstone_user_id;
+                votes += 1;
+            }
+        }
+
+        let mut posts = 0;
+        let mut erased_post_ids = vec![];
+        for post in state.posts.iter_mut() {
+            if post.author_id == user_id {
+                post.author_id = tombstone_user_id;
+                if erase_posts {
+                    post.content = DELETED_POST_CONTENT.to_string();
+                    erased_post_ids.push(post.id);
+                }
+                posts += 1;
+            }
+        }
+        state
+            .post_references
+            .retain(|(from, _)| !erased_post_ids.contains(from));
+
+        state
+            .sessions
+            .retain(|record| record.session.user_id != user_id);
+        state.device_links.retain(|link| link.user_id != user_id);
+        state
+            .api_tokens
+            .retain(|record| record.token.user_id != user_id);
+        state
+            .tag_follows
+            .retain(|(follower_id, _)| *follower_id != user_id);
+        state
+            .user_follows
+            .retain(|(follower_id, followed_user_id)| {
+                *follower_id != user_id && *followed_user_id != user_id
+            });
+        state
+            .notifications
+            .retain(|record| record.user_id != user_id);
+        state
+            .bookmarks
+            .retain(|(bookmark_user_id, _)| *bookmark_user_id != user_id);
+        state
+            .tag_mutes
+            .retain(|(muting_user_id, _)| *muting_user_id != user_id);
+        state
+            .user_blocks
+            .retain(|(blocking_user_id, blocked_user_id)| {
+                *blocking_user_id != user_id && *blocked_user_id != user_id
+            });
+        state.users.retain(|user| user.id != user_id);
+
+        Ok(DeletionReport {
+            tombstone_user_id,
+            posts,
+            votes,
+        })
+    }
+
+    async fn set_secret_hash(&self, user_id: i64, secret_hash: &str) -> Result<()> {
+        let mut state = self.state();
+        if let Some(user) = state.users.iter_mut().find(|user| user.id == user_id) {
+            user.secret_hash = secret_hash.to_string();
+        }
+        Ok(())
+    }
+
+    async fn set_credentials(
+        &self,
+        user_id: i64,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<()> {
+        let mut state = self.state();
+        if state
+            .users
+            .iter()
+            .any(|user| user.id != user_id && user.username.as_deref() == Some(username))
+        {
+            return Err(anyhow!("UNIQUE constraint failed: users.username"));
+        }
+        if let Some(user) = state.users.iter_mut().find(|user| user.id == user_id) {
+            user.username = Some(username.to_string());
+            user.password_hash = Some(password_hash.to_string());
+        }
+        Ok(())
+    }
+
+    async fn get_credentials(&self, username: &str) -> Result<Option<(i64, String)>> {
+        Ok(self.state().users.iter().find_map(|user| {
+            match (user.username.as_deref(), &user.password_hash) {
+                (Some(name), Some(password_hash)) if name == username => {
+                    Some((user.id, password_hash.clone()))
+                }
+                _ => None,
+            }
+        }))
+    }
+
+    async fn get_username(&self, user_id: i64) -> Result<Option<String>> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.id == user_id)
+            .and_then(|user| user.username.clone()))
+    }
+
+    async fn set_display_name(&self, user_id: i64, display_name: Option<&str>) -> Result<()> {
+        if let Some(user) = self
+            .state()
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+        {
+            user.display_name = display_name.map(str::to_string);
+        }
+        Ok(())
+    }
+
+    async fn get_display_name(&self, user_id: i64) -> Result<Option<String>> {
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.id == user_id)
+            .and_then(|user| user.display_name.clone()))
+    }
+
+    async fn create_session(&self, user_id: i64, token_hash: &str) -> Result<Session> {
+        let mut state = self.state();
+        if state
+            .sessions
+            .iter()
+            .any(|record| record.token_hash == token_hash)
+        {
+            return Err(anyhow!("UNIQUE constraint failed: sessions.token_hash"));
+        }
+        state.last_session_id += 1;
+        let now = timestamp(Utc::now().naive_utc());
+        let session = Session {
+            id: state.last_session_id,
+            user_id,
+            created: now.clone(),
+            last_used: now,
+        };
+        state.sessions.push(SessionRecord {
+            session: session.clone(),
+            token_hash: token_hash.to_string(),
+        });
+        Ok(session)
+    }
+
+    async fn use_session(&self, token_hash: &str) -> Result<Option<Session>> {
+        let mut state = self.state();
+        Ok(state
+            .sessions
+            .iter_mut()
+            .find(|record| record.token_hash == token_hash)
+            .map(|record| {
+                record.session.last_used = timestamp(Utc::now().naive_utc());
+                record.session.clone()
+            }))
+    }
+
+    async fn get_sessions(&self, user_id: i64) -> Result<Vec<Session>> {
+        let mut sessions: Vec<Session> = self
+            .state()
+            .sessions
+            .iter()
+            .filter(|record| record.session.user_id == user_id)
+            .map(|record| record.session.clone())
+            .collect();
+        sessions.sort_by(|a, b| (&b.last_used, b.id).cmp(&(&a.last_used, a.id)));
+        Ok(sessions)
+    }
+
+    async fn delete_session(&self, user_id: i64, session_id: i64) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.sessions.len();
+        state.sessions.retain(|record| {
+            !(record.session.user_id == user_id && record.session.id == session_id)
+        });
+        Ok(state.sessions.len() < before)
+    }
+
+    async fn delete_other_sessions(&self, user_id: i64, keep_session_id: i64) -> Result<u64> {
+        let mut state = self.state();
+        let before = state.sessions.len();
+        state.sessions.retain(|record| {
+            record.session.user_id != user_id || record.session.id == keep_session_id
+        });
+        Ok((before - state.sessions.len()) as u64)
+    }
+
+    async fn create_device_link(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        valid_minutes: i64,
+    ) -> Result<()> {
+        self.state().device_links.push(DeviceLinkRecord {
+            token_hash: token_hash.to_string(),
+            user_id,
+            expires: Utc::now().naive_utc() + Duration::minutes(valid_minutes),
+        });
+        Ok(())
+    }
+
+    async fn device_link_user_id(&self, token_hash: &str) -> Result<Option<i64>> {
+        let now = Utc::now().naive_utc();
+        Ok(self
+            .state()
+            .device_links
+            .iter()
+            .find(|link| link.token_hash == token_hash && link.expires > now)
+            .map(|link| link.user_id))
+    }
+
+    async fn delete_device_link(&self, token_hash: &str) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        self.state()
+            .device_links
+            .retain(|link| link.token_hash != token_hash && link.expires > now);
+        Ok(())
+    }
+
+    async fn delete_device_links(&self, user_id: i64) -> Result<()> {
+        self.state()
+            .device_links
+            .retain(|link| link.user_id != user_id);
+        Ok(())
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        token_hash: &str,
+        scopes: &str,
+        valid_days: Option<i64>,
+    ) -> Result<ApiToken> {
+        let now = Utc::now().naive_utc();
+        let expires = valid_days.map(|days| now + Duration::days(days));
+        let mut state = self.state();
+        state.last_api_token_id += 1;
+        let token = ApiToken {
+            id: state.last_api_token_id,
+            user_id,
+            name: name.to_string(),
+            scopes: scopes.to_string(),
+            created: timestamp(now),
+            expires: expires.map(timestamp),
+        };
+        state.api_tokens.push(ApiTokenRecord {
+            token: token.clone(),
+            token_hash: token_hash.to_string(),
+            expires,
+        });
+        Ok(token)
+    }
+
+    async fn api_token_from_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let now = Utc::now().naive_utc();
+        Ok(self
+            .state()
+            .api_tokens
+            .iter()
+            .find(|record| {
+                record.token_hash == token_hash
+                    && record.expires.is_none_or(|expires| expires > now)
+            })
+            .map(|record| record.token.clone()))
+    }
+
+    async fn get_api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>> {
+        Ok(self
+            .state()
+            .api_tokens
+            .iter()
+            .rev()
+            .filter(|record| record.token.user_id == user_id)
+            .map(|record| record.token.clone())
+            .collect())
+    }
+
+    async fn delete_api_token(&self, user_id: i64, token_id: i64) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.api_tokens.len();
+        state
+            .api_tokens
+            .retain(|record| !(record.token.user_id == user_id && record.token.id == token_id));
+        Ok(state.api_tokens.len() < before)
+    }
+
+    async fn follow_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        let mut state = self.state();
+        let tag_id = state.get_or_insert_tag_id(tag);
+        if !state.tag_follows.contains(&(user_id, tag_id)) {
+            state.tag_follows.push((user_id, tag_id));
+        }
+        Ok(())
+    }
+
+    async fn unfollow_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        let mut state = self.state();
+        if let Some(tag_id) = state.tag_id(tag) {
+            state
+                .tag_follows
+                .retain(|follow| *follow != (user_id, tag_id));
+        }
+        Ok(())
+    }
+
+    async fn get_followed_tags(&self, user_id: i64) -> Result<Vec<String>> {
+        let state = self.state();
+        Ok(state
+            .tag_follows
+            .iter()
+            .filter(|(follower_id, _)| *follower_id == user_id)
+            .map(|(_, tag_id)| state.tags[tag_id].clone())
+            .collect())
+    }
+
+    async fn follow_user(&self, user_id: i64, followed_user_id: i64) -> Result<()> {
+        let mut state = self.state();
+        if !state.user_follows.contains(&(user_id, followed_user_id)) {
+            state.user_follows.push((user_id, followed_user_id));
+        }
+        Ok(())
+    }
+
+    async fn unfollow_user(&self, user_id: i64, followed_user_id: i64) -> Result<()> {
+        self.state()
+            .user_follows
+            .retain(|follow| *follow != (user_id, followed_user_id));
+        Ok(())
+    }
+
+    async fn get_followed_user_ids(&self, user_id: i64) -> Result<Vec<i64>> {
+        Ok(self
+            .state()
+            .user_follows
+            .iter()
+            .filter(|(follower_id, _)| *follower_id == user_id)
+            .map(|(_, followed_user_id)| *followed_user_id)
+            .collect())
+    }
+
+    async fn get_feed(&self, user_id: i64) -> Result<Vec<(String, Post)>> {
+        let state = self.state();
+        let followed_tag_ids: Vec<i64> = state
+            .tag_follows
+            .iter()
+            .filter(|(follower_id, _)| *follower_id == user_id)
+            .map(|(_, tag_id)| *tag_id)
+            .collect();
+        let followed_user_ids: Vec<i64> = state
+            .user_follows
+            .iter()
+            .filter(|(follower_id, _)| *follower_id == user_id)
+            .map(|(_, followed_user_id)| *followed_user_id)
+            .collect();
+
+        // best `(score, tag_id)` per post
+        let mut best: BTreeMap<i64, (i64, i64)> = BTreeMap::new();
+        for ((tag_id, post_id), t) in state.current_tallies() {
+            let Some(post) = state.post(post_id) else {
+                continue;
+            };
+            if post.parent_id.is_some()
+                || !(followed_tag_ids.contains(&tag_id)
+                    || followed_user_ids.contains(&post.author_id))
+            {
+                continue;
+            }
+            let score = t.upvotes - (t.total - t.upvotes);
+            if best
+                .get(&post_id)
+                .is_none_or(|(best_score, _)| score > *best_score)
+            {
+                best.insert(post_id, (score, tag_id));
+            }
+        }
+
+        let mut feed: Vec<(i64, String, Post)> = best
+            .into_iter()
+            .filter_map(|(post_id, (score, tag_id))| {
+                let post = state.post(post_id)?.clone();
+                Some((score, state.tags[&tag_id].clone(), post))
+            })
+            .collect();
+        feed.sort_by_key(|(score, _, post)| (-score, -post.id));
+
+        Ok(feed.into_iter().map(|(_, tag, post)| (tag, post)).collect())
+    }
+
+    async fn mute_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        let mut state = self.state();
+        let tag_id = state.get_or_insert_tag_id(tag);
+        if !state.tag_mutes.contains(&(user_id, tag_id)) {
+            state.tag_mutes.push((user_id, tag_id));
+        }
+        Ok(())
+    }
+
+    async fn unmute_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        let mut state = self.state();
+        if let Some(tag_id) = state.tag_id(tag) {
+            state.tag_mutes.retain(|mute| *mute != (user_id, tag_id));
+        }
+        Ok(())
+    }
+
+    async fn get_muted_tags(&self, user_id: i64) -> Result<Vec<String>> {
+        let state = self.state();
+        Ok(state
+            .tag_mutes
+            .iter()
+            .filter(|(muting_user_id, _)| *muting_user_id == user_id)
+            .map(|(_, tag_id)| state.tags[tag_id].clone())
+            .collect())
+    }
+
+    async fn block_user(&self, user_id: i64, blocked_user_id: i64) -> Result<()> {
+        let mut state = self.state();
+        if !state.user_blocks.contains(&(user_id, blocked_user_id)) {
+            state.user_blocks.push((user_id, blocked_user_id));
+        }
+        Ok(())
+    }
+
+    async fn unblock_user(&self, user_id: i64, blocked_user_id: i64) -> Result<()> {
+        self.state()
+            .user_blocks
+            .retain(|block| *block != (user_id, blocked_user_id));
+        Ok(())
+    }
+
+    async fn get_blocked_user_ids(&self, user_id: i64) -> Result<Vec<i64>> {
+        Ok(self
+            .state()
+            .user_blocks
+            .iter()
+            .filter(|(blocking_user_id, _)| *blocking_user_id == user_id)
+            .map(|(_, blocked_user_id)| *blocked_user_id)
+            .collect())
+    }
+
+    async fn create_notification(
+        &self,
+        user_id: i64,
+        kind: NotificationKind,
+        tag: &str,
+        post_id: i64,
+        note_id: i64,
+    ) -> Result<()> {
+        let mut state = self.state();
+        let kind = kind.to_string();
+        let exists = state.notifications.iter().any(|record| {
+            record.user_id == user_id
+                && record.notification.kind == kind
+                && record.notification.post_id == post_id
+                && record.notification.note_id == note_id
+        });
+        if exists {
+            return Ok(());
+        }
+
+        state.get_or_insert_tag_id(tag);
+        state.last_notification_id += 1;
+        let notification = Notification {
+            id: state.last_notification_id,
+            kind,
+            tag: tag.to_string(),
+            post_id,
+            note_id,
+            created: timestamp(Utc::now().naive_utc()),
+            read: false,
+        };
+        state.notifications.push(NotificationRecord {
+            user_id,
+            notification,
+        });
+        Ok(())
+    }
+
+    async fn get_notifications(&self, user_id: i64, limit: i64) -> Result<Vec<Notification>> {
+        Ok(self
+            .state()
+            .notifications
+            .iter()
+            .rev()
+            .filter(|record| record.user_id == user_id)
+            .take(limit.max(0) as usize)
+            .map(|record| record.notification.clone())
+            .collect())
+    }
+
+    async fn count_unread_notifications(&self, user_id: i64) -> Result<i64> {
+        Ok(self
+            .state()
+            .notifications
+            .iter()
+            .filter(|record| record.user_id == user_id && !record.notification.read)
+            .count() as i64)
+    }
+
+    async fn mark_notifications_read(&self, user_id: i64) -> Result<()> {
+        for record in self.state().notifications.iter_mut() {
+            if record.user_id == user_id {
+                record.notification.read = true;
+            }
+        }
+        Ok(())
+    }
+
+    async fn bookmark_post(&self, user_id: i64, tag: &str, post_id: i64) -> Result<()> {
+        let mut state = self.state();
+        state.get_or_insert_tag_id(tag);
+        let existing = state
+            .bookmarks
+            .iter()
+            .position(|(bookmark_user_id, bookmark)| {
+                *bookmark_user_id == user_id && bookmark.post_id == post_id
+            });
+        match existing {
+            Some(index) => state.bookmarks[index].1.tag = tag.to_string(),
+            None => state.bookmarks.push((
+                user_id,
+                Bookmark {
+                    post_id,
+                    tag: tag.to_string(),
+                    created: timestamp(Utc::now().naive_utc()),
+                },
+            )),
+        }
+        Ok(())
+    }
+
+    async fn remove_bookmark(&self, user_id: i64, post_id: i64) -> Result<()> {
+        self.state()
+            .bookmarks
+            .retain(|(bookmark_user_id, bookmark)| {
+                !(*bookmark_user_id == user_id && bookmark.post_id == post_id)
+            });
+        Ok(())
+    }
+
+    async fn get_bookmark(&self, user_id: i64, post_id: i64) -> Result<Option<Bookmark>> {
+        Ok(self
+            .state()
+            .bookmarks
+            .iter()
+            .find(|(bookmark_user_id, bookmark)| {
+                *bookmark_user_id == user_id && bookmark.post_id == post_id
+            })
+            .map(|(_, bookmark)| bookmark.clone()))
+    }
+
+    async fn get_bookmarks(&self, user_id: i64) -> Result<Vec<Bookmark>> {
+        Ok(self
+            .state()
+            .bookmarks
+            .iter()
+            .rev()
+            .filter(|(bookmark_user_id, _)| *bookmark_user_id == user_id)
+            .map(|(_, bookmark)| bookmark.clone())
+            .collect())
+    }
+
+    async fn current_tally(&self, tag: &str, post_id: i64) -> Result<Tally> {
+        let state = self.state();
+        let Some(tag_id) = state.tag_id(tag) else {
+            return Ok(EMPTY_TALLY);
+        };
+        Ok(state
+            .current_tallies()
+            .get(&(tag_id, post_id))
+            .copied()
+            .unwrap_or(EMPTY_TALLY))
+    }
+
+    async fn informed_tallies(&self, tag: &str, post_id: i64) -> Result<Vec<InformedTally>> {
+        let state = self.state();
+        let Some(tag_id) = state.tag_id(tag) else {
+            return Ok(vec![]);
+        };
+        let rows: Vec<InformedTallyRow> = state
+            .current_informed_tally()
+            .into_iter()
+            .filter(|row| row.tag_id == tag_id)
+            .collect();
+        let tallies = state.current_tallies();
+
+        // Like the recursive query in the SQLite implementation, subnotes report their
+        // "not shown" tally in place of the "shown" one.
+        let mut children: Vec<(i64, i64, Tally, Tally)> = rows
+            .iter()
+            .filter(|row| row.post_id == post_id)
+            .map(|row| {
+                (
+                    row.post_id,
+                    row.note_id,
+                    row.given_not_shown_this_note,
+                    row.given_shown_this_note,
+                )
+            })
+            .collect();
+        let mut i = 0;
+        while i < children.len() {
+            let note_id = children[i].1;
+            children.extend(rows.iter().filter(|row| row.post_id == note_id).map(|row| {
+                (
+                    row.post_id,
+                    row.note_id,
+                    row.given_not_shown_this_note,
+                    row.given_not_shown_this_note,
+                )
+            }));
+            i += 1;
+        }
+
+        Ok(children
+            .into_iter()
+            .filter_map(|(post_id, note_id, not_shown, shown)| {
+                tallies
+                    .get(&(tag_id, note_id))
+                    .map(|for_note| InformedTally {
+                        post_id,
+                        note_id,
+                        given_not_shown_this_note: not_shown,
+                        given_shown_this_note: shown,
+                        for_note: *for_note,
+                    })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    #[cfg(feature = "postgres")]
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+    use sqlx::sqlite::SqlitePoolOptions;
+    #[cfg(feature = "postgres")]
+    use sqlx::PgPool;
+    use sqlx::SqlitePool;
+
+    use super::*;
+    #[cfg(feature = "postgres")]
+    use crate::repository::postgres::PostgresRepository;
+    use crate::repository::sqlite::SqliteRepository;
+    use crate::repository::DynRepository;
+
+    /// Database behind the repository that is compared with memory
+    enum Pool {
+        Sqlite(SqlitePool),
+        #[cfg(feature = "postgres")]
+        Postgres(PgPool),
+    }
+
+    /// The same operations on a [MemoryRepository] and a repository with a migrated database
+    struct Both {
+        memory: MemoryRepository,
+        other: DynRepository,
+        pool: Pool,
+    }
+
+    impl Both {
+        async fn sqlite() -> Both {
+            // every connection to `sqlite::memory:` opens a separate database
+            let pool = SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect("sqlite::memory:")
+                .await
+                .unwrap();
+            sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+            // the first migration seeds example users, posts and votes
+            for table in ["vote_history", "posts", "tags", "users"] {
+                sqlx::query(&format!("delete from {table}"))
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            }
+            Both {
+                memory: MemoryRepository::new(),
+                other: Arc::new(SqliteRepository::new(pool.clone())),
+                pool: Pool::Sqlite(pool),
+            }
+        }
+
+        /// A new database on the server at `POSTGRES_DATABASE_URL`, or `None` if it is not set
+        #[cfg(feature = "postgres")]
+        async fn postgres(name: &str) -> Option<Both> {
+            let url = std::env::var("POSTGRES_DATABASE_URL").ok()?;
+            let server = PgPoolOptions::new()
+                .max_connections(1)
+                .connect(&url)
+                .await
+                .unwrap();
+            let name = format!("y_test_{name}");
+            for statement in [
+                format!("drop database if exists {name} with (force)"),
+                format!("create database {name}"),
+            ] {
+                sqlx::query(&statement).execute(&server).await.unwrap();
+            }
+            let options: PgConnectOptions = url.parse().unwrap();
+            let pool = PgPoolOptions::new()
+                .connect_with(options.database(&name))
+                .await
+                .unwrap();
+            sqlx::migrate!("./migrations_postgres")
+                .run(&pool)
+                .await
+                .unwrap();
+            Some(Both {
+                memory: MemoryRepository::new(),
+                other: Arc::new(PostgresRepository::new(pool.clone())),
+                pool: Pool::Postgres(pool),
+            })
+        }
+
+        /// `current_vote` picks the latest vote by `created`, which only has a resolution of
+        /// seconds in SQLite. Memory picks the latest by insertion, so give every vote its own
+        /// second.
+        async fn space_out_votes(&self) {
+            match &self.pool {
+                Pool::Sqlite(pool) => {
+                    sqlx::query(
+                        r#"
+                            update vote_history
+                            set created = datetime('2023-01-01', '+' || rowid || ' seconds')
+                        "#,
+                    )
+                    .execute(pool)
+                    .await
+                    .unwrap();
+                }
+                #[cfg(feature = "postgres")]
+                Pool::Postgres(pool) => {
+                    sqlx::query(
+                        r#"
+                            update vote_history
+                            set created = timestamp '2023-01-01' + id * interval '1 second'
+                        "#,
+                    )
+                    .execute(pool)
+                    .await
+                    .unwrap();
+                }
+            }
+        }
+
+        async fn create_user(&self, secret_hash: &str) -> i64 {
+            let id = self.memory.create_user(secret_hash).await.unwrap();
+            assert_eq!(id, self.other.create_user(secret_hash).await.unwrap());
+            id
+        }
+
+        async fn create_post(&self, parent_id: Option<i64>, author_id: i64) -> i64 {
+            let tags = ["test".to_string()];
+            let content = "content";
+            let id = self
+                .memory
+                .create_post(&tags, parent_id, content, author_id)
+                .await
+                .unwrap();
+            let other_id = self
+                .other
+                .create_post(&tags, parent_id, content, author_id)
+                .await
+                .unwrap();
+            assert_eq!(id, other_id);
+            self.space_out_votes().await;
+            id
+        }
+
+        async fn vote(
+            &self,
+            user_id: i64,
+            post_id: i64,
+            note_id: Option<i64>,
+            direction: Direction,
+        ) {
+            self.vote_in_tag("test", user_id, post_id, note_id, direction)
+                .await;
+        }
+
+        async fn vote_in_tag(
+            &self,
+            tag: &str,
+            user_id: i64,
+            post_id: i64,
+            note_id: Option<i64>,
+            direction: Direction,
+        ) {
+            self.memory
+                .vote(user_id, tag, post_id, note_id, direction)
+                .await
+                .unwrap();
+            self.other
+                .vote(user_id, tag, post_id, note_id, direction)
+                .await
+                .unwrap();
+            self.space_out_votes().await;
+        }
+
+        async fn merge_users(&self, from_user_id: i64, into_user_id: i64) {
+            let memory = self
+                .memory
+                .merge_users(from_user_id, into_user_id)
+                .await
+                .unwrap();
+            let other = self
+                .other
+                .merge_users(from_user_id, into_user_id)
+                .await
+                .unwrap();
+            assert_eq!(memory.archived_votes, other.archived_votes);
+        }
+
+        async fn assert_same_tallies(&self, tag: &str, post_id: i64) {
+            let tally = |tally: Tally| (tally.upvotes, tally.total);
+            let informed = |tallies: Vec<InformedTally>| {
+                let mut tallies: Vec<_> = tallies
+                    .into_iter()
+                    .map(|t| {
+                        (
+                            t.post_id,
+                            t.note_id,
+                            tally(t.given_not_shown_this_note),
+                            tally(t.given_shown_this_note),
+                            tally(t.for_note),
+                        )
+                    })
+                    .collect();
+                tallies.sort();
+                tallies
+            };
+
+            assert_eq!(
+                tally(self.memory.current_tally(tag, post_id).await.unwrap()),
+                tally(self.other.current_tally(tag, post_id).await.unwrap()),
+                "current_tally of post {post_id} in {tag}",
+            );
+            assert_eq!(
+                informed(self.memory.informed_tallies(tag, post_id).await.unwrap()),
+                informed(self.other.informed_tallies(tag, post_id).await.unwrap()),
+                "current_informed_tally of post {post_id} in {tag}",
+            );
+            assert_eq!(
+                self.memory.get_post_tags(post_id).await.unwrap(),
+                self.other.get_post_tags(post_id).await.unwrap(),
+                "tags of post {post_id}",
+            );
+        }
+
+        async fn assert_same_history(&self, user_id: i64) {
+            let history = |votes: Vec<HistoricVote>| {
+                votes
+                    .into_iter()
+                    .map(|vote| (vote.post_id, vote.note_id, vote.direction))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(
+                history(self.memory.get_vote_history(user_id).await.unwrap()),
+                history(self.other.get_vote_history(user_id).await.unwrap()),
+                "vote history of user {user_id}",
+            );
+        }
+    }
+
+    /// Votes of several users on a post, its notes and a subnote, then a merge of two users
+    async fn tallies_match(both: Both) {
+        let mut users = Vec::new();
+        for i in 0..5 {
+            users.push(both.create_user(&format!("{i:064}")).await);
+        }
+        let [author, a, b, c, d] = users[..] else {
+            unreachable!()
+        };
+
+        let post = both.create_post(None, author).await;
+        let note = both.create_post(Some(post), a).await;
+        let other_note = both.create_post(Some(post), b).await;
+        let subnote = both.create_post(Some(note), c).await;
+
+        both.vote(a, post, None, Direction::Down).await;
+        both.vote(a, post, Some(note), Direction::Up).await;
+        both.vote(b, post, None, Direction::Up).await;
+        both.vote(b, post, Some(note), Direction::Down).await;
+        both.vote(b, post, Some(other_note), Direction::Neutral)
+            .await;
+        both.vote(c, post, Some(other_note), Direction::Up).await;
+        both.vote(c, note, None, Direction::Down).await;
+        both.vote(c, note, Some(subnote), Direction::Up).await;
+        // repeats the current vote, so neither backend records it
+        both.vote(c, note, Some(subnote), Direction::Up).await;
+        both.vote(d, note, None, Direction::Up).await;
+        both.vote(d, note, Some(subnote), Direction::Down).await;
+        both.vote(author, note, None, Direction::Up).await;
+
+        // the same post in another tag, with its own tallies
+        both.vote_in_tag("other", a, post, None, Direction::Up)
+            .await;
+        both.vote_in_tag("other", b, post, Some(other_note), Direction::Down)
+            .await;
+        both.vote_in_tag("other", b, other_note, None, Direction::Up)
+            .await;
+
+        assert!(!both
+            .memory
+            .informed_tallies("test", post)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            both.memory
+                .current_tally("other", post)
+                .await
+                .unwrap()
+                .total,
+            2
+        );
+        for tag in ["test", "other"] {
+            for post_id in [post, note, other_note, subnote] {
+                both.assert_same_tallies(tag, post_id).await;
+            }
+        }
+
+        // c voted on the note before d, so d's vote wins
+        both.merge_users(c, d).await;
+        both.vote(d, post, None, Direction::Down).await;
+        for tag in ["test", "other"] {
+            for post_id in [post, note, other_note, subnote] {
+                both.assert_same_tallies(tag, post_id).await;
+            }
+        }
+        both.assert_same_history(d).await;
+    }
+
+    #[tokio::test]
+    async fn tallies_match_the_sqlite_views() {
+        tallies_match(Both::sqlite().await).await;
+    }
+
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn tallies_match_the_postgres_views() {
+        match Both::postgres("tallies_match").await {
+            Some(both) => tallies_match(both).await,
+            None => eprintln!("POSTGRES_DATABASE_URL is not set, skipped"),
+        }
+    }
+
+    #[tokio::test]
+    async fn votes_after_merging_users_come_last() {
+        let repo = MemoryRepository::new();
+        let tags = ["test".to_string()];
+        let author = repo.create_user("author").await.unwrap();
+        let post = repo.create_post(&tags, None, "post", author).await.unwrap();
+        let device = repo.create_user("device").await.unwrap();
+        let other_device = repo.create_user("other device").await.unwrap();
+        repo.vote(device, "test", post, None, Direction::Down)
+            .await
+            .unwrap();
+        repo.vote(other_device, "test", post, None, Direction::Up)
+            .await
+            .unwrap();
+        repo.vote(device, "test", post, None, Direction::Neutral)
+            .await
+            .unwrap();
+
+        let report = repo.merge_users(device, other_device).await.unwrap();
+        assert_eq!(report.archived_votes, 1);
+        repo.vote(other_device, "test", post, None, Direction::Up)
+            .await
+            .unwrap();
+
+        let rowids: Vec<i64> = repo
+            .state()
+            .vote_history
+            .iter()
+            .map(|vote| vote.rowid)
+            .collect();
+        assert!(
+            rowids.windows(2).all(|pair| pair[0] < pair[1]),
+            "{rowids:?}"
+        );
+        let tally = repo.current_tally("test", post).await.unwrap();
+        assert_eq!((tally.upvotes, tally.total), (2, 2));
+        let history = repo.get_vote_history(other_device).await.unwrap();
+        assert_eq!(history.last().unwrap().direction, Direction::Up as i64);
+    }
+}
@@ -0,0 +1,333 @@
+//! Storage abstraction
+//!
+//! Handlers and the ranking code only talk to a [Repository]. [sqlite::SqliteRepository] is
+//! the default backend, `postgres::PostgresRepository` is available with the `postgres`
+//! feature and [memory::MemoryRepository] keeps everything in process memory and needs no
+//! migrations.
+
+pub mod memory;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod sqlite;
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+
+use crate::structs::{
+    ApiToken, Bookmark, DeletionReport, Direction, HistoricVote, InformedTally, MergeReport,
+    Notification, NotificationKind, Post, Session, Tally,
+};
+
+/// Shared handle to the configured backend, passed to handlers as an axum `Extension`
+pub type DynRepository = Arc<dyn Repository>;
+
+/// Content of posts whose author deleted their account and chose to erase them
+pub const DELETED_POST_CONTENT: &str = "[deleted]";
+
+#[async_trait]
+pub trait Repository: Send + Sync {
+    // posts
+
+    /// Inserts a post and casts the author's initial upvote in each of `tags`, all in one
+    /// transaction. `#post:123` references to existing posts in `content` are stored as
+    /// well. Fails if `tags` is empty.
+    async fn create_post(
+        &self,
+        tags: &[String],
+        parent_id: Option<i64>,
+        content: &str,
+        author_id: i64,
+    ) -> Result<i64>;
+
+    async fn get_post(&self, post_id: i64) -> Result<Option<Post>>;
+
+    /// Top-level posts that have votes in `tag`, best first
+    async fn get_posts_for_tag(&self, tag: &str) -> Result<Vec<Post>>;
+
+    async fn get_replies(&self, tag: &str, post_id: i64) -> Result<Vec<Post>>;
+
+    /// Posts that `post_id` references in its content
+    async fn get_referenced_posts(&self, post_id: i64) -> Result<Vec<Post>>;
+
+    /// Posts that reference `post_id` in their content, newest first
+    async fn get_referencing_posts(&self, post_id: i64) -> Result<Vec<Post>>;
+
+    /// Posts written by `user_id`, newest first
+    async fn get_posts_by_author(&self, user_id: i64) -> Result<Vec<Post>>;
+
+    /// All ancestors of `post`, starting with its direct parent
+    async fn get_transitive_parents(&self, post: &Post) -> Result<Vec<Post>> {
+        let mut parents: Vec<Post> = vec![];
+        let mut p = post.clone();
+
+        // loop until a post without parent_id is found
+        while let Some(parent_id) = p.parent_id {
+            match self.get_post(parent_id).await? {
+                None => return Err(anyhow!("Couldn't find post with id: {}", parent_id)),
+                Some(parent_post) => {
+                    parents.push(parent_post.clone());
+                    p = parent_post;
+                }
+            }
+        }
+        Ok(parents)
+    }
+
+    // votes
+
+    /// Appends a vote to the history, unless it repeats the user's current vote
+    async fn vote(
+        &self,
+        user_id: i64,
+        tag: &str,
+        post_id: i64,
+        note_id: Option<i64>,
+        direction: Direction,
+    ) -> Result<()>;
+
+    /// Current votes of a user on the ancestors and direct replies of a post, as
+    /// `(post_id, direction)`
+    async fn get_positions_for_post(&self, post_id: i64, user_id: i64) -> Result<Vec<(i64, i64)>>;
+
+    /// Current votes of a user on top-level posts in `tag`, as `(post_id, direction)`
+    async fn get_positions_for_tag(&self, tag: &str, user_id: i64) -> Result<Vec<(i64, i64)>>;
+
+    /// Users with a current vote on `post_id` in any tag
+    async fn get_voter_ids(&self, post_id: i64) -> Result<Vec<i64>>;
+
+    /// Number of current votes of `user_id` per tag, as `(tag, votes)`, most votes first
+    async fn get_vote_counts_per_tag(&self, user_id: i64) -> Result<Vec<(String, i64)>>;
+
+    /// How often `user_id` changed the direction of a vote while being shown a note, including
+    /// archived votes
+    async fn count_votes_changed_after_note(&self, user_id: i64) -> Result<i64>;
+
+    // tags
+
+    async fn get_or_insert_tag_id(&self, tag: &str) -> Result<i64>;
+
+    async fn get_tag_id(&self, tag: &str) -> Result<Option<i64>>;
+
+    /// Tags with the most tallied posts
+    async fn get_top_tags(&self, limit: i64) -> Result<Vec<String>>;
+
+    /// Tags that `post_id` is tallied in, alphabetically
+    async fn get_post_tags(&self, post_id: i64) -> Result<Vec<String>>;
+
+    // users
+
+    /// Returns the id of the new user. Only the hash of the secret is stored, see
+    /// [crate::auth::hash_secret].
+    async fn create_user(&self, secret_hash: &str) -> Result<i64>;
+
+    async fn user_id_from_secret_hash(&self, secret_hash: &str) -> Result<Option<i64>>;
+
+    async fn user_exists(&self, user_id: i64) -> Result<bool>;
+
+    /// Replaces the secret of `user_id`, the old one stops working
+    async fn set_secret_hash(&self, user_id: i64, secret_hash: &str) -> Result<()>;
+
+    /// Sets the username and password of `user_id`, see [crate::auth::set_credentials]
+    async fn set_credentials(
+        &self,
+        user_id: i64,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<()>;
+
+    /// User id and password hash of `username`
+    async fn get_credentials(&self, username: &str) -> Result<Option<(i64, String)>>;
+
+    async fn get_username(&self, user_id: i64) -> Result<Option<String>>;
+
+    /// None removes the display name, see [crate::pseudonym::author_name]
+    async fn set_display_name(&self, user_id: i64, display_name: Option<&str>) -> Result<()>;
+
+    async fn get_display_name(&self, user_id: i64) -> Result<Option<String>>;
+
+    /// Number of current (not retracted) votes of `user_id`
+    async fn count_votes(&self, user_id: i64) -> Result<i64>;
+
+    /// Moves the posts and votes of `from_user_id` to `into_user_id`, for someone who used two
+    /// devices with separate accounts. Where both voted on the same post in the same tag, the
+    /// vote history of the user who voted last is kept and the other one is archived. Merging the
+    /// histories would make votes of one device look like a change of mind after seeing a note on
+    /// the other. `from_user_id` remains as an account without posts and votes.
+    async fn merge_users(&self, from_user_id: i64, into_user_id: i64) -> Result<MergeReport>;
+
+    /// All votes `user_id` ever cast, including archived ones, oldest first
+    async fn get_vote_history(&self, user_id: i64) -> Result<Vec<HistoricVote>>;
+
+    /// Deletes `user_id` with its sessions, device links, API tokens, follows, notifications,
+    /// bookmarks, mutes and blocks. Its votes and posts move to a new tombstone user with
+    /// `tombstone_secret_hash`, a hash nobody knows the secret of, so that tallies and threads
+    /// stay intact. Each deleted account gets its own tombstone, because votes of different users
+    /// on the same post must not collapse into one. With `erase_posts`, the content of the posts
+    /// is replaced by [DELETED_POST_CONTENT].
+    async fn delete_user(
+        &self,
+        user_id: i64,
+        tombstone_secret_hash: &str,
+        erase_posts: bool,
+    ) -> Result<DeletionReport>;
+
+    // sessions
+
+    async fn create_session(&self, user_id: i64, token_hash: &str) -> Result<Session>;
+
+    /// Returns the session of the token and records its use
+    async fn use_session(&self, token_hash: &str) -> Result<Option<Session>>;
+
+    /// Sessions of `user_id`, most recently used first
+    async fn get_sessions(&self, user_id: i64) -> Result<Vec<Session>>;
+
+    /// Revokes a session of `user_id`. Returns false if there is no such session.
+    async fn delete_session(&self, user_id: i64, session_id: i64) -> Result<bool>;
+
+    /// Revokes all sessions of `user_id` except `keep_session_id`, returns how many
+    async fn delete_other_sessions(&self, user_id: i64, keep_session_id: i64) -> Result<u64>;
+
+    async fn create_device_link(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        valid_minutes: i64,
+    ) -> Result<()>;
+
+    /// The user of an unexpired device link
+    async fn device_link_user_id(&self, token_hash: &str) -> Result<Option<i64>>;
+
+    /// Deletes the link, so that it can't be used again. Also cleans up expired links.
+    async fn delete_device_link(&self, token_hash: &str) -> Result<()>;
+
+    async fn delete_device_links(&self, user_id: i64) -> Result<()>;
+
+    // api tokens
+
+    /// `scopes` as in [ApiToken::scopes], no expiry without `valid_days`
+    async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        token_hash: &str,
+        scopes: &str,
+        valid_days: Option<i64>,
+    ) -> Result<ApiToken>;
+
+    /// The token, unless it expired
+    async fn api_token_from_hash(&self, token_hash: &str) -> Result<Option<ApiToken>>;
+
+    /// Tokens of `user_id` including expired ones, newest first
+    async fn get_api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>>;
+
+    /// Returns false if `user_id` has no such token
+    async fn delete_api_token(&self, user_id: i64, token_id: i64) -> Result<bool>;
+
+    // follows
+
+    /// Does nothing if `user_id` already follows `tag`
+    async fn follow_tag(&self, user_id: i64, tag: &str) -> Result<()>;
+
+    async fn unfollow_tag(&self, user_id: i64, tag: &str) -> Result<()>;
+
+    /// Tags that `user_id` follows, in the order they were followed
+    async fn get_followed_tags(&self, user_id: i64) -> Result<Vec<String>>;
+
+    /// Does nothing if `user_id` already follows `followed_user_id`
+    async fn follow_user(&self, user_id: i64, followed_user_id: i64) -> Result<()>;
+
+    async fn unfollow_user(&self, user_id: i64, followed_user_id: i64) -> Result<()>;
+
+    /// Users that `user_id` follows, in the order they were followed
+    async fn get_followed_user_ids(&self, user_id: i64) -> Result<Vec<i64>>;
+
+    /// The home feed of `user_id`: top-level posts in followed tags and by followed users, as
+    /// `(tag, post)`, ranked like [Repository::get_posts_for_tag]. A post that has votes in
+    /// several of these tags appears once, with the tag it ranks best in.
+    async fn get_feed(&self, user_id: i64) -> Result<Vec<(String, Post)>>;
+
+    // mutes and blocks
+
+    /// Does nothing if `user_id` already muted `tag`
+    async fn mute_tag(&self, user_id: i64, tag: &str) -> Result<()>;
+
+    async fn unmute_tag(&self, user_id: i64, tag: &str) -> Result<()>;
+
+    /// Tags that `user_id` muted, in the order they were muted
+    async fn get_muted_tags(&self, user_id: i64) -> Result<Vec<String>>;
+
+    /// Does nothing if `user_id` already blocked `blocked_user_id`
+    async fn block_user(&self, user_id: i64, blocked_user_id: i64) -> Result<()>;
+
+    async fn unblock_user(&self, user_id: i64, blocked_user_id: i64) -> Result<()>;
+
+    /// Users that `user_id` blocked, in the order they were blocked
+    async fn get_blocked_user_ids(&self, user_id: i64) -> Result<Vec<i64>>;
+
+    // notifications
+
+    /// Does nothing if `user_id` was already notified of the same event
+    async fn create_notification(
+        &self,
+        user_id: i64,
+        kind: NotificationKind,
+        tag: &str,
+        post_id: i64,
+        note_id: i64,
+    ) -> Result<()>;
+
+    /// Newest first
+    async fn get_notifications(&self, user_id: i64, limit: i64) -> Result<Vec<Notification>>;
+
+    async fn count_unread_notifications(&self, user_id: i64) -> Result<i64>;
+
+    async fn mark_notifications_read(&self, user_id: i64) -> Result<()>;
+
+    // bookmarks
+
+    /// Bookmarking a post again only changes its tag
+    async fn bookmark_post(&self, user_id: i64, tag: &str, post_id: i64) -> Result<()>;
+
+    async fn remove_bookmark(&self, user_id: i64, post_id: i64) -> Result<()>;
+
+    async fn get_bookmark(&self, user_id: i64, post_id: i64) -> Result<Option<Bookmark>>;
+
+    /// Newest first
+    async fn get_bookmarks(&self, user_id: i64) -> Result<Vec<Bookmark>>;
+
+    // tallies
+
+    /// Votes on `post_id` in `tag`
+    async fn current_tally(&self, tag: &str, post_id: i64) -> Result<Tally>;
+
+    /// Informed tallies of `post_id` and, recursively, of all notes below it, in `tag`
+    async fn informed_tallies(&self, tag: &str, post_id: i64) -> Result<Vec<InformedTally>>;
+}
+
+// TODO: we probably only want to allow a limited character set
+// TODO: can #global be gamed? Does it give you some advantage to post in #global?
+pub fn normalize_tag(tag: &str) -> String {
+    tag.to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+/// Normalized, distinct and non-empty tags, in their original order. A leading `#` is dropped.
+pub fn normalize_tags<S: AsRef<str>>(tags: impl IntoIterator<Item = S>) -> Vec<String> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags {
+        let tag = normalize_tag(tag.as_ref().trim_start_matches('#'));
+        if !tag.is_empty() && !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+/// Splits user input like `#science, politics` into tags, see [normalize_tags]
+pub fn parse_tags(input: &str) -> Vec<String> {
+    normalize_tags(input.split(|c: char| c == ',' || c.is_whitespace()))
+}
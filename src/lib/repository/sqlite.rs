@@ -0,0 +1,1482 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use sqlx::SqlitePool;
+
+use crate::references::parse_post_references;
+use crate::repository::{Repository, DELETED_POST_CONTENT};
+use crate::structs::{
+    ApiToken, Bookmark, DeletionReport, Direction, HistoricVote, InformedTally, MergeReport,
+    Notification, NotificationKind, Post, Session, Tally, EMPTY_TALLY,
+};
+
+#[derive(Clone)]
+pub struct SqliteRepository {
+    pub pool: SqlitePool,
+}
+
+impl SqliteRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+/// `(user_id, tag_id, post_id)` of the vote histories that lose a merge: both users voted on the
+/// post in the tag, and the other one voted last. Binds the two user ids.
+const MERGE_SUPERSEDED_HISTORIES: &str = r#"
+    with latest as (
+        select user_id, tag_id, post_id, max(created) as created, max(rowid) as last_rowid
+        from vote_history
+        where user_id in (?, ?)
+        group by user_id, tag_id, post_id
+    )
+    select older.user_id, older.tag_id, older.post_id
+    from latest older
+    join latest newer
+        on newer.tag_id = older.tag_id
+        and newer.post_id = older.post_id
+        and newer.user_id != older.user_id
+    where (older.created, older.last_rowid) < (newer.created, newer.last_rowid)
+"#;
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+struct InformedTallyQueryResult {
+    post_id: i64,
+    note_id: i64,
+    upvotes_given_shown_this_note: i64,
+    votes_given_shown_this_note: i64,
+    upvotes_given_not_shown_this_note: i64,
+    votes_given_not_shown_this_note: i64,
+    upvotes_for_note: i64,
+    votes_for_note: i64,
+}
+
+impl InformedTallyQueryResult {
+    fn informed_tally(&self) -> InformedTally {
+        InformedTally {
+            post_id: self.post_id,
+            note_id: self.note_id,
+            given_not_shown_this_note: Tally {
+                upvotes: self.upvotes_given_not_shown_this_note,
+                total: self.votes_given_not_shown_this_note,
+            },
+            given_shown_this_note: Tally {
+                upvotes: self.upvotes_given_shown_this_note,
+                total: self.votes_given_shown_this_note,
+            },
+            for_note: Tally {
+                upvotes: self.upvotes_for_note,
+                total: self.votes_for_note,
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl Repository for SqliteRepository {
+    // TODO: if a new post is untagged, do we post in in #global?
+    async fn create_post(
+        &self,
+        tags: &[String],
+        parent_id: Option<i64>,
+        content: &str,
+        author_id: i64,
+    ) -> Result<i64> {
+        if tags.is_empty() {
+            bail!("A post needs at least one tag");
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        let created_post_id = sqlx::query_scalar::<_, i64>(
+            r#"
+                insert into posts (content, parent_id, author_id)
+                values (?, ?, ?)
+                returning id
+            "#,
+        )
+        .bind(content)
+        .bind(parent_id)
+        .bind(author_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        for tag in tags {
+            sqlx::query("insert or ignore into tags (tag) values (?)")
+                .bind(tag)
+                .execute(&mut *tx)
+                .await?;
+
+            // the post is new, so there is no current vote to compare with
+            sqlx::query(
+                r#"
+                    insert into vote_history (user_id, tag_id, post_id, note_id, direction)
+                    select ?, id, ?, null, ?
+                    from tags
+                    where tag = ?
+                "#,
+            )
+            .bind(author_id)
+            .bind(created_post_id)
+            .bind(Direction::Up as i32)
+            .bind(tag)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        for referenced_post_id in parse_post_references(content) {
+            if referenced_post_id == created_post_id {
+                continue;
+            }
+            sqlx::query(
+                r#"
+                    insert into post_references (post_id, referenced_post_id)
+                    select ?, id
+                    from posts
+                    where id = ?
+                "#,
+            )
+            .bind(created_post_id)
+            .bind(referenced_post_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(created_post_id)
+    }
+
+    async fn get_post(&self, post_id: i64) -> Result<Option<Post>> {
+        let post = sqlx::query_as::<_, Post>(
+            r#"
+                select
+                      id
+                    , content
+                    , parent_id
+                    , author_id
+                from posts
+                where id = ?
+            "#,
+        )
+        .bind(post_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(post)
+    }
+
+    async fn get_posts_for_tag(&self, tag: &str) -> Result<Vec<Post>> {
+        let tag_id: Option<i64> = self.get_tag_id(tag).await?;
+
+        let result: Vec<Post> = match tag_id {
+            Some(tag_id) => {
+                sqlx::query_as::<_, Post>(
+                    r#"
+                        select
+                              id
+                            , content
+                            , parent_id
+                            , author_id
+                        from posts
+                        join current_tally ct
+                        on posts.id = ct.post_id
+                        and ct.tag_id = ?
+                        where posts.parent_id is null
+                        order by ct.upvotes - (ct.votes - ct.upvotes) desc
+                        --order by ct.upvotes * (1 + log((ct.upvotes + 1) / (ct.votes + 2))) desc
+                    "#,
+                )
+                .bind(tag_id)
+                .fetch_all(&self.pool)
+                .await?
+            }
+            None => vec![],
+        };
+        Ok(result)
+    }
+
+    async fn get_replies(&self, tag: &str, post_id: i64) -> Result<Vec<Post>> {
+        // TODO: sort replies by score for tag
+        let tag_id = self.get_tag_id(tag).await?;
+
+        let posts = sqlx::query_as::<_, Post>(
+            r#"
+                select
+                      id
+                    , content
+                    , parent_id
+                    , author_id
+                from posts
+                join current_tally ct
+                on posts.id = ct.post_id
+                and ct.tag_id = ?
+                where parent_id is ?
+                order by ct.upvotes * (1 + log(ct.upvotes / ct.votes)) desc
+            "#,
+        )
+        .bind(tag_id)
+        .bind(post_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(posts)
+    }
+
+    async fn get_referenced_posts(&self, post_id: i64) -> Result<Vec<Post>> {
+        let posts = sqlx::query_as::<_, Post>(
+            r#"
+                select
+                      id
+                    , content
+                    , parent_id
+                    , author_id
+                from post_references
+                join posts on (posts.id = referenced_post_id)
+                where post_id = ?
+            "#,
+        )
+        .bind(post_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(posts)
+    }
+
+    async fn get_referencing_posts(&self, post_id: i64) -> Result<Vec<Post>> {
+        let posts = sqlx::query_as::<_, Post>(
+            r#"
+                select
+                      id
+                    , content
+                    , parent_id
+                    , author_id
+                from post_references
+                join posts on (posts.id = post_references.post_id)
+                where referenced_post_id = ?
+                order by id desc
+            "#,
+        )
+        .bind(post_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(posts)
+    }
+
+    async fn get_posts_by_author(&self, user_id: i64) -> Result<Vec<Post>> {
+        let posts = sqlx::query_as::<_, Post>(
+            r#"
+                select
+                      id
+                    , content
+                    , parent_id
+                    , author_id
+                from posts
+                where author_id = ?
+                order by id desc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(posts)
+    }
+
+    async fn vote(
+        &self,
+        user_id: i64,
+        tag: &str,
+        post_id: i64,
+        note_id: Option<i64>,
+        direction: Direction,
+    ) -> Result<()> {
+        let direction_i32 = direction as i32;
+
+        let tag_id = self.get_or_insert_tag_id(tag).await?;
+
+        sqlx::query(
+            r#"
+                with parameters as (
+                    select
+                        ? as user_id,
+                        ? as tag_id,
+                        ? as post_id,
+                        ? as note_id,
+                        ? as direction
+                )
+                , duplicates as (
+                    select
+                          parameters.user_id
+                        , parameters.tag_id
+                        , parameters.post_id
+                        , parameters.direction == ifnull(current_vote.direction, 0) as duplicate
+                    from parameters
+                    left join current_vote using (user_id, tag_id, post_id)
+                )
+                insert into vote_history (
+                      user_id
+                    , tag_id
+                    , post_id
+                    , note_id
+                    , direction
+                )
+                select
+                      parameters.user_id
+                    , parameters.tag_id
+                    , parameters.post_id
+                    , parameters.note_id
+                    , parameters.direction
+                from parameters
+                join duplicates
+                where not duplicate
+            "#,
+        )
+        .bind(user_id)
+        .bind(tag_id)
+        .bind(post_id)
+        .bind(note_id)
+        .bind(direction_i32)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_positions_for_post(&self, post_id: i64, user_id: i64) -> Result<Vec<(i64, i64)>> {
+        let query = r#"
+            WITH ancestors AS
+            (
+              SELECT id, parent_id
+              FROM posts
+              WHERE id = ?
+              UNION ALL
+              SELECT p.id, p.parent_id
+              FROM ancestors a
+              INNER JOIN posts p ON a.parent_id = p.id
+            )
+            , children as (
+              select id from posts where parent_id = ?
+            )
+            SELECT post_id, direction
+            FROM (select id from ancestors UNION ALL select id from children) ids
+            join current_vote on (post_id = id)
+            where user_id = ?
+        "#;
+
+        // execute the query and get a vector of Votes
+        let positions: Vec<(i64, i64)> = sqlx::query_as::<_, (i64, i64)>(query)
+            .bind(post_id)
+            .bind(post_id)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(positions)
+    }
+
+    async fn get_positions_for_tag(&self, tag: &str, user_id: i64) -> Result<Vec<(i64, i64)>> {
+        let query = r#"
+            select
+                post_id, direction
+            from
+                current_vote
+                join posts on (post_id = posts.id)
+                join tags on (tag_id = tags.id)
+            where
+                user_id = ?
+                and tag = ?
+                and posts.parent_id is null
+        "#;
+
+        // execute the query and get a vector of Votes
+        let positions: Vec<(i64, i64)> = sqlx::query_as::<_, (i64, i64)>(query)
+            .bind(user_id)
+            .bind(tag)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(positions)
+    }
+
+    async fn get_voter_ids(&self, post_id: i64) -> Result<Vec<i64>> {
+        let user_ids = sqlx::query_scalar::<_, i64>(
+            r#"
+                select distinct user_id
+                from current_vote
+                where post_id = ?
+                order by user_id
+            "#,
+        )
+        .bind(post_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(user_ids)
+    }
+
+    async fn get_vote_counts_per_tag(&self, user_id: i64) -> Result<Vec<(String, i64)>> {
+        let counts = sqlx::query_as::<_, (String, i64)>(
+            r#"
+                select tags.tag, count(*) as votes
+                from current_vote
+                join tags on (tags.id = current_vote.tag_id)
+                where current_vote.user_id = ?
+                group by tags.tag
+                order by votes desc, tags.tag
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(counts)
+    }
+
+    async fn count_votes_changed_after_note(&self, user_id: i64) -> Result<i64> {
+        let count = sqlx::query_scalar::<_, i64>(
+            r#"
+                with history as (
+                    select rowid as vote_rowid, tag_id, post_id, note_id, direction, created
+                    from vote_history
+                    where user_id = ?
+                    union all
+                    select vote_rowid, tag_id, post_id, note_id, direction, created
+                    from vote_history_archive
+                    where user_id = ?
+                )
+                , changes as (
+                    select
+                          note_id
+                        , direction
+                        , lag(direction) over (
+                            partition by tag_id, post_id order by created, vote_rowid
+                        ) as previous_direction
+                    from history
+                )
+                select count(*)
+                from changes
+                where note_id is not null
+                and direction != previous_direction
+            "#,
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count)
+    }
+
+    async fn get_or_insert_tag_id(&self, tag: &str) -> Result<i64> {
+        let tag_id = sqlx::query_scalar::<_, i64>(
+            r#"
+                insert or ignore into tags (tag) values (?)
+                returning id
+            "#,
+        )
+        .bind(tag)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(match tag_id {
+            None => self.get_tag_id(tag).await?.unwrap(),
+            Some(tag_id) => tag_id,
+        })
+    }
+
+    async fn get_tag_id(&self, tag: &str) -> Result<Option<i64>> {
+        let tag_id = sqlx::query_scalar::<_, i64>(
+            r#"
+                select id
+                from tags
+                where tag = ?
+            "#,
+        )
+        .bind(tag)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(tag_id)
+    }
+
+    async fn get_top_tags(&self, limit: i64) -> Result<Vec<String>> {
+        let result = sqlx::query_scalar::<_, String>(
+            r#"
+                select tag
+                from current_tally join tags on (tags.id = tag_id)
+                group by tag_id
+                order by count(*) desc
+                limit ?
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(result)
+    }
+
+    async fn get_post_tags(&self, post_id: i64) -> Result<Vec<String>> {
+        let tags = sqlx::query_scalar::<_, String>(
+            r#"
+                select tag
+                from current_tally join tags on (tags.id = tag_id)
+                where post_id = ?
+                order by tag
+            "#,
+        )
+        .bind(post_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tags)
+    }
+
+    async fn create_user(&self, secret_hash: &str) -> Result<i64> {
+        let id =
+            sqlx::query_scalar::<_, i64>("INSERT INTO users (secret_hash) VALUES (?) RETURNING id")
+                .bind(secret_hash)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok(id)
+    }
+
+    async fn user_id_from_secret_hash(&self, secret_hash: &str) -> Result<Option<i64>> {
+        Ok(
+            sqlx::query_scalar!("SELECT id from users WHERE secret_hash = ?", secret_hash)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn user_exists(&self, user_id: i64) -> Result<bool> {
+        let exists =
+            sqlx::query_scalar::<_, bool>("select exists (select 1 from users where id = ?)")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(exists)
+    }
+
+    async fn count_votes(&self, user_id: i64) -> Result<i64> {
+        let count = sqlx::query_scalar::<_, i64>(
+            r#"
+                select count(*)
+                from current_vote
+                where user_id = ?
+            "#,
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count)
+    }
+
+    async fn merge_users(&self, from_user_id: i64, into_user_id: i64) -> Result<MergeReport> {
+        if from_user_id == into_user_id {
+            bail!("Cannot merge user {from_user_id} into itself");
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        // take the write lock before reading, the latest votes decide which history is kept
+        sqlx::query("delete from vote_history_archive where false")
+            .execute(&mut *tx)
+            .await?;
+
+        let users = sqlx::query_scalar::<_, i64>("select count(*) from users where id in (?, ?)")
+            .bind(from_user_id)
+            .bind(into_user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if users != 2 {
+            bail!("User {from_user_id} or {into_user_id} does not exist");
+        }
+
+        let archived_votes = sqlx::query(&format!(
+            r#"
+                insert into vote_history_archive (
+                    vote_rowid, user_id, tag_id, post_id, note_id, direction, created
+                )
+                select rowid, user_id, tag_id, post_id, note_id, direction, created
+                from vote_history
+                where (user_id, tag_id, post_id) in ({MERGE_SUPERSEDED_HISTORIES})
+            "#
+        ))
+        .bind(from_user_id)
+        .bind(into_user_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+        sqlx::query(&format!(
+            "delete from vote_history where (user_id, tag_id, post_id) in ({MERGE_SUPERSEDED_HISTORIES})"
+        ))
+        .bind(from_user_id)
+        .bind(into_user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        let votes = sqlx::query("update vote_history set user_id = ? where user_id = ?")
+            .bind(into_user_id)
+            .bind(from_user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        let posts = sqlx::query("update posts set author_id = ? where author_id = ?")
+            .bind(into_user_id)
+            .bind(from_user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(MergeReport {
+            posts,
+            votes,
+            archived_votes,
+        })
+    }
+
+    async fn get_vote_history(&self, user_id: i64) -> Result<Vec<HistoricVote>> {
+        let votes = sqlx::query_as::<_, HistoricVote>(
+            r#"
+                with history as (
+                    select rowid as vote_rowid, tag_id, post_id, note_id, direction, created
+                    from vote_history
+                    where user_id = ?
+                    union all
+                    select vote_rowid, tag_id, post_id, note_id, direction, created
+                    from vote_history_archive
+                    where user_id = ?
+                )
+                select
+                      tags.tag
+                    , post_id
+                    , note_id
+                    , direction
+                    , cast(created as text) as created
+                from history
+                join tags on (tags.id = history.tag_id)
+                order by history.created, vote_rowid
+            "#,
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(votes)
+    }
+
+    async fn delete_user(
+        &self,
+        user_id: i64,
+        tombstone_secret_hash: &str,
+        erase_posts: bool,
+    ) -> Result<DeletionReport> {
+        let mut tx = self.pool.begin().await?;
+
+        let tombstone_user_id =
+            sqlx::query_scalar::<_, i64>("insert into users (secret_hash) values (?) returning id")
+                .bind(tombstone_secret_hash)
+                .fetch_one(&mut *tx)
+                .await?;
+
+        let votes = sqlx::query("update vote_history set user_id = ? where user_id = ?")
+            .bind(tombstone_user_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        sqlx::query("update vote_history_archive set user_id = ? where user_id = ?")
+            .bind(tombstone_user_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        if erase_posts {
+            sqlx::query(
+                r#"
+                    delete from post_references
+                    where post_id in (select id from posts where author_id = ?)
+                "#,
+            )
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query("update posts set content = ? where author_id = ?")
+                .bind(DELETED_POST_CONTENT)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let posts = sqlx::query("update posts set author_id = ? where author_id = ?")
+            .bind(tombstone_user_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+
+        for table in [
+            "sessions",
+            "device_links",
+            "api_tokens",
+            "tag_follows",
+            "user_follows",
+            "notifications",
+            "bookmarks",
+            "tag_mutes",
+            "user_blocks",
+        ] {
+            sqlx::query(&format!("delete from {table} where user_id = ?"))
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for (table, column) in [
+            ("user_follows", "followed_user_id"),
+            ("user_blocks", "blocked_user_id"),
+        ] {
+            sqlx::query(&format!("delete from {table} where {column} = ?"))
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let deleted = sqlx::query("delete from users where id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            bail!("User {user_id} does not exist");
+        }
+
+        tx.commit().await?;
+
+        Ok(DeletionReport {
+            tombstone_user_id,
+            posts,
+            votes,
+        })
+    }
+
+    async fn set_secret_hash(&self, user_id: i64, secret_hash: &str) -> Result<()> {
+        sqlx::query("update users set secret_hash = ? where id = ?")
+            .bind(secret_hash)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_credentials(
+        &self,
+        user_id: i64,
+        username: &str,
+        password_hash: &str,
+    ) -> Result<()> {
+        sqlx::query("update users set username = ?, password_hash = ? where id = ?")
+            .bind(username)
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_credentials(&self, username: &str) -> Result<Option<(i64, String)>> {
+        let credentials = sqlx::query_as::<_, (i64, String)>(
+            r#"
+                select id, password_hash
+                from users
+                where username = ?
+                and password_hash is not null
+            "#,
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(credentials)
+    }
+
+    async fn get_username(&self, user_id: i64) -> Result<Option<String>> {
+        let username =
+            sqlx::query_scalar::<_, Option<String>>("select username from users where id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(username.flatten())
+    }
+
+    async fn set_display_name(&self, user_id: i64, display_name: Option<&str>) -> Result<()> {
+        sqlx::query("update users set display_name = ? where id = ?")
+            .bind(display_name)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_display_name(&self, user_id: i64) -> Result<Option<String>> {
+        let display_name =
+            sqlx::query_scalar::<_, Option<String>>("select display_name from users where id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(display_name.flatten())
+    }
+
+    async fn create_session(&self, user_id: i64, token_hash: &str) -> Result<Session> {
+        let session = sqlx::query_as::<_, Session>(
+            r#"
+                insert into sessions (user_id, token_hash) values (?, ?)
+                returning
+                    id
+                  , user_id
+                  , cast(created as text) as created
+                  , cast(last_used as text) as last_used
+            "#,
+        )
+        .bind(user_id)
+        .bind(token_hash)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    async fn use_session(&self, token_hash: &str) -> Result<Option<Session>> {
+        // at most one write every few minutes per session
+        let session = sqlx::query_as::<_, Session>(
+            r#"
+                update sessions
+                set last_used = current_timestamp
+                where token_hash = ?
+                and last_used < datetime('now', '-5 minutes')
+                returning
+                    id
+                  , user_id
+                  , cast(created as text) as created
+                  , cast(last_used as text) as last_used
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        if session.is_some() {
+            return Ok(session);
+        }
+
+        let session = sqlx::query_as::<_, Session>(
+            r#"
+                select
+                    id
+                  , user_id
+                  , cast(created as text) as created
+                  , cast(last_used as text) as last_used
+                from sessions
+                where token_hash = ?
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    async fn get_sessions(&self, user_id: i64) -> Result<Vec<Session>> {
+        let sessions = sqlx::query_as::<_, Session>(
+            r#"
+                select
+                    id
+                  , user_id
+                  , cast(created as text) as created
+                  , cast(last_used as text) as last_used
+                from sessions
+                where user_id = ?
+                order by last_used desc, id desc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    async fn delete_session(&self, user_id: i64, session_id: i64) -> Result<bool> {
+        let result = sqlx::query("delete from sessions where user_id = ? and id = ?")
+            .bind(user_id)
+            .bind(session_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn delete_other_sessions(&self, user_id: i64, keep_session_id: i64) -> Result<u64> {
+        let result = sqlx::query("delete from sessions where user_id = ? and id != ?")
+            .bind(user_id)
+            .bind(keep_session_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn create_device_link(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        valid_minutes: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+                insert into device_links (token_hash, user_id, expires)
+                values (?, ?, datetime('now', '+' || ? || ' minutes'))
+            "#,
+        )
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(valid_minutes)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn device_link_user_id(&self, token_hash: &str) -> Result<Option<i64>> {
+        let user_id = sqlx::query_scalar::<_, i64>(
+            r#"
+                select user_id
+                from device_links
+                where token_hash = ?
+                and expires > current_timestamp
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user_id)
+    }
+
+    async fn delete_device_link(&self, token_hash: &str) -> Result<()> {
+        sqlx::query(
+            "delete from device_links where token_hash = ? or expires <= current_timestamp",
+        )
+        .bind(token_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn delete_device_links(&self, user_id: i64) -> Result<()> {
+        sqlx::query("delete from device_links where user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        token_hash: &str,
+        scopes: &str,
+        valid_days: Option<i64>,
+    ) -> Result<ApiToken> {
+        let token = sqlx::query_as::<_, ApiToken>(
+            r#"
+                insert into api_tokens (user_id, name, token_hash, scopes, expires)
+                values (?, ?, ?, ?, datetime('now', '+' || ? || ' days'))
+                returning
+                    id
+                  , user_id
+                  , name
+                  , scopes
+                  , cast(created as text) as created
+                  , cast(expires as text) as expires
+            "#,
+        )
+        .bind(user_id)
+        .bind(name)
+        .bind(token_hash)
+        .bind(scopes)
+        .bind(valid_days)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn api_token_from_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let token = sqlx::query_as::<_, ApiToken>(
+            r#"
+                select
+                    id
+                  , user_id
+                  , name
+                  , scopes
+                  , cast(created as text) as created
+                  , cast(expires as text) as expires
+                from api_tokens
+                where token_hash = ?
+                and (expires is null or expires > current_timestamp)
+            "#,
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    async fn get_api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>> {
+        let tokens = sqlx::query_as::<_, ApiToken>(
+            r#"
+                select
+                    id
+                  , user_id
+                  , name
+                  , scopes
+                  , cast(created as text) as created
+                  , cast(expires as text) as expires
+                from api_tokens
+                where user_id = ?
+                order by id desc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    async fn delete_api_token(&self, user_id: i64, token_id: i64) -> Result<bool> {
+        let result = sqlx::query("delete from api_tokens where user_id = ? and id = ?")
+            .bind(user_id)
+            .bind(token_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn follow_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        let tag_id = self.get_or_insert_tag_id(tag).await?;
+        sqlx::query("insert or ignore into tag_follows (user_id, tag_id) values (?, ?)")
+            .bind(user_id)
+            .bind(tag_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn unfollow_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+                delete from tag_follows
+                where user_id = ?
+                and tag_id = (select id from tags where tag = ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(tag)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_followed_tags(&self, user_id: i64) -> Result<Vec<String>> {
+        let tags = sqlx::query_scalar::<_, String>(
+            r#"
+                select tags.tag
+                from tag_follows
+                join tags on (tags.id = tag_follows.tag_id)
+                where tag_follows.user_id = ?
+                order by tag_follows.created, tag_follows.rowid
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tags)
+    }
+
+    async fn follow_user(&self, user_id: i64, followed_user_id: i64) -> Result<()> {
+        sqlx::query("insert or ignore into user_follows (user_id, followed_user_id) values (?, ?)")
+            .bind(user_id)
+            .bind(followed_user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn unfollow_user(&self, user_id: i64, followed_user_id: i64) -> Result<()> {
+        sqlx::query("delete from user_follows where user_id = ? and followed_user_id = ?")
+            .bind(user_id)
+            .bind(followed_user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_followed_user_ids(&self, user_id: i64) -> Result<Vec<i64>> {
+        let user_ids = sqlx::query_scalar::<_, i64>(
+            r#"
+                select followed_user_id
+                from user_follows
+                where user_id = ?
+                order by created, rowid
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(user_ids)
+    }
+
+    async fn get_feed(&self, user_id: i64) -> Result<Vec<(String, Post)>> {
+        let rows = sqlx::query_as::<_, (String, i64, String, Option<i64>, i64)>(
+            r#"
+                with followed as (
+                    select
+                          ct.tag_id
+                        , ct.post_id
+                        , ct.upvotes - (ct.votes - ct.upvotes) as score
+                    from current_tally ct
+                    join posts on (posts.id = ct.post_id)
+                    where posts.parent_id is null
+                    and (
+                        ct.tag_id in (select tag_id from tag_follows where user_id = ?)
+                        or posts.author_id in (
+                            select followed_user_id from user_follows where user_id = ?
+                        )
+                    )
+                )
+                -- with max(), sqlite takes tag_id from the row with the best score
+                , best as (
+                    select tag_id, post_id, max(score) as score
+                    from followed
+                    group by post_id
+                )
+                select
+                      tags.tag
+                    , posts.id
+                    , posts.content
+                    , posts.parent_id
+                    , posts.author_id
+                from best
+                join posts on (posts.id = best.post_id)
+                join tags on (tags.id = best.tag_id)
+                order by best.score desc, posts.id desc
+            "#,
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(tag, id, content, parent_id, author_id)| {
+                (
+                    tag,
+                    Post {
+                        id,
+                        content,
+                        parent_id,
+                        author_id,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    async fn mute_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        let tag_id = self.get_or_insert_tag_id(tag).await?;
+        sqlx::query("insert or ignore into tag_mutes (user_id, tag_id) values (?, ?)")
+            .bind(user_id)
+            .bind(tag_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn unmute_tag(&self, user_id: i64, tag: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+                delete from tag_mutes
+                where user_id = ?
+                and tag_id = (select id from tags where tag = ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(tag)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_muted_tags(&self, user_id: i64) -> Result<Vec<String>> {
+        let tags = sqlx::query_scalar::<_, String>(
+            r#"
+                select tags.tag
+                from tag_mutes
+                join tags on (tags.id = tag_mutes.tag_id)
+                where tag_mutes.user_id = ?
+                order by tag_mutes.created, tag_mutes.rowid
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tags)
+    }
+
+    async fn block_user(&self, user_id: i64, blocked_user_id: i64) -> Result<()> {
+        sqlx::query("insert or ignore into user_blocks (user_id, blocked_user_id) values (?, ?)")
+            .bind(user_id)
+            .bind(blocked_user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn unblock_user(&self, user_id: i64, blocked_user_id: i64) -> Result<()> {
+        sqlx::query("delete from user_blocks where user_id = ? and blocked_user_id = ?")
+            .bind(user_id)
+            .bind(blocked_user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_blocked_user_ids(&self, user_id: i64) -> Result<Vec<i64>> {
+        let user_ids = sqlx::query_scalar::<_, i64>(
+            r#"
+                select blocked_user_id
+                from user_blocks
+                where user_id = ?
+                order by created, rowid
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(user_ids)
+    }
+
+    async fn create_notification(
+        &self,
+        user_id: i64,
+        kind: NotificationKind,
+        tag: &str,
+        post_id: i64,
+        note_id: i64,
+    ) -> Result<()> {
+        let tag_id = self.get_or_insert_tag_id(tag).await?;
+        sqlx::query(
+            r#"
+                insert or ignore into notifications (user_id, kind, tag_id, post_id, note_id)
+                values (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(user_id)
+        .bind(kind.to_string())
+        .bind(tag_id)
+        .bind(post_id)
+        .bind(note_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_notifications(&self, user_id: i64, limit: i64) -> Result<Vec<Notification>> {
+        let notifications = sqlx::query_as::<_, Notification>(
+            r#"
+                select
+                      notifications.id
+                    , notifications.kind
+                    , tags.tag
+                    , notifications.post_id
+                    , notifications.note_id
+                    , cast(notifications.created as text) as created
+                    , notifications.read_at is not null as read
+                from notifications
+                join tags on (tags.id = notifications.tag_id)
+                where notifications.user_id = ?
+                order by notifications.created desc, notifications.id desc
+                limit ?
+            "#,
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(notifications)
+    }
+
+    async fn count_unread_notifications(&self, user_id: i64) -> Result<i64> {
+        let count = sqlx::query_scalar::<_, i64>(
+            "select count(*) from notifications where user_id = ? and read_at is null",
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
+    async fn mark_notifications_read(&self, user_id: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+                update notifications set read_at = current_timestamp
+                where user_id = ?
+                and read_at is null
+            "#,
+        )
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn bookmark_post(&self, user_id: i64, tag: &str, post_id: i64) -> Result<()> {
+        let tag_id = self.get_or_insert_tag_id(tag).await?;
+        sqlx::query(
+            r#"
+                insert into bookmarks (user_id, post_id, tag_id) values (?, ?, ?)
+                on conflict (user_id, post_id) do update set tag_id = excluded.tag_id
+            "#,
+        )
+        .bind(user_id)
+        .bind(post_id)
+        .bind(tag_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_bookmark(&self, user_id: i64, post_id: i64) -> Result<()> {
+        sqlx::query("delete from bookmarks where user_id = ? and post_id = ?")
+            .bind(user_id)
+            .bind(post_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_bookmark(&self, user_id: i64, post_id: i64) -> Result<Option<Bookmark>> {
+        let bookmark = sqlx::query_as::<_, Bookmark>(
+            r#"
+                select
+                      bookmarks.post_id
+                    , tags.tag
+                    , cast(bookmarks.created as text) as created
+                from bookmarks
+                join tags on (tags.id = bookmarks.tag_id)
+                where bookmarks.user_id = ?
+                and bookmarks.post_id = ?
+            "#,
+        )
+        .bind(user_id)
+        .bind(post_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(bookmark)
+    }
+
+    async fn get_bookmarks(&self, user_id: i64) -> Result<Vec<Bookmark>> {
+        let bookmarks = sqlx::query_as::<_, Bookmark>(
+            r#"
+                select
+                      bookmarks.post_id
+                    , tags.tag
+                    , cast(bookmarks.created as text) as created
+                from bookmarks
+                join tags on (tags.id = bookmarks.tag_id)
+                where bookmarks.user_id = ?
+                order by bookmarks.created desc, bookmarks.post_id desc
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(bookmarks)
+    }
+
+    async fn current_tally(&self, tag: &str, post_id: i64) -> Result<Tally> {
+        let query = r#"
+            select upvotes, votes as total
+            from current_tally
+            join tags on (tags.id = current_tally.tag_id)
+            where tags.tag = ? and post_id = ?
+        "#;
+
+        let tally: Option<Tally> = sqlx::query_as::<_, Tally>(query)
+            .bind(tag)
+            .bind(post_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(tally.unwrap_or(EMPTY_TALLY))
+    }
+
+    async fn informed_tallies(&self, tag: &str, post_id: i64) -> Result<Vec<InformedTally>> {
+        // first, get table which has stats for this note, all subnotes, and all subnotes
+        let query = r#"
+            WITH tag AS (SELECT id FROM tags WHERE tag = ?)
+            , children AS
+            (
+              SELECT
+                  tag_id
+                , post_id
+                , note_id
+                , votes_given_shown_this_note
+                , upvotes_given_shown_this_note
+                , votes_given_not_shown_this_note
+                , upvotes_given_not_shown_this_note
+              FROM current_informed_tally p
+              WHERE tag_id = (SELECT id FROM tag) AND post_id = ?
+              UNION ALL
+              SELECT
+                  p.tag_id
+                , p.post_id
+                , p.note_id
+                , p.votes_given_not_shown_this_note
+                , p.upvotes_given_not_shown_this_note
+                , p.votes_given_not_shown_this_note
+                , p.upvotes_given_not_shown_this_note
+              FROM children c
+              INNER JOIN current_informed_tally p ON p.tag_id = c.tag_id AND p.post_id = c.note_id
+            )
+            SELECT
+              children.*
+              , current_tally.votes as votes_for_note
+              , current_tally.upvotes as upvotes_for_note
+            FROM children join current_tally on (
+              current_tally.tag_id = children.tag_id
+              AND current_tally.post_id = children.note_id
+            );
+        "#;
+
+        // execute the query and get a vector of InformedTally
+        let tallies: Vec<InformedTally> = sqlx::query_as::<_, InformedTallyQueryResult>(query)
+            .bind(tag)
+            .bind(post_id)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|result| result.informed_tally())
+            .collect();
+
+        Ok(tallies)
+    }
+}
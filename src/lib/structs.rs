@@ -6,12 +6,23 @@ use derive_more::Display;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 /// Representation of a user. Provides various methods to find & update them
 #[derive(Serialize, sqlx::FromRow, Debug, Clone)]
 pub struct User {
     pub id: i64,
-    pub secret: String,
+}
+
+/// A signed in device, see [crate::auth]
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct Session {
+    pub id: i64,
+    pub user_id: i64,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`
+    pub created: String,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`. Updated at most every few minutes.
+    pub last_used: String,
 }
 
 #[derive(Clone)]
@@ -21,7 +32,7 @@ pub struct PageMeta {
     pub url: Option<String>,
 }
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
 pub struct Post {
     pub id: i64,
     pub content: String,
@@ -41,3 +52,179 @@ impl Direction {
         FromPrimitive::from_i32(direction).ok_or(anyhow!("Unknown direction value: {}", direction))
     }
 }
+
+#[derive(sqlx::FromRow, sqlx::Decode, Debug, Clone, Copy)]
+pub struct Tally {
+    pub upvotes: i64,
+    pub total: i64,
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tally({}/{})", self.upvotes, self.total)
+    }
+}
+
+pub const EMPTY_TALLY: Tally = Tally {
+    upvotes: 0,
+    total: 0,
+};
+
+/// What an API token may do
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
+pub enum ApiScope {
+    #[display(fmt = "read")]
+    Read,
+    #[display(fmt = "vote")]
+    Vote,
+    #[display(fmt = "post")]
+    Post,
+    /// Changes that only the user sees, like bookmarks and marking notifications as read
+    #[display(fmt = "account")]
+    Account,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 4] = [
+        ApiScope::Read,
+        ApiScope::Vote,
+        ApiScope::Post,
+        ApiScope::Account,
+    ];
+
+    pub fn from_name(name: &str) -> Option<ApiScope> {
+        ApiScope::ALL
+            .into_iter()
+            .find(|scope| scope.to_string() == name)
+    }
+
+    /// As stored in `api_tokens.scopes`
+    pub fn join(scopes: &[ApiScope]) -> String {
+        scopes
+            .iter()
+            .map(ApiScope::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// A bearer token for the API, see [crate::axum_extractors::ApiUser]
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    /// Comma separated, see [ApiScope::join]
+    pub scopes: String,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`
+    pub created: String,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`. Never expires if None.
+    pub expires: Option<String>,
+}
+
+impl ApiToken {
+    pub fn scopes(&self) -> Vec<ApiScope> {
+        self.scopes
+            .split(',')
+            .filter_map(ApiScope::from_name)
+            .collect()
+    }
+}
+
+/// Why a user is notified, as stored in `notifications.kind`
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Display)]
+pub enum NotificationKind {
+    /// `note_id` replied to the user's post `post_id`
+    #[display(fmt = "reply")]
+    Reply,
+    /// `note_id` became the top note of `post_id`, which the user voted on
+    #[display(fmt = "top_note")]
+    TopNote,
+    /// The user's `note_id` became the top note of `post_id`
+    #[display(fmt = "own_top_note")]
+    OwnTopNote,
+}
+
+impl NotificationKind {
+    pub const ALL: [NotificationKind; 3] = [
+        NotificationKind::Reply,
+        NotificationKind::TopNote,
+        NotificationKind::OwnTopNote,
+    ];
+
+    pub fn from_name(name: &str) -> Option<NotificationKind> {
+        NotificationKind::ALL
+            .into_iter()
+            .find(|kind| kind.to_string() == name)
+    }
+}
+
+/// See [crate::repository::Repository::get_notifications]
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct Notification {
+    pub id: i64,
+    /// See [NotificationKind]
+    pub kind: String,
+    pub tag: String,
+    pub post_id: i64,
+    pub note_id: i64,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`
+    pub created: String,
+    pub read: bool,
+}
+
+impl Notification {
+    pub fn kind(&self) -> Option<NotificationKind> {
+        NotificationKind::from_name(&self.kind)
+    }
+}
+
+/// A vote of a user from `vote_history` or `vote_history_archive`, see
+/// [crate::repository::Repository::get_vote_history]
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct HistoricVote {
+    pub tag: String,
+    pub post_id: i64,
+    pub note_id: Option<i64>,
+    pub direction: i64,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`
+    pub created: String,
+}
+
+/// A post saved by a user, see [crate::repository::Repository::get_bookmarks]
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct Bookmark {
+    pub post_id: i64,
+    /// The tag the post was bookmarked in
+    pub tag: String,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`
+    pub created: String,
+}
+
+/// What [crate::repository::Repository::delete_user] moved to the tombstone user
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeletionReport {
+    pub tombstone_user_id: i64,
+    pub posts: u64,
+    pub votes: u64,
+}
+
+/// What [crate::repository::Repository::merge_users] moved
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MergeReport {
+    pub posts: u64,
+    pub votes: u64,
+    /// Votes moved to `vote_history_archive` because the other user voted on the same post later
+    pub archived_votes: u64,
+}
+
+/// Votes on a post, split by whether the voter was shown a specific note
+#[derive(Debug, Clone)]
+pub struct InformedTally {
+    pub post_id: i64,
+    pub note_id: i64,
+
+    pub given_not_shown_this_note: Tally,
+    pub given_shown_this_note: Tally,
+    pub for_note: Tally,
+}
@@ -1,7 +1,8 @@
 use chatgpt::functions::schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::structs::Direction;
+use crate::markdown::render_markdown;
+use crate::structs::{Bookmark, Direction, Notification, Post};
 
 fn default_none() -> Option<i64> {
     None
@@ -10,7 +11,21 @@ fn default_none() -> Option<i64> {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiPost {
     pub id: i64,
+    /// Markdown source, as written by the author
     pub content: String,
+    /// Sanitized HTML rendering of `content`
+    #[serde(default)]
+    pub content_html: String,
+}
+
+impl ApiPost {
+    pub fn from_post(post: &Post) -> ApiPost {
+        ApiPost {
+            id: post.id,
+            content: post.content.clone(),
+            content_html: render_markdown(&post.content, true, &|_| None).into_string(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,12 +33,103 @@ pub struct ApiFrontpage {
     pub posts: Vec<ApiPost>,
 }
 
+/// The home feed of the token's user, see `/`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiFeed {
+    /// false if the user follows nothing and gets all posts in the global tag
+    pub personalized: bool,
+    /// Best first
+    pub posts: Vec<ApiFeedPost>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiFeedPost {
+    /// The tag the post is ranked in
+    pub tag: String,
+    #[serde(flatten)]
+    pub post: ApiPost,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ApiPostPage {
     pub parent_context: Vec<ApiPost>,
     pub post: ApiPost,
+    /// None when the top note is by a user the token's user blocked, see `note_blocked`
     pub note: Option<ApiPost>,
+    /// Whether the top note was left out because the token's user blocked its author
+    #[serde(default)]
+    pub note_blocked: bool,
     pub replies: Vec<ApiPost>,
+    /// Whether the token's user saved the post, None without token
+    pub bookmarked: Option<bool>,
+}
+
+/// The profile of the token's user, see `/user`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiUserProfile {
+    /// Display name or pseudonym, as shown on posts
+    pub name: String,
+    pub username: Option<String>,
+    /// Newest first
+    pub posts: Vec<ApiPost>,
+    /// Posts of the user that are currently the top note of their parent
+    pub top_notes: Vec<ApiPost>,
+    /// Most votes first
+    pub votes_per_tag: Vec<ApiTagVotes>,
+    /// How often the user changed a vote while being shown a note
+    pub votes_changed_after_note: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTagVotes {
+    pub tag: String,
+    /// Current votes, not counting retracted ones
+    pub votes: i64,
+}
+
+/// Posts saved by the token's user, see `/bookmarks`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiBookmarks {
+    /// Newest first
+    pub bookmarks: Vec<ApiBookmark>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiBookmark {
+    /// The tag the post was saved in
+    pub tag: String,
+    /// UTC, `YYYY-MM-DD HH:MM:SS`
+    pub created: String,
+    #[serde(flatten)]
+    pub post: ApiPost,
+}
+
+impl ApiBookmark {
+    pub fn from_bookmark(bookmark: Bookmark, post: &Post) -> ApiBookmark {
+        ApiBookmark {
+            tag: bookmark.tag,
+            created: bookmark.created,
+            post: ApiPost::from_post(post),
+        }
+    }
+}
+
+/// Saves or removes a bookmark
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiSetBookmark {
+    pub post_id: i64,
+    /// Defaults to the global tag if empty
+    #[serde(default)]
+    pub tag: String,
+    pub bookmarked: bool,
+}
+
+/// Notifications of the token's user, see `/notifications`
+#[derive(Debug, Serialize)]
+pub struct ApiNotifications {
+    pub unread: i64,
+    /// Newest first
+    pub notifications: Vec<Notification>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -39,6 +145,9 @@ pub struct ApiVote {
 pub struct ApiCreatePost {
     pub parent_id: Option<i64>,
     pub content: String,
+    /// Defaults to the global tag if empty
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -1,42 +1,127 @@
 mod api;
+mod backup;
 mod command_line_args;
-mod db;
+mod compaction;
+mod content_filter;
+mod csrf;
 mod db_setup;
 mod error;
+mod notifications;
 mod pages;
 
 mod http_server;
 mod http_static;
 
-mod probabilities;
 mod constants;
+mod probabilities;
+mod rate_limit;
+mod schema;
+mod validation;
 
 mod util;
 
 use clap::Parser;
 use http_server::start_http_server;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::command_line_args::CommandLineArgs;
-use crate::db_setup::setup_database;
+use crate::command_line_args::{Command, CommandLineArgs, MigrateCommand};
+use crate::constants::GLOBAL_TAG;
+use crate::db_setup::{connect_database, setup_database};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     init_tracing();
 
     let command_line_args = CommandLineArgs::parse();
-    let sqlite_pool = setup_database(&command_line_args.database).await;
 
-    match crate::probabilities::find_top_note(1, &sqlite_pool).await? {
+    // restore swaps the database file, so it must run before any connection is opened
+    if let Some(Command::Restore { file }) = &command_line_args.command {
+        return backup::restore(&command_line_args.database, file).await;
+    }
+
+    if let Some(Command::Migrate {
+        command: MigrateCommand::Run,
+    }) = &command_line_args.command
+    {
+        let database = connect_database(&command_line_args.database).await;
+        database.migrate().await.context("Unable to migrate")?;
+        println!("Finished migrating database.");
+        return Ok(());
+    }
+
+    if let Some(Command::Migrate {
+        command: MigrateCommand::Status,
+    }) = &command_line_args.command
+    {
+        let database = connect_database(&command_line_args.database).await;
+        let report = schema::schema_report(&database).await?;
+        print!("{report}");
+        if !report.is_ok() {
+            bail!("The database schema does not match this build");
+        }
+        return Ok(());
+    }
+
+    // backups are taken as is, before migrating
+    if let Some(Command::Backup { output }) = &command_line_args.command {
+        let database = connect_database(&command_line_args.database).await;
+        let path = backup::backup_database(&database, &command_line_args.backup, output.as_deref())
+            .await?;
+        println!("Wrote backup to {}", path.display());
+        return Ok(());
+    }
+
+    let database = setup_database(&command_line_args.database).await?;
+
+    if let Some(Command::CompactVotes {
+        horizon_days,
+        dry_run,
+    }) = &command_line_args.command
+    {
+        let report = compaction::compact_vote_history(&database, *horizon_days, *dry_run).await?;
+        let verb = if *dry_run {
+            "Would archive"
+        } else {
+            "Archived"
+        };
+        println!(
+            "{verb} {} superseded votes, {} votes remain in vote_history",
+            report.archived, report.remaining
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::MergeUsers { from, into }) = &command_line_args.command {
+        let report = database.repository().merge_users(*from, *into).await?;
+        println!(
+            "Moved {} posts and {} votes from user {from} to user {into}, archived {} superseded votes",
+            report.posts, report.votes, report.archived_votes
+        );
+        return Ok(());
+    }
+
+    if let Some(interval_minutes) = command_line_args.backup.backup_interval_minutes {
+        tokio::spawn(backup::periodic_backups(
+            database.clone(),
+            command_line_args.backup.clone(),
+            interval_minutes,
+        ));
+    }
+
+    let repo = database.repository();
+
+    match crate::probabilities::find_top_note(GLOBAL_TAG, 1, repo.as_ref()).await? {
         None => println!("No top note"),
-        Some((note_id, p, q)) => println!("Top note for post {} is {}. p={}, q={}", 1, note_id, p, q),
+        Some((note_id, p, q)) => {
+            println!("Top note for post {} is {}. p={}, q={}", 1, note_id, p, q)
+        }
     };
 
     tokio::select! {
-        res = start_http_server(sqlite_pool.clone()) => {
+        res = start_http_server(repo.clone(), command_line_args.rate_limit) => {
             res.context("http server crashed").unwrap();
         }
     }
@@ -0,0 +1,170 @@
+//! Events that notify users, shown at `/notifications` and `/api/v0/notifications`
+//!
+//! - The author of a post is notified of replies.
+//! - When a vote changes the top note of a post, everyone who voted on that post is notified,
+//!   and the author of the note as well.
+//!
+//! A vote on a post can change the top note of the post itself and of all of its ancestors,
+//! because top notes are chosen recursively, see [probabilities::find_top_note]. So [TopNotes]
+//! remembers them before the vote and compares afterwards. To bound the work per vote, it only
+//! looks at the closest [NOTIFIED_ANCESTORS] ancestors.
+//!
+//! Nobody is notified of posts by users they blocked.
+//!
+//! Notifying is best effort: failures are logged, but don't fail the vote or post that caused
+//! them. That includes loading the top notes before a vote.
+
+use anyhow::Result;
+use common::repository::Repository;
+use common::structs::NotificationKind;
+use tracing::warn;
+
+use crate::probabilities;
+
+/// How many notifications the page and the API show
+pub const NOTIFICATIONS_LIMIT: i64 = 100;
+
+/// How many ancestors of a voted post [TopNotes] checks for a new top note
+pub const NOTIFIED_ANCESTORS: usize = 5;
+
+/// Notifies the author of `parent_id` of the reply `post_id`, unless they replied to themselves
+/// or blocked the author of the reply
+pub async fn notify_reply(
+    tag: &str,
+    post_id: i64,
+    parent_id: i64,
+    author_id: i64,
+    repo: &dyn Repository,
+) {
+    if let Err(error) = try_notify_reply(tag, post_id, parent_id, author_id, repo).await {
+        warn!("Could not notify of reply {post_id}: {error}");
+    }
+}
+
+async fn try_notify_reply(
+    tag: &str,
+    post_id: i64,
+    parent_id: i64,
+    author_id: i64,
+    repo: &dyn Repository,
+) -> Result<()> {
+    let Some(parent) = repo.get_post(parent_id).await? else {
+        return Ok(());
+    };
+    let blocked = repo
+        .get_blocked_user_ids(parent.author_id)
+        .await?
+        .contains(&author_id);
+    if parent.author_id != author_id && !blocked {
+        repo.create_notification(
+            parent.author_id,
+            NotificationKind::Reply,
+            tag,
+            parent_id,
+            post_id,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// The top notes of a post and its ancestors at one point in time, as `(post_id, note_id)`
+pub struct TopNotes {
+    tag: String,
+    top_notes: Vec<(i64, Option<i64>)>,
+}
+
+impl TopNotes {
+    /// The top notes of `post_id` and its closest [NOTIFIED_ANCESTORS] ancestors. If they can't
+    /// be loaded, nothing is remembered and [TopNotes::notify_changes] does nothing.
+    pub async fn load(tag: &str, post_id: i64, repo: &dyn Repository) -> TopNotes {
+        let top_notes = match Self::try_load(tag, post_id, repo).await {
+            Ok(top_notes) => top_notes,
+            Err(error) => {
+                warn!("Could not load the top notes of post {post_id}: {error}");
+                vec![]
+            }
+        };
+        TopNotes {
+            tag: tag.to_string(),
+            top_notes,
+        }
+    }
+
+    async fn try_load(
+        tag: &str,
+        post_id: i64,
+        repo: &dyn Repository,
+    ) -> Result<Vec<(i64, Option<i64>)>> {
+        let mut post_ids = vec![post_id];
+        let mut parent_id = repo
+            .get_post(post_id)
+            .await?
+            .and_then(|post| post.parent_id);
+        while let Some(id) = parent_id {
+            if post_ids.len() > NOTIFIED_ANCESTORS {
+                break;
+            }
+            post_ids.push(id);
+            parent_id = repo.get_post(id).await?.and_then(|post| post.parent_id);
+        }
+
+        let mut top_notes = vec![];
+        for post_id in post_ids {
+            let note_id = probabilities::find_top_note(tag, post_id, repo)
+                .await?
+                .map(|(note_id, _, _)| note_id);
+            top_notes.push((post_id, note_id));
+        }
+        Ok(top_notes)
+    }
+
+    /// Notifies of the top notes that changed since [TopNotes::load]
+    pub async fn notify_changes(self, repo: &dyn Repository) {
+        if let Err(error) = self.try_notify_changes(repo).await {
+            warn!("Could not notify of new top notes: {error}");
+        }
+    }
+
+    async fn try_notify_changes(&self, repo: &dyn Repository) -> Result<()> {
+        for &(post_id, previous_note_id) in self.top_notes.iter() {
+            let Some((note_id, _, _)) =
+                probabilities::find_top_note(&self.tag, post_id, repo).await?
+            else {
+                continue;
+            };
+            if previous_note_id == Some(note_id) {
+                continue;
+            }
+            let Some(note) = repo.get_post(note_id).await? else {
+                continue;
+            };
+
+            repo.create_notification(
+                note.author_id,
+                NotificationKind::OwnTopNote,
+                &self.tag,
+                post_id,
+                note_id,
+            )
+            .await?;
+            for user_id in repo.get_voter_ids(post_id).await? {
+                let blocked = repo
+                    .get_blocked_user_ids(user_id)
+                    .await?
+                    .contains(&note.author_id);
+                if user_id != note.author_id && !blocked {
+                    repo.create_notification(
+                        user_id,
+                        NotificationKind::TopNote,
+                        &self.tag,
+                        post_id,
+                        note_id,
+                    )
+                    .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
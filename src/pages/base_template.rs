@@ -1,24 +1,37 @@
+use anyhow::Result;
 use async_trait::async_trait;
 use axum::extract::FromRequestParts;
+use axum::Extension;
+use common::pseudonym::author_name;
+use common::repository::{DynRepository, Repository};
 use common::structs::{PageMeta, User};
 use http::HeaderMap;
 use maud::{html, Markup, DOCTYPE};
 use tower_cookies::Cookies;
 
-use crate::{http_static::StaticAsset, util::base_url};
+use crate::{
+    csrf::{csrf_token, CSRF_HEADER},
+    error::AppError,
+    http_static::StaticAsset,
+    util::base_url,
+};
 
 fn render_base_template(
     title: Option<String>,
-    user: &Option<User>,
+    user_name: Option<&str>,
+    unread_notifications: i64,
     content: Markup,
     headers: &HeaderMap,
     page_meta: Option<PageMeta>,
+    csrf_token: &str,
 ) -> Markup {
+    // inherited by all htmx requests of the page, see crate::csrf
+    let hx_headers = format!(r#"{{"{CSRF_HEADER}": "{csrf_token}"}}"#);
     html! {
         (DOCTYPE)
         // hx-boost makes the navigation faster by making links and forms use AJAX:
         // https://htmx.org/attributes/hx-boost/
-        html lang="en" hx-boost="true" {
+        html lang="en" hx-boost="true" hx-headers=(hx_headers) {
             head {
                 // TODO: link preview
                 meta name="viewport" content="width=device-width, initial-scale=1.0";
@@ -69,15 +82,33 @@ fn render_base_template(
                 nav class="px-5 py-3" {
                     ul class="flex gap-6" {
                         li class="mr-auto text-3xl font-black" { a href="/" data-testid="nav-home" { "𝕐" } }
-                        // first 4 characters of user id
-                        @if let Some(user) = user {
+                        @if let Some(user_name) = user_name {
                             li {
-                                a href="/user" class="font-mono" {
+                                a href="/bookmarks" title="Saved posts" {
+                                    @let bookmark_icon = "🔖";
+                                    (bookmark_icon)
+                                }
+                            }
+                            li {
+                                a href="/notifications" title="Notifications" {
+                                    @let bell_icon = "🔔";
+                                    (bell_icon)
+                                    @if unread_notifications > 0 {
+                                        span class="ml-1 px-1.5 rounded-full bg-red-600 text-white text-sm font-bold" {
+                                            (unread_notifications)
+                                        }
+                                    }
+                                }
+                            }
+                            li {
+                                a href="/user" {
                                     @let user_icon = "👤";
                                     span class="mr-1" { (user_icon) }
-                                    (user.secret.chars().take(4).collect::<String>())
+                                    (user_name)
                                 }
                             }
+                        } @else {
+                            li { a href="/login" { "Log in" } }
                         }
                         li {
                             a href="/options" {
@@ -98,11 +129,16 @@ fn render_base_template(
 #[derive(Clone)]
 pub struct BaseTemplate {
     pub user: Option<User>,
+    /// Display name or pseudonym of `user`
+    pub user_name: Option<String>,
+    /// Shown in the navigation, 0 without `user`
+    pub unread_notifications: i64,
     pub cookies: Cookies,
     pub headers: HeaderMap,
     pub title: Option<String>,
     pub content: Markup,
     pub page_meta: Option<PageMeta>,
+    pub csrf_token: String,
 }
 
 impl BaseTemplate {
@@ -126,14 +162,46 @@ impl BaseTemplate {
         self.page_meta = m;
         self
     }
+    /// Without title and content, for pages that are not rendered by a handler, see
+    /// [crate::error::error_pages]
+    pub async fn load(
+        user: Option<User>,
+        cookies: Cookies,
+        headers: HeaderMap,
+        repo: &dyn Repository,
+    ) -> Result<BaseTemplate> {
+        let (user_name, unread_notifications) = match &user {
+            Some(user) => (
+                Some(author_name(user.id, repo).await?),
+                repo.count_unread_notifications(user.id).await?,
+            ),
+            None => (None, 0),
+        };
+
+        let csrf_token = csrf_token(&cookies);
+
+        Ok(BaseTemplate {
+            user,
+            user_name,
+            unread_notifications,
+            cookies,
+            headers,
+            title: None,
+            content: html! {},
+            page_meta: None,
+            csrf_token,
+        })
+    }
     /// Render BaseTemplate into markup
     pub fn render(self) -> Markup {
         render_base_template(
             self.title,
-            &self.user,
+            self.user_name.as_deref(),
+            self.unread_notifications,
             self.content,
             &self.headers,
             self.page_meta,
+            &self.csrf_token,
         )
     }
 }
@@ -149,7 +217,7 @@ impl<S> FromRequestParts<S> for BaseTemplate
 where
     S: Send + Sync,
 {
-    type Rejection = (http::StatusCode, &'static str);
+    type Rejection = AppError;
 
     async fn from_request_parts(
         parts: &mut http::request::Parts,
@@ -168,14 +236,11 @@ where
             .extract::<HeaderMap>()
             .await
             .expect("Unable to get headers");
+        let Extension(repo) = parts
+            .extract::<Extension<DynRepository>>()
+            .await
+            .expect("Unable to get repository");
 
-        Ok(BaseTemplate {
-            user,
-            cookies,
-            headers,
-            title: None,
-            content: html! {},
-            page_meta: None,
-        })
+        Ok(BaseTemplate::load(user, cookies, headers, repo.as_ref()).await?)
     }
 }
@@ -0,0 +1,103 @@
+//! Muting tags and blocking users, see [crate::content_filter]. The page reloads afterwards, to
+//! hide or show the posts.
+
+use crate::error::AppError;
+use crate::validation::{form_error, validate_other_user, validate_tag};
+use anyhow::Result;
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::auth;
+use common::repository::{normalize_tag, DynRepository};
+use maud::{html, Markup};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+fn toggle_button(action: &str, active: bool, label: &str, undo_label: &str) -> Markup {
+    html! {
+        form hx-post=(action) hx-swap="outerHTML" class="inline-block mb-4" {
+            input type="hidden" name="active" value=(!active) {}
+            button class="text-sm text-red-600 hover:text-red-800" {
+                @if active { (undo_label) } @else { (label) }
+            }
+            div class="form-errors" {}
+        }
+    }
+}
+
+pub fn mute_tag_button(tag: &str, muted: bool) -> Markup {
+    toggle_button(&format!("/mute/tag/{tag}"), muted, "Mute", "Unmute")
+}
+
+pub fn block_user_button(user_id: i64, blocked: bool) -> Markup {
+    toggle_button(
+        &format!("/block/user/{user_id}"),
+        blocked,
+        "Block",
+        "Unblock",
+    )
+}
+
+#[derive(Deserialize)]
+pub struct ToggleForm {
+    /// false to unmute or unblock
+    active: bool,
+}
+
+pub async fn mute_tag(
+    cookies: Cookies,
+    Path(tag): Path<String>,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<ToggleForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let tag = normalize_tag(&tag);
+    if let Err(error) = validate_tag(&tag) {
+        return form_error(error);
+    }
+
+    if form_data.active {
+        repo.mute_tag(user.id, &tag).await?;
+    } else {
+        repo.unmute_tag(user.id, &tag).await?;
+    }
+
+    Ok((
+        [("HX-Refresh", "true")],
+        mute_tag_button(&tag, form_data.active),
+    )
+        .into_response())
+}
+
+/// Blocking also unfollows
+pub async fn block_user(
+    cookies: Cookies,
+    Path(blocked_user_id): Path<i64>,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<ToggleForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    if form_data.active {
+        if let Err(error) = validate_other_user(user.id, blocked_user_id, repo.as_ref()).await {
+            return form_error(error);
+        }
+        repo.block_user(user.id, blocked_user_id).await?;
+        repo.unfollow_user(user.id, blocked_user_id).await?;
+    } else {
+        repo.unblock_user(user.id, blocked_user_id).await?;
+    }
+
+    Ok((
+        [("HX-Refresh", "true")],
+        block_user_button(blocked_user_id, form_data.active),
+    )
+        .into_response())
+}
@@ -1,26 +1,47 @@
 use crate::{
-    db,
+    content_filter::ContentFilter,
     error::AppError,
     pages::{
         base_template::BaseTemplate,
+        block::mute_tag_button,
         components::{create_post_form, post_feed},
+        follow::follow_tag_button,
     },
 };
 use anyhow::Result;
 use axum::{extract::Path, Extension};
+use common::repository::DynRepository;
+use common::structs::User;
 use maud::{html, Markup};
-use sqlx::SqlitePool;
 
 pub async fn community_frontpage(
+    maybe_user: Option<User>,
     Path(tag): Path<String>,
-    Extension(pool): Extension<SqlitePool>,
+    Extension(repo): Extension<DynRepository>,
     base: BaseTemplate,
 ) -> Result<Markup, AppError> {
-    let posts = db::get_posts_for_tag(tag.as_str(), &pool).await?;
+    let filter = ContentFilter::load(maybe_user.as_ref(), repo.as_ref()).await?;
+    let muted = filter.is_muted(tag.as_str());
+    let posts = filter.posts(repo.get_posts_for_tag(tag.as_str()).await?);
+    let suggested_tags = filter.tags(repo.get_top_tags(5).await?);
+    let following = match &maybe_user {
+        Some(user) => Some(repo.get_followed_tags(user.id).await?.contains(&tag)),
+        None => None,
+    };
     let content = html! {
-        (create_post_form())
+        (create_post_form(tag.as_str(), &suggested_tags))
         h1 class="text-xl font-bold mb-4" { (format!("#{tag}")) }
-        (post_feed(tag.as_str(), posts, &pool).await?)
+        @if let Some(following) = following {
+            div class="flex gap-4" {
+                (follow_tag_button(tag.as_str(), following))
+                (mute_tag_button(tag.as_str(), muted))
+            }
+        }
+        @if muted {
+            p class="text-gray-500" { (format!("You muted #{tag}.")) }
+        } @else {
+            (post_feed(tag.as_str(), posts, &filter, repo.as_ref()).await?)
+        }
     };
     Ok(base.title("Y").content(content).render())
 }
@@ -1,39 +1,70 @@
-use crate::{db, pages::vote::vote_buttons};
+use crate::{content_filter::ContentFilter, pages::vote::vote_buttons, probabilities};
 use anyhow::Result;
+use common::markdown::render_markdown;
+use common::pseudonym::author_name;
+use common::repository::Repository;
 use common::structs::{Direction::Neutral, Post};
 use maud::{html, Markup};
-use sqlx::SqlitePool;
 
+/// A top note by a user that `filter` blocks is replaced by a placeholder, and votes are cast as
+/// if no note was shown, because none was.
 pub async fn post_details(
     tag: &str,
     post: &Post,
     focused: bool,
-    pool: &SqlitePool,
+    filter: &ContentFilter,
+    repo: &dyn Repository,
 ) -> Result<Markup> {
-    let top_note = db::get_top_note(tag, post.id, pool).await?;
+    let mut top_note = probabilities::get_top_note(tag, post.id, repo).await?;
+    let note_blocked = top_note
+        .as_ref()
+        .is_some_and(|note| filter.is_blocked(note));
+    if note_blocked {
+        top_note = None;
+    }
     let top_note_id = top_note.clone().map(|post| post.id);
+    let referenced_posts = repo.get_referenced_posts(post.id).await?;
+    let author = author_name(post.author_id, repo).await?;
+    let note_author = match &top_note {
+        Some(note) => Some(author_name(note.author_id, repo).await?),
+        None => None,
+    };
 
     Ok(html! {
         div data-postid=(post.id) class="post mb-5 p-5 rounded-lg shadow bg-white dark:bg-slate-700" {
             div  {
                 @if !focused {
                     a href=(format!("/y/{}/post/{}", tag, post.id)) {
-                        (post.content)
+                        (post_content(tag, &post.content, &referenced_posts, true))
                     }
                 } @else {
-                    (post.content)
+                    (post_content(tag, &post.content, &referenced_posts, false))
                 }
+                (byline(post.author_id, &author))
             }
             div {
                 @match top_note.clone() {
                     Some(note) => {
-                        a href=(format!("/y/{}/post/{}", tag, note.id)) {
-                            div data-postid=(note.id) class="post mt-4 mb-5 p-5 rounded-lg shadow bg-gray-100 dark:bg-slate-600" {
-                                p { (note.content) }
+                        // not wrapped in a link, so that links to sources in the note work
+                        div data-postid=(note.id) class="post mt-4 mb-5 p-5 rounded-lg shadow bg-gray-100 dark:bg-slate-600" {
+                            div class="markdown" { (render_markdown(&note.content, true, &|_| None)) }
+                            @if let Some(note_author) = &note_author {
+                                (byline(note.author_id, note_author))
+                            }
+                            a href=(format!("/y/{}/post/{}", tag, note.id)) class="text-sm text-blue-500 hover:text-blue-700" {
+                                "View note"
+                            }
+                        }
+                    },
+                    None => {
+                        @if note_blocked {
+                            div class="mt-4 mb-5 p-5 rounded-lg bg-gray-100 dark:bg-slate-600 text-gray-500 italic" {
+                                "The top note is by a user you blocked."
                             }
+                        } @else {
+                            div {}
                         }
                     },
-                    None => div {},
                 }
             }
             div class="w-full flex flex-col mt-4" {
@@ -42,16 +73,78 @@ pub async fn post_details(
                 @if focused {
                     (tag_form(post.id, top_note_id))
                     (reply_form(tag, post.id))
+                    (quote_button(post.id))
                 }
            }
         }
     })
 }
 
-pub fn create_post_form() -> Markup {
+fn byline(author_id: i64, author: &str) -> Markup {
+    html! {
+        small class="block mt-2 text-gray-500 dark:text-gray-400" {
+            "by "
+            a href=(format!("/user/{author_id}")) class="hover:underline" { (author) }
+        }
+    }
+}
+
+/// Renders the Markdown content, with `#post:123` references to existing posts as cards. Inside
+/// a link (`in_link`), neither links nor cards are links themselves, because links can't be
+/// nested.
+fn post_content(tag: &str, content: &str, referenced_posts: &[Post], in_link: bool) -> Markup {
+    let post_reference = |post_id: i64| {
+        let referenced = referenced_posts
+            .iter()
+            .find(|referenced| referenced.id == post_id)?;
+        Some(html! {
+            @if in_link {
+                (reference_card(referenced))
+            } @else {
+                a href=(format!("/y/{}/post/{}", tag, referenced.id)) {
+                    (reference_card(referenced))
+                }
+            }
+        })
+    };
+    html! {
+        div class="markdown" {
+            (render_markdown(content, !in_link, &post_reference))
+        }
+    }
+}
+
+fn reference_card(referenced: &Post) -> Markup {
+    // a span, because the card can be part of a paragraph
+    html! {
+        span data-postid=(referenced.id) class="post block truncate my-2 p-3 rounded-lg shadow bg-gray-100 dark:bg-slate-600" {
+            (referenced.content)
+        }
+    }
+}
+
+/// Copies the `#post:123` reference to the clipboard, to be pasted into another post
+pub fn quote_button(post_id: i64) -> Markup {
+    html! {
+        div class="mt-2" x-data="{}" {
+            button
+                type="button"
+                data-reference=(format!("#post:{}", post_id))
+                x-on:click="navigator.clipboard.writeText($el.dataset.reference)"
+                class="text-sm text-blue-500 hover:text-blue-700"
+                title="Copy a reference to this post"
+            {
+                "Quote"
+            }
+        }
+    }
+}
+
+/// Form for new top-level posts, preselecting `tag`. Clicking one of `suggested_tags` adds it.
+pub fn create_post_form(tag: &str, suggested_tags: &[String]) -> Markup {
     html! {
         div class="bg-white rounded-lg shadow-lg w-120 h-30 p-5 mb-10 flex dark:bg-slate-700" {
-            form hx-post="/create_post" {
+            form hx-post="/create_post" x-data="{}" {
                 div class="w-full flex" {
                     div class="mr-1" {
                         textarea
@@ -70,6 +163,29 @@ pub fn create_post_form() -> Markup {
                         "Post"
                     }
                 }
+                div class="w-full flex items-center mt-2 text-sm" {
+                    input
+                        type="text"
+                        name="tags"
+                        value=(tag)
+                        x-ref="tags"
+                        class=r#"
+                            p-1 mr-2 text-gray-900 bg-gray-50 rounded-lg border border-gray-300
+                            dark:bg-gray-700 dark:border-gray-600 dark:text-white
+                        "#
+                        placeholder="Tags, separated by spaces" {}
+                    @for suggested_tag in suggested_tags.iter().filter(|t| t.as_str() != tag) {
+                        button
+                            type="button"
+                            data-tag=(suggested_tag)
+                            x-on:click="$refs.tags.value = ($refs.tags.value + ' ' + $el.dataset.tag).trim()"
+                            class="mr-2 text-blue-500 hover:text-blue-700"
+                        {
+                            (format!("+#{suggested_tag}"))
+                        }
+                    }
+                }
+                div class="form-errors" {}
             }
         }
     }
@@ -95,7 +211,7 @@ pub fn reply_form(tag: &str, parent_id: i64) -> Markup {
                     value=(format!("{}", parent_id)) {}
                 input
                     type="hidden"
-                    name="tag"
+                    name="tags"
                     value=(tag)
                 div class="mr-1" {
                     textarea
@@ -115,6 +231,7 @@ pub fn reply_form(tag: &str, parent_id: i64) -> Markup {
                     }
                 }
             }
+            div class="form-errors" {}
         }
     }
 }
@@ -149,16 +266,43 @@ pub fn tag_form(post_id: i64, note_id: Option<i64>) -> Markup {
                         }
                     }
                 }
+                div class="form-errors" {}
             }
         }
     }
 }
 
-pub async fn post_feed(tag: &str, posts: Vec<Post>, pool: &SqlitePool) -> Result<Markup> {
+/// Like [post_feed], for posts from several tags, as `(tag, post)`
+pub async fn tagged_post_feed(
+    posts: &[(String, Post)],
+    filter: &ContentFilter,
+    repo: &dyn Repository,
+) -> Result<Markup> {
+    Ok(html! {
+        div {
+            @for (tag, post) in posts.iter() {
+                div {
+                    a href=(format!("/y/{tag}")) class="text-sm text-gray-500 hover:underline" {
+                        (format!("#{tag}"))
+                    }
+                    (post_details(tag, post, false, filter, repo).await?)
+                }
+            }
+        }
+    })
+}
+
+/// Expects posts that `filter` does not block, it is only used for their notes
+pub async fn post_feed(
+    tag: &str,
+    posts: Vec<Post>,
+    filter: &ContentFilter,
+    repo: &dyn Repository,
+) -> Result<Markup> {
     Ok(html! {
         div {
             @for post in posts.iter() {
-                div { (post_details(tag, post, false, pool).await?) }
+                div { (post_details(tag, post, false, filter, repo).await?) }
             }
         }
     })
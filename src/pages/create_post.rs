@@ -1,10 +1,17 @@
-use crate::{db, error::AppError};
-use anyhow::anyhow;
-use axum::{extract::Query, response::IntoResponse, Extension, Form};
-use common::auth;
+use crate::error::AppError;
+use crate::notifications::notify_reply;
+use crate::validation::{form_error, validate_new_post};
+use axum::{
+    extract::Query,
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::{
+    auth,
+    repository::{parse_tags, DynRepository},
+};
 use http::StatusCode;
 use serde::Deserialize;
-use sqlx::SqlitePool;
 use tower_cookies::Cookies;
 
 fn default_none<T>() -> Option<T> {
@@ -14,7 +21,8 @@ fn default_none<T>() -> Option<T> {
 #[derive(Deserialize)]
 pub struct CreatePostForm {
     post_content: String,
-    tag: String,
+    /// Separated by commas or whitespace
+    tags: String,
     #[serde(default = "default_none")]
     post_parent_id: Option<i64>,
 }
@@ -28,25 +36,36 @@ pub struct Redirect {
 pub async fn create_post(
     redirect: Query<Redirect>,
     cookies: Cookies,
-    Extension(pool): Extension<SqlitePool>,
+    Extension(repo): Extension<DynRepository>,
     Form(form_data): Form<CreatePostForm>,
-) -> Result<impl IntoResponse, AppError> {
-    let user = auth::get_or_create_user(&cookies, &pool).await?;
-    if form_data.post_content.is_empty() {
-        return Err(AppError(anyhow!("Post content cannot be empty")));
-    }
-    let tag = form_data.tag;
-
-    let _post_id = db::create_post(
-        tag.as_str(),
+) -> Result<Response, AppError> {
+    let tags = parse_tags(&form_data.tags);
+    if let Err(error) = validate_new_post(
+        &form_data.post_content,
+        &tags,
         form_data.post_parent_id,
-        form_data.post_content.as_str(),
-        user.id,
-        &pool,
+        repo.as_ref(),
     )
-    .await?;
+    .await
+    {
+        return form_error(error);
+    }
+
+    let user = auth::get_or_create_user(&cookies, repo.as_ref()).await?;
+
+    let post_id = repo
+        .create_post(
+            &tags,
+            form_data.post_parent_id,
+            form_data.post_content.as_str(),
+            user.id,
+        )
+        .await?;
+    if let Some(parent_id) = form_data.post_parent_id {
+        notify_reply(&tags[0], post_id, parent_id, user.id, repo.as_ref()).await;
+    }
 
     let redirect_url = redirect.0.redirect.unwrap_or_else(|| "/".to_string());
 
-    Ok((StatusCode::OK, [("HX-Location", redirect_url)]))
+    Ok((StatusCode::OK, [("HX-Location", redirect_url)]).into_response())
 }
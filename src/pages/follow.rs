@@ -0,0 +1,96 @@
+//! Following tags and users. Their posts make up the home feed, see `pages::frontpage`.
+
+use crate::error::AppError;
+use crate::validation::{form_error, validate_other_user, validate_tag};
+use anyhow::Result;
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::auth;
+use common::repository::{normalize_tag, DynRepository};
+use maud::{html, Markup};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+fn follow_button(action: &str, following: bool, label: &str) -> Markup {
+    let class = if following {
+        "bg-gray-200 hover:bg-gray-300 text-gray-800 font-bold py-1 px-3 rounded"
+    } else {
+        "bg-blue-500 hover:bg-blue-700 text-white font-bold py-1 px-3 rounded"
+    };
+    html! {
+        form hx-post=(action) hx-swap="outerHTML" class="inline-block mb-4" {
+            input type="hidden" name="follow" value=(!following) {}
+            button class=(class) {
+                @if following { "Unfollow" } @else { (label) }
+            }
+            div class="form-errors" {}
+        }
+    }
+}
+
+pub fn follow_tag_button(tag: &str, following: bool) -> Markup {
+    follow_button(
+        &format!("/follow/tag/{tag}"),
+        following,
+        &format!("Follow #{tag}"),
+    )
+}
+
+pub fn follow_user_button(user_id: i64, following: bool) -> Markup {
+    follow_button(&format!("/follow/user/{user_id}"), following, "Follow")
+}
+
+#[derive(Deserialize)]
+pub struct FollowForm {
+    /// false to unfollow
+    follow: bool,
+}
+
+pub async fn follow_tag(
+    cookies: Cookies,
+    Path(tag): Path<String>,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<FollowForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let tag = normalize_tag(&tag);
+    if let Err(error) = validate_tag(&tag) {
+        return form_error(error);
+    }
+
+    if form_data.follow {
+        repo.follow_tag(user.id, &tag).await?;
+    } else {
+        repo.unfollow_tag(user.id, &tag).await?;
+    }
+
+    Ok(follow_tag_button(&tag, form_data.follow).into_response())
+}
+
+pub async fn follow_user(
+    cookies: Cookies,
+    Path(followed_user_id): Path<i64>,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<FollowForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    if form_data.follow {
+        if let Err(error) = validate_other_user(user.id, followed_user_id, repo.as_ref()).await {
+            return form_error(error);
+        }
+        repo.follow_user(user.id, followed_user_id).await?;
+    } else {
+        repo.unfollow_user(user.id, followed_user_id).await?;
+    }
+
+    Ok(follow_user_button(followed_user_id, form_data.follow).into_response())
+}
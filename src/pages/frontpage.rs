@@ -1,48 +1,109 @@
 use crate::{
-    db,
+    content_filter::ContentFilter,
     error::AppError,
     pages::{
         base_template::BaseTemplate,
-        components::{create_post_form, post_feed},
+        components::{create_post_form, post_feed, tagged_post_feed},
         positions::load_positions_js_for_tag,
     },
 };
-use common::structs::User;
+use common::repository::{DynRepository, Repository};
+use common::structs::{Post, User};
 
 use anyhow::Result;
 use axum::Extension;
 use maud::{html, Markup};
 
-use sqlx::SqlitePool;
-
 use crate::constants::GLOBAL_TAG;
 
+/// The front page posts of a user, see [load_home_feed]
+pub struct HomeFeed {
+    /// false if the user follows nothing and gets all posts in [GLOBAL_TAG]
+    pub personalized: bool,
+    /// `(tag, post)`, best first
+    pub posts: Vec<(String, Post)>,
+}
+
+/// Posts in the tags and by the users that `user` follows. Users who follow nothing, and
+/// visitors without account, get the posts in [GLOBAL_TAG]. Leaves out what `filter` hides.
+pub async fn load_home_feed(
+    user: Option<&User>,
+    filter: &ContentFilter,
+    repo: &dyn Repository,
+) -> Result<HomeFeed> {
+    if let Some(user) = user {
+        let follows_anything = !repo.get_followed_tags(user.id).await?.is_empty()
+            || !repo.get_followed_user_ids(user.id).await?.is_empty();
+        if follows_anything {
+            return Ok(HomeFeed {
+                personalized: true,
+                posts: filter
+                    .tagged_posts(repo.get_feed(user.id).await?, repo)
+                    .await?,
+            });
+        }
+    }
+
+    let posts = repo.get_posts_for_tag(GLOBAL_TAG).await?;
+    Ok(HomeFeed {
+        personalized: false,
+        posts: filter
+            .tagged_posts(
+                posts
+                    .into_iter()
+                    .map(|post| (GLOBAL_TAG.to_string(), post))
+                    .collect(),
+                repo,
+            )
+            .await?,
+    })
+}
+
 pub async fn frontpage(
-    _maybe_user: Option<User>,
-    Extension(pool): Extension<SqlitePool>,
+    maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
     base: BaseTemplate,
 ) -> Result<Markup, AppError> {
-    let posts = db::get_posts_for_tag(GLOBAL_TAG, &pool).await?;
+    let filter = ContentFilter::load(maybe_user.as_ref(), repo.as_ref()).await?;
+    let feed = load_home_feed(maybe_user.as_ref(), &filter, repo.as_ref()).await?;
+    let suggested_tags = filter.tags(repo.get_top_tags(5).await?);
     let content = html! {
         div class="mb-10" {
             div class="fixed top-0 left-0 m-5" {
-                (most_used_tags(&pool).await?)
+                (most_used_tags(&suggested_tags))
             }
             div {
-                (create_post_form())
-                (post_feed(GLOBAL_TAG, posts, &pool).await?)
-                (load_positions_js_for_tag(GLOBAL_TAG))
+                (create_post_form(GLOBAL_TAG, &suggested_tags))
+                @if feed.personalized {
+                    p class="mb-4 text-sm text-gray-500" {
+                        "Posts from the tags and people you follow. "
+                        a href=(format!("/y/{GLOBAL_TAG}")) class="text-blue-500 hover:text-blue-700" { "All posts" }
+                    }
+                    (tagged_post_feed(&feed.posts, &filter, repo.as_ref()).await?)
+                    @for tag in feed_tags(&feed.posts) {
+                        (load_positions_js_for_tag(tag))
+                    }
+                } @else {
+                    (post_feed(GLOBAL_TAG, feed.posts.into_iter().map(|(_, post)| post).collect(), &filter, repo.as_ref()).await?)
+                    (load_positions_js_for_tag(GLOBAL_TAG))
+                }
             }
         }
     };
 
-    // TODO: redirect from "/y/global"
     Ok(base.title("Y").content(content).render())
 }
 
-async fn most_used_tags(pool: &SqlitePool) -> Result<Markup, AppError> {
-    let tags = db::get_top_5_tags(pool).await?;
-    Ok(html! {
+/// Distinct tags of the feed, to load the user's positions in each
+fn feed_tags(posts: &[(String, Post)]) -> Vec<&str> {
+    let mut tags: Vec<&str> = posts.iter().map(|(tag, _)| tag.as_str()).collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+fn most_used_tags(tags: &[String]) -> Markup {
+    html! {
         ul class="list-none" {
             @for tag in tags.iter() {
                 li class="font-bold pb-4" {
@@ -50,5 +111,97 @@ async fn most_used_tags(pool: &SqlitePool) -> Result<Markup, AppError> {
                 }
             }
         }
-    })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::repository::memory::MemoryRepository;
+
+    use super::*;
+
+    fn post_ids(feed: &HomeFeed) -> Vec<i64> {
+        feed.posts.iter().map(|(_, post)| post.id).collect()
+    }
+
+    #[tokio::test]
+    async fn home_feed_follows_tags_and_hides_blocked_users() {
+        let repo = MemoryRepository::new();
+        let author = repo.create_user("author").await.unwrap();
+        let blocked_author = repo.create_user("blocked author").await.unwrap();
+        let reader = repo.create_user("reader").await.unwrap();
+        let global_post = repo
+            .create_post(&[GLOBAL_TAG.to_string()], None, "global", author)
+            .await
+            .unwrap();
+        let tagged_post = repo
+            .create_post(&["rust".to_string()], None, "rust", author)
+            .await
+            .unwrap();
+        let blocked_post = repo
+            .create_post(&["rust".to_string()], None, "blocked", blocked_author)
+            .await
+            .unwrap();
+
+        let filter = ContentFilter::default();
+        let visitor_feed = load_home_feed(None, &filter, &repo).await.unwrap();
+        assert!(!visitor_feed.personalized);
+        assert_eq!(post_ids(&visitor_feed), vec![global_post]);
+
+        let reader = User { id: reader };
+        repo.follow_tag(reader.id, "rust").await.unwrap();
+        repo.block_user(reader.id, blocked_author).await.unwrap();
+        let filter = ContentFilter::load(Some(&reader), &repo).await.unwrap();
+        let reader_feed = load_home_feed(Some(&reader), &filter, &repo).await.unwrap();
+        assert!(reader_feed.personalized);
+        assert_eq!(post_ids(&reader_feed), vec![tagged_post]);
+        assert!(!post_ids(&reader_feed).contains(&blocked_post));
+    }
+
+    #[tokio::test]
+    async fn home_feed_hides_posts_in_any_muted_tag() {
+        let repo = MemoryRepository::new();
+        let author = repo.create_user("author").await.unwrap();
+        let reader = repo.create_user("reader").await.unwrap();
+        let kept_post = repo
+            .create_post(&["rust".to_string()], None, "rust", author)
+            .await
+            .unwrap();
+        let muted_post = repo
+            .create_post(
+                &["rust".to_string(), "politics".to_string()],
+                None,
+                "rust and politics",
+                author,
+            )
+            .await
+            .unwrap();
+        let global_post = repo
+            .create_post(&[GLOBAL_TAG.to_string()], None, "global", author)
+            .await
+            .unwrap();
+        let muted_global_post = repo
+            .create_post(
+                &[GLOBAL_TAG.to_string(), "politics".to_string()],
+                None,
+                "global and politics",
+                author,
+            )
+            .await
+            .unwrap();
+
+        let reader = User { id: reader };
+        repo.mute_tag(reader.id, "politics").await.unwrap();
+        let filter = ContentFilter::load(Some(&reader), &repo).await.unwrap();
+        let global_feed = load_home_feed(Some(&reader), &filter, &repo).await.unwrap();
+        assert!(!global_feed.personalized);
+        assert_eq!(post_ids(&global_feed), vec![global_post]);
+        assert!(!post_ids(&global_feed).contains(&muted_global_post));
+
+        repo.follow_tag(reader.id, "rust").await.unwrap();
+        let reader_feed = load_home_feed(Some(&reader), &filter, &repo).await.unwrap();
+        assert!(reader_feed.personalized);
+        assert_eq!(post_ids(&reader_feed), vec![kept_post]);
+        assert!(!post_ids(&reader_feed).contains(&muted_post));
+    }
 }
@@ -1,9 +1,11 @@
 //! One file per page
 
 pub mod base_template;
+pub mod block;
 pub mod communities;
 pub mod components;
 pub mod create_post;
+pub mod follow;
 pub mod frontpage;
 pub mod positions;
 pub mod tags;
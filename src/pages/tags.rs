@@ -1,9 +1,6 @@
-use crate::db;
 use crate::error::AppError;
 use anyhow::Result;
 use axum::Extension;
 use axum::{response::IntoResponse, Form};
 use http::StatusCode;
 use serde::Deserialize;
-use sqlx::SqlitePool;
-
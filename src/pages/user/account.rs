@@ -0,0 +1,126 @@
+//! Export of everything stored about a user, and deleting the account
+
+use crate::error::AppError;
+use anyhow::Result;
+use axum::{
+    response::{IntoResponse, Response},
+    Extension, Form, Json,
+};
+use common::auth;
+use common::repository::DynRepository;
+use common::structs::{ApiToken, Bookmark, HistoricVote, Notification, Post, Session, User};
+use http::{header, StatusCode};
+use maud::{html, Markup};
+use serde::{Deserialize, Serialize};
+use tower_cookies::Cookies;
+use tracing::info;
+
+#[derive(Serialize)]
+struct AccountExport {
+    user_id: i64,
+    username: Option<String>,
+    display_name: Option<String>,
+    posts: Vec<Post>,
+    /// Oldest first, including votes that were changed later
+    votes: Vec<HistoricVote>,
+    /// Tags the user voted in
+    tags: Vec<String>,
+    sessions: Vec<Session>,
+    api_tokens: Vec<ApiToken>,
+    followed_tags: Vec<String>,
+    followed_user_ids: Vec<i64>,
+    notifications: Vec<Notification>,
+    bookmarks: Vec<Bookmark>,
+    muted_tags: Vec<String>,
+    blocked_user_ids: Vec<i64>,
+}
+
+/// Options page section with the export link and the deletion form
+pub fn account_section() -> Markup {
+    html! {
+        p {
+            // not boosted, htmx would swap the JSON into the page instead of downloading it
+            a href="/options/export" hx-boost="false" download class="text-blue-500 hover:text-blue-700" { "Download your data" }
+            " as JSON: your posts, votes, tags, follows, notifications, saved posts,"
+            " signed in devices and API tokens."
+        }
+        form
+            hx-post="/options/delete_account"
+            hx-confirm="Delete your account? This cannot be undone."
+            class="mt-2"
+        {
+            p { "Delete your account. Your votes stay anonymously, so that tallies don't change." }
+            label class="block" {
+                input type="radio" name="erase_posts" value="false" checked {}
+                " Keep my posts without my name"
+            }
+            label class="block mb-2" {
+                input type="radio" name="erase_posts" value="true" {}
+                " Replace the content of my posts with \"[deleted]\""
+            }
+            button class="bg-red-600 hover:bg-red-800 text-white font-bold py-2 px-4 rounded" {
+                "Delete account"
+            }
+            div class="form-errors" {}
+        }
+    }
+}
+
+pub async fn export(
+    maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
+) -> Result<Response, AppError> {
+    let Some(user) = maybe_user else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let votes = repo.get_vote_history(user.id).await?;
+    let mut tags: Vec<String> = votes.iter().map(|vote| vote.tag.clone()).collect();
+    tags.sort();
+    tags.dedup();
+
+    let export = AccountExport {
+        user_id: user.id,
+        username: repo.get_username(user.id).await?,
+        display_name: repo.get_display_name(user.id).await?,
+        posts: repo.get_posts_by_author(user.id).await?,
+        votes,
+        tags,
+        sessions: repo.get_sessions(user.id).await?,
+        api_tokens: repo.get_api_tokens(user.id).await?,
+        followed_tags: repo.get_followed_tags(user.id).await?,
+        followed_user_ids: repo.get_followed_user_ids(user.id).await?,
+        notifications: repo.get_notifications(user.id, i64::MAX).await?,
+        bookmarks: repo.get_bookmarks(user.id).await?,
+        muted_tags: repo.get_muted_tags(user.id).await?,
+        blocked_user_ids: repo.get_blocked_user_ids(user.id).await?,
+    };
+
+    let disposition = format!("attachment; filename=\"y-account-{}.json\"", user.id);
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(export)).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccountForm {
+    #[serde(default)]
+    erase_posts: bool,
+}
+
+pub async fn delete_account(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<DeleteAccountForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let report =
+        auth::delete_account(&user, form_data.erase_posts, &cookies, repo.as_ref()).await?;
+    info!(
+        "Deleted user {}: {} posts and {} votes moved to tombstone user {}",
+        user.id, report.posts, report.votes, report.tombstone_user_id
+    );
+
+    Ok((StatusCode::OK, [("HX-Location", "/")]).into_response())
+}
@@ -0,0 +1,108 @@
+//! Private bookmarks of posts, listed on the saved posts page
+
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use crate::pages::user::options::warning_dialog;
+use crate::validation::{form_error, validate_post_exists, validate_tag};
+use anyhow::Result;
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::auth;
+use common::repository::{normalize_tag, DynRepository};
+use common::structs::User;
+use maud::{html, Markup};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+/// Saves the post with the tag of the page it is shown on
+pub fn bookmark_button(tag: &str, post_id: i64, bookmarked: bool) -> Markup {
+    html! {
+        form hx-post=(format!("/bookmark/{post_id}")) hx-swap="outerHTML" class="mb-4" {
+            input type="hidden" name="tag" value=(tag) {}
+            input type="hidden" name="bookmark" value=(!bookmarked) {}
+            button class="text-sm text-blue-500 hover:text-blue-700" {
+                @if bookmarked { "★ Saved" } @else { "☆ Save" }
+            }
+            div class="form-errors" {}
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BookmarkForm {
+    tag: String,
+    /// false to remove the bookmark
+    bookmark: bool,
+}
+
+pub async fn bookmark(
+    cookies: Cookies,
+    Path(post_id): Path<i64>,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<BookmarkForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let tag = normalize_tag(&form_data.tag);
+    if form_data.bookmark {
+        if let Err(error) = validate_tag(&tag) {
+            return form_error(error);
+        }
+        if let Err(error) = validate_post_exists("post_id", post_id, repo.as_ref()).await {
+            return form_error(error);
+        }
+        repo.bookmark_post(user.id, &tag, post_id).await?;
+    } else {
+        repo.remove_bookmark(user.id, post_id).await?;
+    }
+
+    Ok(bookmark_button(&tag, post_id, form_data.bookmark).into_response())
+}
+
+pub async fn bookmarks(
+    maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let title = "Saved posts";
+    let Some(user) = maybe_user else {
+        return Ok(base
+            .title(title)
+            .content(warning_dialog(
+                "Posts you save appear here after your first vote or post.",
+                None,
+            ))
+            .render());
+    };
+
+    let mut saved = vec![];
+    for bookmark in repo.get_bookmarks(user.id).await? {
+        if let Some(post) = repo.get_post(bookmark.post_id).await? {
+            saved.push((bookmark, post));
+        }
+    }
+
+    let content = html! {
+        h2 class="text-xl font-bold mb-4" { (title) }
+        @if saved.is_empty() {
+            p { "Save posts with the ☆ button on their page. Only you can see what you saved." }
+        }
+        @for (bookmark, post) in saved.iter() {
+            div class="mb-3 p-3 rounded-lg shadow bg-white dark:bg-slate-700" {
+                a href=(format!("/y/{}/post/{}", bookmark.tag, post.id)) {
+                    p class="truncate" { (post.content) }
+                }
+                small class="block text-gray-500" {
+                    (format!("#{}, saved {} UTC", bookmark.tag, bookmark.created))
+                }
+                (bookmark_button(&bookmark.tag, post.id, true))
+            }
+        }
+    };
+    Ok(base.title(title).content(content).render())
+}
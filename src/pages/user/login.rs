@@ -0,0 +1,106 @@
+//! Signing in with a username and password, and signing out
+
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use crate::validation::{form_error, ValidationError};
+use anyhow::Result;
+use axum::{
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::{auth, repository::DynRepository, structs::User};
+use http::StatusCode;
+use maud::{html, Markup};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+
+const INPUT_CLASS: &str = r#"
+    block p-2.5 mb-2 text-gray-900 bg-gray-50 rounded-lg border border-gray-300
+    dark:bg-gray-700 dark:border-gray-600 dark:text-white
+"#;
+const BUTTON_CLASS: &str = "bg-blue-500 hover:bg-blue-700 text-white font-bold py-2 px-4 rounded";
+
+pub async fn login_page(base: BaseTemplate) -> Result<Markup, AppError> {
+    let content = html! {
+        form hx-post="/login" {
+            input type="text" name="username" class=(INPUT_CLASS) placeholder="Username" autocomplete="username" {}
+            input type="password" name="password" class=(INPUT_CLASS) placeholder="Password" autocomplete="current-password" {}
+            button class=(BUTTON_CLASS) { "Log in" }
+            div class="form-errors" {}
+        }
+        p class="mt-4 text-sm" {
+            "No username yet? Add one to your account on the "
+            a href="/options" class="text-blue-500 hover:text-blue-700" { "options page" }
+            "."
+        }
+    };
+    Ok(base.title("Log in").content(content).render())
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+/// Replaces the session of this device with one of the user
+pub async fn login(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<LoginForm>,
+) -> Result<Response, AppError> {
+    let Some(user) =
+        auth::user_from_credentials(&form_data.username, &form_data.password, repo.as_ref())
+            .await?
+    else {
+        return form_error(
+            ValidationError::Invalid {
+                field: "password",
+                message: "Wrong username or password".to_string(),
+            }
+            .into(),
+        );
+    };
+
+    auth::sign_out(&cookies, repo.as_ref()).await?;
+    auth::sign_in(&user, &cookies, repo.as_ref()).await?;
+
+    Ok((StatusCode::OK, [("HX-Location", "/")]).into_response())
+}
+
+pub async fn logout_page(
+    maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let content = match maybe_user {
+        Some(user) => {
+            let username = repo.get_username(user.id).await?;
+            html! {
+                @match username {
+                    Some(username) => p { (format!("Signed in as {username}.")) },
+                    None => p {
+                        "This account has no username. After logging out, only a device link from another device signs in to it again. You can add a username on the "
+                        a href="/options" class="text-blue-500 hover:text-blue-700" { "options page" }
+                        "."
+                    },
+                }
+                form hx-post="/logout" class="mt-2" {
+                    button class=(BUTTON_CLASS) { "Log out" }
+                }
+            }
+        }
+        None => html! {
+            p { "You are not logged in." }
+        },
+    };
+    Ok(base.title("Log out").content(content).render())
+}
+
+pub async fn logout(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+) -> Result<Response, AppError> {
+    auth::sign_out(&cookies, repo.as_ref()).await?;
+    Ok((StatusCode::OK, [("HX-Location", "/")]).into_response())
+}
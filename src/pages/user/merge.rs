@@ -0,0 +1,123 @@
+//! Switching a device to another account via a device link (the QR code on the options page) or
+//! the account's secret
+
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use anyhow::Result;
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::{auth, repository::DynRepository, structs::User};
+use http::StatusCode;
+use maud::{html, Markup};
+use serde::Deserialize;
+use tower_cookies::Cookies;
+use tracing::info;
+
+const LINK_NOT_FOUND: &str =
+    "This link has expired, was already used or does not belong to any account.";
+
+fn confirmation(secret: &str, current_user_votes: i64) -> Markup {
+    let button_class =
+        "bg-blue-500 hover:bg-blue-700 text-white font-bold py-2 px-4 rounded mt-2 mr-2";
+    html! {
+        fieldset {
+            p { "Switch this device to the account of the link you opened?" }
+            @if current_user_votes > 0 {
+                p {
+                    (format!(
+                        "This device already has an account with {current_user_votes} vote{}. You can move its votes and posts to the other account. Where both accounts voted on the same post, the latest vote counts.",
+                        if current_user_votes == 1 { "" } else { "s" }
+                    ))
+                }
+            }
+            form hx-post=(format!("/merge/{secret}")) {
+                @if current_user_votes > 0 {
+                    button name="merge_votes" value="true" class=(button_class) {
+                        "Switch and move votes"
+                    }
+                    button name="merge_votes" value="false" class=(button_class) {
+                        "Switch without votes"
+                    }
+                } @else {
+                    button class=(button_class) { "Switch account" }
+                }
+            }
+        }
+    }
+}
+
+/// The user of the device, if it has one. Unlike extracting `Option<User>`, this passes on
+/// errors of the session lookup instead of taking the device for signed out.
+fn current_user(user: Result<User, AppError>) -> Result<Option<User>, AppError> {
+    match user {
+        Ok(user) => Ok(Some(user)),
+        Err(AppError::Unauthorized(_)) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Asks for confirmation before switching, the switch itself is a POST to the same URL
+pub async fn merge_confirmation(
+    Path(secret): Path<String>,
+    user: Result<User, AppError>,
+    Extension(repo): Extension<DynRepository>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let title = "Switch account";
+    let maybe_user = current_user(user)?;
+
+    let Some(target_user) = auth::user_from_link(&secret, repo.as_ref()).await? else {
+        return Err(AppError::NotFound(LINK_NOT_FOUND.to_string()));
+    };
+
+    let content = match maybe_user {
+        Some(user) if user.id == target_user.id => html! {
+            p { "This device already uses this account." }
+            a href="/" { "Back to 𝕐" }
+        },
+        Some(user) => confirmation(&secret, repo.count_votes(user.id).await?),
+        None => confirmation(&secret, 0),
+    };
+
+    Ok(base.title(title).content(content).render())
+}
+
+#[derive(Deserialize)]
+pub struct MergeForm {
+    #[serde(default)]
+    merge_votes: bool,
+}
+
+/// Signs the device in to the account of `secret`, optionally moving the votes and posts of the
+/// device's current account there
+pub async fn merge(
+    Path(secret): Path<String>,
+    user: Result<User, AppError>,
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<MergeForm>,
+) -> Result<Response, AppError> {
+    let maybe_user = current_user(user)?;
+    let Some(target_user) = auth::user_from_link(&secret, repo.as_ref()).await? else {
+        return Err(AppError::NotFound(LINK_NOT_FOUND.to_string()));
+    };
+
+    if let Some(user) = maybe_user.filter(|user| user.id != target_user.id) {
+        if form_data.merge_votes {
+            let report = repo.merge_users(user.id, target_user.id).await?;
+            info!(
+                "Merged user {} into {}: {} posts, {} votes, {} superseded votes archived",
+                user.id, target_user.id, report.posts, report.votes, report.archived_votes
+            );
+        }
+    }
+
+    // only now, so that the link can be used again if merging failed
+    auth::consume_link(&secret, repo.as_ref()).await?;
+    auth::sign_in(&target_user, &cookies, repo.as_ref()).await?;
+
+    Ok((StatusCode::OK, [("HX-Location", "/")]).into_response())
+}
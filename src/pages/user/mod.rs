@@ -1 +1,7 @@
+pub mod account;
+pub mod bookmarks;
+pub mod login;
+pub mod merge;
+pub mod notifications;
 pub mod options;
+pub mod profile;
@@ -0,0 +1,94 @@
+//! Notifications of the signed in user, see [crate::notifications]. Viewing the page marks them
+//! as read.
+
+use crate::error::AppError;
+use crate::notifications::NOTIFICATIONS_LIMIT;
+use crate::pages::base_template::BaseTemplate;
+use crate::pages::user::options::warning_dialog;
+use anyhow::Result;
+use axum::Extension;
+use common::pseudonym::author_name;
+use common::repository::{DynRepository, Repository};
+use common::structs::{Notification, NotificationKind, User};
+use maud::{html, Markup};
+
+async fn notification_item(notification: &Notification, repo: &dyn Repository) -> Result<Markup> {
+    let Some(note) = repo.get_post(notification.note_id).await? else {
+        return Ok(html! {});
+    };
+    let tag = &notification.tag;
+    let (message, link_post_id) = match notification.kind() {
+        Some(NotificationKind::Reply) => (
+            format!(
+                "{} replied to your post",
+                author_name(note.author_id, repo).await?
+            ),
+            note.id,
+        ),
+        Some(NotificationKind::TopNote) => (
+            "A note now shows on a post you voted on".to_string(),
+            notification.post_id,
+        ),
+        Some(NotificationKind::OwnTopNote) => (
+            "Your note is now the top note of a post".to_string(),
+            notification.post_id,
+        ),
+        None => return Ok(html! {}),
+    };
+    let class = if notification.read {
+        "mb-3 p-3 rounded-lg shadow bg-white dark:bg-slate-700"
+    } else {
+        "mb-3 p-3 rounded-lg shadow bg-white dark:bg-slate-700 border-l-4 border-blue-500"
+    };
+
+    Ok(html! {
+        a href=(format!("/y/{tag}/post/{link_post_id}")) {
+            div class=(class) {
+                p class="font-bold" { (message) }
+                p class="truncate" { (note.content) }
+                small class="text-gray-500" { (format!("#{tag}, {} UTC", notification.created)) }
+            }
+        }
+    })
+}
+
+pub async fn notifications(
+    maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let title = "Notifications";
+    let Some(user) = maybe_user else {
+        return Ok(base
+            .title(title)
+            .content(warning_dialog(
+                "Your notifications appear after your first vote or post.",
+                None,
+            ))
+            .render());
+    };
+
+    let notifications = repo.get_notifications(user.id, NOTIFICATIONS_LIMIT).await?;
+    repo.mark_notifications_read(user.id).await?;
+
+    let mut items = vec![];
+    for notification in notifications.iter() {
+        items.push(notification_item(notification, repo.as_ref()).await?);
+    }
+    let content = html! {
+        h2 class="text-xl font-bold mb-4" { (title) }
+        @if items.is_empty() {
+            p { "Replies to your posts and new notes on posts you voted on show up here." }
+        }
+        @for item in items {
+            (item)
+        }
+    };
+
+    // the count in the navigation was taken before marking the notifications as read
+    let base = BaseTemplate {
+        unread_notifications: 0,
+        ..base
+    };
+    Ok(base.title(title).content(content).render())
+}
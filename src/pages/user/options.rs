@@ -1,17 +1,31 @@
 use crate::error::AppError;
 use crate::pages::base_template::BaseTemplate;
+use crate::pages::user::account::account_section;
 use crate::util::base_url;
-use common::structs::User;
+use crate::validation::{
+    form_error, validate_api_token, validate_display_name, validate_password, validate_username,
+    ValidationError,
+};
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::auth::{self, DEVICE_LINK_VALID_MINUTES};
+use common::pseudonym::pseudonym;
+use common::repository::DynRepository;
+use common::structs::{ApiScope, ApiToken, Session, User};
+use http::HeaderMap;
 use maud::{html, Markup};
 
 use anyhow::Result;
 
-use http::HeaderMap;
-
 use qrcode::render::svg;
 use qrcode::QrCode;
 
 use base64::{engine::general_purpose, Engine as _};
+use serde::Deserialize;
+use tower_cookies::Cookies;
 
 pub fn qr_code_base64(code: &String) -> String {
     let code = QrCode::new(code.as_bytes()).unwrap();
@@ -19,18 +33,59 @@ pub fn qr_code_base64(code: &String) -> String {
     general_purpose::STANDARD_NO_PAD.encode(code.render::<svg::Color>().build())
 }
 
-fn html(merge_url: &str, qr_code: &str) -> Markup {
+/// Choices for the expiry of new API tokens, in days
+const API_TOKEN_VALIDITY: [(&str, Option<i64>); 4] = [
+    ("7 days", Some(7)),
+    ("30 days", Some(30)),
+    ("1 year", Some(365)),
+    ("Never", None),
+];
+
+fn html(
+    sessions: Markup,
+    display_name: Markup,
+    credentials: Markup,
+    api_tokens: Markup,
+    account: Markup,
+) -> Markup {
     html! {
+        fieldset class="mb-4" {
+            (display_name)
+        }
         fieldset {
-            p { "Use this QR Code on another device to switch it to this account:" }
-            img id="qr-code" src=(format!("data:image/svg+xml;base64,{qr_code}"));
-            br;
-            small {
-                "Or open ";
-                a href=( merge_url ) { ( merge_url ) }
-                " on your other device"
+            p { "Sign in another device to this account with a QR code:" }
+            div id="device-link" {
+                form hx-post="/options/device_link" hx-target="#device-link" {
+                    button class="bg-blue-500 hover:bg-blue-700 text-white font-bold py-2 px-4 rounded mt-2" {
+                        "Show QR code"
+                    }
+                }
+            }
+        }
+        fieldset class="mt-4" {
+            p { "Signed in devices:" }
+            (sessions)
+        }
+        fieldset class="mt-4" {
+            (credentials)
+        }
+        fieldset class="mt-4" {
+            p { "API tokens let bots read, vote and post for you, without knowing your secret:" }
+            (api_tokens)
+        }
+        fieldset class="mt-4" {
+            p { "Your secret signs in other devices and the API. If it leaked, replace it:" }
+            div id="secret" {
+                form hx-post="/options/rotate_secret" hx-target="#secret" {
+                    button class="bg-blue-500 hover:bg-blue-700 text-white font-bold py-2 px-4 rounded mt-2" {
+                        "New secret"
+                    }
+                }
             }
         }
+        fieldset class="mt-4" {
+            (account)
+        }
         // TODO: save theme in localstorage
         // fieldset {
         //     label for="theme" { "theme" }
@@ -42,17 +97,174 @@ fn html(merge_url: &str, qr_code: &str) -> Markup {
     }
 }
 
+/// Without a display name, posts show the pseudonym of the user
+fn display_name_form(user: &User, display_name: Option<&str>) -> Markup {
+    html! {
+        form hx-post="/options/display_name" hx-swap="outerHTML" {
+            p { "Name shown on your posts and notes:" }
+            input
+                type="text"
+                name="display_name"
+                value=(display_name.unwrap_or_default())
+                placeholder=(pseudonym(user.id))
+                class=r#"
+                    block p-2.5 mb-2 text-gray-900 bg-gray-50 rounded-lg border border-gray-300
+                    dark:bg-gray-700 dark:border-gray-600 dark:text-white
+                "#
+                {}
+            small class="block mb-2" {
+                (format!("Leave it empty to appear as {}.", pseudonym(user.id)))
+            }
+            button class="bg-blue-500 hover:bg-blue-700 text-white font-bold py-2 px-4 rounded" {
+                "Save"
+            }
+            div class="form-errors" {}
+        }
+    }
+}
+
+/// Anonymous accounts become accounts with a username, keeping their votes
+fn credentials_form(username: Option<&str>) -> Markup {
+    let input_class = r#"
+        block p-2.5 mb-2 text-gray-900 bg-gray-50 rounded-lg border border-gray-300
+        dark:bg-gray-700 dark:border-gray-600 dark:text-white
+    "#;
+    html! {
+        form hx-post="/options/credentials" hx-swap="outerHTML" {
+            @match username {
+                Some(username) => p { (format!("Signed in as {username}. Change your username or password:")) },
+                None => p { "Add a username and password, to log in without a QR code:" },
+            }
+            input type="text" name="username" value=(username.unwrap_or_default()) class=(input_class) placeholder="Username" autocomplete="username" {}
+            @if username.is_some() {
+                input type="password" name="current_password" class=(input_class) placeholder="Current password" autocomplete="current-password" {}
+            }
+            input type="password" name="password" class=(input_class) placeholder="New password" autocomplete="new-password" {}
+            small class="block mb-2" { "Saving signs out all other devices." }
+            button class="bg-blue-500 hover:bg-blue-700 text-white font-bold py-2 px-4 rounded" {
+                "Save"
+            }
+            div class="form-errors" {}
+            p class="mt-2 text-sm" {
+                a href="/logout" class="text-blue-500 hover:text-blue-700" { "Log out" }
+            }
+        }
+    }
+}
+
+fn sessions_list(sessions: &[Session], current_session: &Session) -> Markup {
+    html! {
+        ul id="sessions" {
+            @for session in sessions {
+                li class="flex gap-4 items-center" {
+                    span { (format!("Signed in {}, last used {} (UTC)", session.created, session.last_used)) }
+                    @if session.id == current_session.id {
+                        span class="font-bold" { "This device" }
+                    } @else {
+                        button
+                            hx-post=(format!("/options/sessions/{}/revoke", session.id))
+                            hx-target="#sessions"
+                            hx-swap="outerHTML"
+                            class="text-sm text-red-600 hover:text-red-800"
+                        {
+                            "Sign out"
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn validity_value(valid_days: Option<i64>) -> String {
+    valid_days.map_or("never".to_string(), |days| days.to_string())
+}
+
+/// The tokens of a user and a form for new ones. `new_token` is shown once, after creating it.
+fn api_tokens_section(tokens: &[ApiToken], new_token: Option<&str>) -> Markup {
+    let input_class = r#"
+        block p-2.5 mb-2 text-gray-900 bg-gray-50 rounded-lg border border-gray-300
+        dark:bg-gray-700 dark:border-gray-600 dark:text-white
+    "#;
+    html! {
+        div id="api-tokens" {
+            @if let Some(new_token) = new_token {
+                p class="mb-2" {
+                    "Your new token is "
+                    code class="font-mono" { (new_token) }
+                    ". It is only shown once. Send it as "
+                    code class="font-mono" { "Authorization: Bearer <token>" }
+                    "."
+                }
+            }
+            ul {
+                @for token in tokens {
+                    li class="flex gap-4 items-center" {
+                        span class="font-bold" { (token.name) }
+                        span { (token.scopes.replace(',', ", ")) }
+                        span {
+                            (format!("created {}, ", token.created))
+                            @match &token.expires {
+                                Some(expires) => (format!("expires {expires} (UTC)")),
+                                None => "never expires",
+                            }
+                        }
+                        button
+                            hx-post=(format!("/options/api_tokens/{}/revoke", token.id))
+                            hx-target="#api-tokens"
+                            hx-swap="outerHTML"
+                            class="text-sm text-red-600 hover:text-red-800"
+                        {
+                            "Revoke"
+                        }
+                    }
+                }
+            }
+            form hx-post="/options/api_tokens" hx-target="#api-tokens" hx-swap="outerHTML" class="mt-2" {
+                input type="text" name="name" class=(input_class) placeholder="Name, e.g. the bot using it" {}
+                div class="flex gap-4 mb-2" {
+                    @for scope in ApiScope::ALL {
+                        label {
+                            input type="checkbox" name=(format!("scope_{scope}")) value="true" checked[scope == ApiScope::Read] {}
+                            (format!(" {scope}"))
+                        }
+                    }
+                }
+                select name="valid_days" class=(input_class) {
+                    @for (label, valid_days) in API_TOKEN_VALIDITY {
+                        option value=(validity_value(valid_days)) selected[valid_days == Some(30)] { (label) }
+                    }
+                }
+                button class="bg-blue-500 hover:bg-blue-700 text-white font-bold py-2 px-4 rounded" {
+                    "Create token"
+                }
+                div class="form-errors" {}
+            }
+        }
+    }
+}
+
 pub async fn options(
-    headers: HeaderMap,
     maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
     base: BaseTemplate,
 ) -> Result<Markup, AppError> {
     let title = "Options";
 
-    match maybe_user {
-        Some(user) => {
-            let merge_url = format!("{}/merge/{}", base_url(&headers), &user.secret);
-            let content = html(&merge_url, qr_code_base64(&merge_url).as_str());
+    let session = auth::session_from_cookies(&base.cookies, repo.as_ref()).await?;
+    match maybe_user.zip(session) {
+        Some((user, session)) => {
+            let sessions = repo.get_sessions(user.id).await?;
+            let username = repo.get_username(user.id).await?;
+            let api_tokens = repo.get_api_tokens(user.id).await?;
+            let display_name = repo.get_display_name(user.id).await?;
+            let content = html(
+                sessions_list(&sessions, &session),
+                display_name_form(&user, display_name.as_deref()),
+                credentials_form(username.as_deref()),
+                api_tokens_section(&api_tokens, None),
+                account_section(),
+            );
             Ok(base.title(title).content(content).into())
         }
         None => Ok(base
@@ -65,6 +277,233 @@ pub async fn options(
     }
 }
 
+pub async fn revoke_session(
+    Path(session_id): Path<i64>,
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+) -> Result<Response, AppError> {
+    let Some(current_session) = auth::session_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    if session_id != current_session.id {
+        repo.delete_session(current_session.user_id, session_id)
+            .await?;
+    }
+
+    let sessions = repo.get_sessions(current_session.user_id).await?;
+    Ok(sessions_list(&sessions, &current_session).into_response())
+}
+
+/// Creates a device link on request, so that viewing the options leaves no links behind. It
+/// replaces the previous link.
+pub async fn show_device_link(
+    cookies: Cookies,
+    headers: HeaderMap,
+    Extension(repo): Extension<DynRepository>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let token = auth::create_device_link(&user, repo.as_ref()).await?;
+    let merge_url = format!("{}/merge/{}", base_url(&headers), token);
+
+    Ok(html! {
+        img id="qr-code" src=(format!("data:image/svg+xml;base64,{}", qr_code_base64(&merge_url)));
+        br;
+        small {
+            "Or open ";
+            a href=( merge_url ) { ( merge_url ) }
+            (format!(" on your other device. The link works once, for {DEVICE_LINK_VALID_MINUTES} minutes. Showing a new code stops it from working."))
+        }
+    }
+    .into_response())
+}
+
+/// Shows the new secret once, it is only stored as a hash
+pub async fn rotate_secret(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+) -> Result<Response, AppError> {
+    let Some(current_session) = auth::session_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+    let user = User {
+        id: current_session.user_id,
+    };
+
+    let secret = auth::rotate_secret(&user, &current_session, repo.as_ref()).await?;
+
+    Ok(html! {
+        p {
+            "Your new secret is "
+            code class="font-mono" { (secret) }
+            ". It is only shown once. All other devices were signed out. Your username and password still work, change the password as well if it leaked."
+        }
+    }
+    .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct CredentialsForm {
+    username: String,
+    /// Only asked for once the user has credentials
+    #[serde(default)]
+    current_password: String,
+    password: String,
+}
+
+/// Changing existing credentials needs the current password, so that a session alone cannot take
+/// over the account for good
+pub async fn set_credentials(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<CredentialsForm>,
+) -> Result<Response, AppError> {
+    let Some(current_session) = auth::session_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+    let user = User {
+        id: current_session.user_id,
+    };
+
+    if let Some(current_username) = repo.get_username(user.id).await? {
+        let verified_user = auth::user_from_credentials(
+            &current_username,
+            &form_data.current_password,
+            repo.as_ref(),
+        )
+        .await?;
+        if verified_user.map(|verified_user| verified_user.id) != Some(user.id) {
+            return form_error(
+                ValidationError::Invalid {
+                    field: "current_password",
+                    message: "The current password is wrong".to_string(),
+                }
+                .into(),
+            );
+        }
+    }
+
+    let username = auth::normalize_username(&form_data.username);
+    let validation = match validate_username(&username, user.id, repo.as_ref()).await {
+        Ok(()) => validate_password(&form_data.password),
+        Err(error) => Err(error),
+    };
+    if let Err(error) = validation {
+        return form_error(error);
+    }
+
+    auth::set_credentials(
+        &user,
+        &current_session,
+        &username,
+        &form_data.password,
+        repo.as_ref(),
+    )
+    .await?;
+
+    Ok(credentials_form(Some(&username)).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct DisplayNameForm {
+    display_name: String,
+}
+
+pub async fn set_display_name(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<DisplayNameForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let display_name = Some(form_data.display_name.trim()).filter(|name| !name.is_empty());
+    if let Some(display_name) = display_name {
+        if let Err(error) = validate_display_name(display_name) {
+            return form_error(error);
+        }
+    }
+
+    repo.set_display_name(user.id, display_name).await?;
+
+    Ok(display_name_form(&user, display_name).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct ApiTokenForm {
+    name: String,
+    #[serde(default)]
+    scope_read: bool,
+    #[serde(default)]
+    scope_vote: bool,
+    #[serde(default)]
+    scope_post: bool,
+    #[serde(default)]
+    scope_account: bool,
+    valid_days: String,
+}
+
+pub async fn create_api_token(
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+    Form(form_data): Form<ApiTokenForm>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    let name = form_data.name.trim();
+    let scopes: Vec<ApiScope> = [
+        (ApiScope::Read, form_data.scope_read),
+        (ApiScope::Vote, form_data.scope_vote),
+        (ApiScope::Post, form_data.scope_post),
+        (ApiScope::Account, form_data.scope_account),
+    ]
+    .into_iter()
+    .filter_map(|(scope, selected)| selected.then_some(scope))
+    .collect();
+    if let Err(error) = validate_api_token(name, &scopes) {
+        return form_error(error);
+    }
+    let Some((_, valid_days)) = API_TOKEN_VALIDITY
+        .into_iter()
+        .find(|(_, valid_days)| validity_value(*valid_days) == form_data.valid_days)
+    else {
+        return form_error(
+            ValidationError::Invalid {
+                field: "valid_days",
+                message: "Unknown expiry".to_string(),
+            }
+            .into(),
+        );
+    };
+
+    let (_, token) =
+        auth::create_api_token(&user, name, &scopes, valid_days, repo.as_ref()).await?;
+
+    let tokens = repo.get_api_tokens(user.id).await?;
+    Ok(api_tokens_section(&tokens, Some(&token)).into_response())
+}
+
+pub async fn revoke_api_token(
+    Path(token_id): Path<i64>,
+    cookies: Cookies,
+    Extension(repo): Extension<DynRepository>,
+) -> Result<Response, AppError> {
+    let Some(user) = auth::user_from_cookies(&cookies, repo.as_ref()).await? else {
+        return Err(AppError::not_signed_in());
+    };
+
+    repo.delete_api_token(user.id, token_id).await?;
+
+    let tokens = repo.get_api_tokens(user.id).await?;
+    Ok(api_tokens_section(&tokens, None).into_response())
+}
+
 pub fn warning_dialog(msg: &str, caption: Option<&str>) -> Markup {
     html!(
         div.warn.card {
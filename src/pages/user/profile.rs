@@ -0,0 +1,279 @@
+//! The footprint of the signed in user: posts, top notes, votes, follows, mutes and blocks.
+//! `/api/v0/user` returns the same as JSON. Other users see the posts and top notes at
+//! `/user/:user_id`.
+
+use crate::constants::GLOBAL_TAG;
+use crate::error::AppError;
+use crate::pages::base_template::BaseTemplate;
+use crate::pages::block::{block_user_button, mute_tag_button};
+use crate::pages::follow::{follow_tag_button, follow_user_button};
+use crate::pages::user::options::warning_dialog;
+use crate::probabilities;
+use anyhow::Result;
+use axum::{
+    extract::Path,
+    response::{IntoResponse, Redirect, Response},
+    Extension,
+};
+use common::markdown::render_markdown;
+use common::pseudonym::author_name;
+use common::repository::{DynRepository, Repository};
+use common::structs::{Post, User};
+use maud::{html, Markup};
+
+pub struct Profile {
+    /// Display name or pseudonym, as shown on posts
+    pub name: String,
+    pub username: Option<String>,
+    /// Newest first
+    pub posts: Vec<Post>,
+    /// Posts of the user that are currently the top note of their parent
+    pub top_notes: Vec<Post>,
+    /// `(tag, current votes)`, most votes first
+    pub votes_per_tag: Vec<(String, i64)>,
+    pub votes_changed_after_note: i64,
+}
+
+pub async fn load_profile(user: &User, repo: &dyn Repository) -> Result<Profile> {
+    let posts = repo.get_posts_by_author(user.id).await?;
+
+    let mut top_notes = vec![];
+    for post in posts.iter() {
+        if let Some(parent_id) = post.parent_id {
+            if let Some((note_id, _, _)) =
+                probabilities::find_top_note(GLOBAL_TAG, parent_id, repo).await?
+            {
+                if note_id == post.id {
+                    top_notes.push(post.clone());
+                }
+            }
+        }
+    }
+
+    Ok(Profile {
+        name: author_name(user.id, repo).await?,
+        username: repo.get_username(user.id).await?,
+        posts,
+        top_notes,
+        votes_per_tag: repo.get_vote_counts_per_tag(user.id).await?,
+        votes_changed_after_note: repo.count_votes_changed_after_note(user.id).await?,
+    })
+}
+
+fn post_list(posts: &[Post]) -> Markup {
+    html! {
+        @for post in posts {
+            a href=(format!("/y/{GLOBAL_TAG}/post/{}", post.id)) {
+                div class="mb-3 p-3 rounded-lg shadow bg-white dark:bg-slate-700" {
+                    div class="markdown" { (render_markdown(&post.content, true, &|_| None)) }
+                    @if post.parent_id.is_some() {
+                        small class="text-gray-500" { "Reply" }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tags and users followed by the signed in user, with buttons to unfollow
+async fn following(user: &User, repo: &dyn Repository) -> Result<Markup> {
+    let tags = repo.get_followed_tags(user.id).await?;
+    let mut users = vec![];
+    for user_id in repo.get_followed_user_ids(user.id).await? {
+        users.push((user_id, author_name(user_id, repo).await?));
+    }
+
+    Ok(html! {
+        h3 class="font-bold mb-2" { "Following" }
+        @if tags.is_empty() && users.is_empty() {
+            p class="mb-4" { "Follow tags and users to see their posts on the front page." }
+        } @else {
+            ul class="mb-4" {
+                @for tag in tags.iter() {
+                    li class="flex gap-2" {
+                        a href=(format!("/y/{tag}")) class="text-blue-500 hover:text-blue-700" { (format!("#{tag}")) }
+                        (follow_tag_button(tag, true))
+                    }
+                }
+                @for (user_id, name) in users.iter() {
+                    li class="flex gap-2" {
+                        a href=(format!("/user/{user_id}")) class="text-blue-500 hover:text-blue-700" { (name) }
+                        (follow_user_button(*user_id, true))
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Tags muted and users blocked by the signed in user, with buttons to undo
+async fn muted_and_blocked(user: &User, repo: &dyn Repository) -> Result<Markup> {
+    let tags = repo.get_muted_tags(user.id).await?;
+    let mut users = vec![];
+    for user_id in repo.get_blocked_user_ids(user.id).await? {
+        users.push((user_id, author_name(user_id, repo).await?));
+    }
+
+    Ok(html! {
+        @if !tags.is_empty() || !users.is_empty() {
+            h3 class="font-bold mb-2" { "Muted and blocked" }
+            ul class="mb-4" {
+                @for tag in tags.iter() {
+                    li class="flex gap-2" {
+                        a href=(format!("/y/{tag}")) class="text-blue-500 hover:text-blue-700" { (format!("#{tag}")) }
+                        (mute_tag_button(tag, true))
+                    }
+                }
+                @for (user_id, name) in users.iter() {
+                    li class="flex gap-2" {
+                        a href=(format!("/user/{user_id}")) class="text-blue-500 hover:text-blue-700" { (name) }
+                        (block_user_button(*user_id, true))
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn html(profile: &Profile, following: Markup, muted_and_blocked: Markup) -> Markup {
+    html! {
+        h2 class="text-xl font-bold" { (profile.name) }
+        p class="mb-2 text-sm text-gray-500" {
+            @match &profile.username {
+                Some(username) => (format!("Logs in as {username}. ")),
+                None => "Anonymous account. ",
+            }
+            a href="/options" class="text-blue-500 hover:text-blue-700" { "Change your name" }
+        }
+        p class="mb-4" {
+            (format!(
+                "{} posts, {} top notes. You changed a vote {} times after seeing a note.",
+                profile.posts.len(),
+                profile.top_notes.len(),
+                profile.votes_changed_after_note
+            ))
+        }
+        (following)
+        (muted_and_blocked)
+        h3 class="font-bold mb-2" { "Votes per tag" }
+        @if profile.votes_per_tag.is_empty() {
+            p class="mb-4" { "No votes yet." }
+        } @else {
+            ul class="mb-4" {
+                @for (tag, votes) in profile.votes_per_tag.iter() {
+                    li {
+                        a href=(format!("/y/{tag}")) class="text-blue-500 hover:text-blue-700" { (tag) }
+                        (format!(": {votes}"))
+                    }
+                }
+            }
+        }
+        @if !profile.top_notes.is_empty() {
+            h3 class="font-bold mb-2" { "Top notes" }
+            (post_list(&profile.top_notes))
+        }
+        h3 class="font-bold mb-2" { "Posts" }
+        @if profile.posts.is_empty() {
+            p { "No posts yet." }
+        } @else {
+            (post_list(&profile.posts))
+        }
+    }
+}
+
+/// What other users see: no votes. Users who blocked this one see no posts.
+fn public_html(profile: &Profile, buttons: Option<Markup>, blocked: bool) -> Markup {
+    html! {
+        h2 class="text-xl font-bold mb-2" { (profile.name) }
+        @if let Some(buttons) = buttons {
+            div class="flex gap-4" { (buttons) }
+        }
+        @if blocked {
+            p class="text-gray-500" { "You blocked this user." }
+        } @else {
+            (public_posts(profile))
+        }
+    }
+}
+
+fn public_posts(profile: &Profile) -> Markup {
+    html! {
+        p class="mb-4" {
+            (format!("{} posts, {} top notes.", profile.posts.len(), profile.top_notes.len()))
+        }
+        @if !profile.top_notes.is_empty() {
+            h3 class="font-bold mb-2" { "Top notes" }
+            (post_list(&profile.top_notes))
+        }
+        h3 class="font-bold mb-2" { "Posts" }
+        @if profile.posts.is_empty() {
+            p { "No posts yet." }
+        } @else {
+            (post_list(&profile.posts))
+        }
+    }
+}
+
+pub async fn profile(
+    maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
+    base: BaseTemplate,
+) -> Result<Markup, AppError> {
+    let title = "Profile";
+
+    match maybe_user {
+        Some(user) => {
+            let profile = load_profile(&user, repo.as_ref()).await?;
+            let following = following(&user, repo.as_ref()).await?;
+            let muted_and_blocked = muted_and_blocked(&user, repo.as_ref()).await?;
+            Ok(base
+                .title(title)
+                .content(html(&profile, following, muted_and_blocked))
+                .render())
+        }
+        None => Ok(base
+            .title(title)
+            .content(warning_dialog(
+                "Your profile appears after your first vote or post.",
+                None,
+            ))
+            .render()),
+    }
+}
+
+pub async fn public_profile(
+    maybe_user: Option<User>,
+    Path(user_id): Path<i64>,
+    Extension(repo): Extension<DynRepository>,
+    base: BaseTemplate,
+) -> Result<Response, AppError> {
+    if !repo.user_exists(user_id).await? {
+        return Err(AppError::NotFound(format!("User {user_id} does not exist")));
+    }
+
+    let (buttons, blocked) = match maybe_user {
+        Some(user) if user.id == user_id => return Ok(Redirect::to("/user").into_response()),
+        Some(user) => {
+            let blocked = repo.get_blocked_user_ids(user.id).await?.contains(&user_id);
+            let following = repo
+                .get_followed_user_ids(user.id)
+                .await?
+                .contains(&user_id);
+            let buttons = html! {
+                @if !blocked {
+                    (follow_user_button(user_id, following))
+                }
+                (block_user_button(user_id, blocked))
+            };
+            (Some(buttons), blocked)
+        }
+        None => (None, false),
+    };
+
+    let profile = load_profile(&User { id: user_id }, repo.as_ref()).await?;
+    Ok(base
+        .title(&profile.name)
+        .content(public_html(&profile, buttons, blocked))
+        .render()
+        .into_response())
+}
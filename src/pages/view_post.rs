@@ -1,12 +1,12 @@
 use anyhow::Result;
 use axum::{extract::Path, Extension};
+use common::repository::{DynRepository, Repository};
 use maud::{html, Markup};
-use sqlx::SqlitePool;
-
-use crate::db;
 
+use crate::content_filter::ContentFilter;
 use crate::pages::components::post_details;
 use crate::pages::positions::load_positions_js;
+use crate::pages::user::bookmarks::bookmark_button;
 
 use crate::error::AppError;
 use common::structs::{Post, User};
@@ -15,28 +15,47 @@ use super::base_template::BaseTemplate;
 
 pub async fn view_post(
     Path((tag_string, post_id)): Path<(String, i64)>,
-    _maybe_user: Option<User>,
-    Extension(pool): Extension<SqlitePool>,
+    maybe_user: Option<User>,
+    Extension(repo): Extension<DynRepository>,
     base: BaseTemplate,
 ) -> Result<Markup, AppError> {
-    let post = db::get_post(post_id, &pool).await?;
+    let post = repo.get_post(post_id).await?;
+    let filter = ContentFilter::load(maybe_user.as_ref(), repo.as_ref()).await?;
     let tag = tag_string.as_str();
+    let bookmarked = match &maybe_user {
+        Some(user) => Some(repo.get_bookmark(user.id, post_id).await?.is_some()),
+        None => None,
+    };
     let content = match post {
+        Some(post) if filter.is_blocked(&post) => html! {
+            (parent_thread(tag, &post, &filter, repo.as_ref()).await?)
+            p class="mb-5 p-5 text-gray-500" { "This post is by a user you blocked." }
+        },
         Some(post) => {
             html! {
-                (parent_thread(tag, &post, &pool).await?)
-                (post_details(tag, &post, true, &pool).await?)
-                (replies(tag, post_id, &pool).await?)
+                (parent_thread(tag, &post, &filter, repo.as_ref()).await?)
+                (post_details(tag, &post, true, &filter, repo.as_ref()).await?)
+                @if let Some(bookmarked) = bookmarked {
+                    (bookmark_button(tag, post_id, bookmarked))
+                }
+                (referenced_by(tag, post_id, &filter, repo.as_ref()).await?)
+                (replies(tag, post_id, &filter, repo.as_ref()).await?)
                 (load_positions_js(tag, post_id))
             }
         }
-        None => html! { "Post not found" },
+        None => return Err(AppError::NotFound(format!("Post {post_id} does not exist"))),
     };
     Ok(base.title("𝕐").content(content).render())
 }
 
-async fn parent_thread(tag: &str, post: &Post, pool: &SqlitePool) -> Result<Markup> {
-    let transitive_parents: Vec<Post> = db::get_transitive_parents(post, pool).await?;
+/// Parents by blocked users keep their place in the thread, without their content
+async fn parent_thread(
+    tag: &str,
+    post: &Post,
+    filter: &ContentFilter,
+    repo: &dyn Repository,
+) -> Result<Markup> {
+    let transitive_parents: Vec<Post> = repo.get_transitive_parents(post).await?;
     Ok(html! {
         a href="/" {
             div class="truncate mb-2 p-3 rounded-lg shadow bg-gray-100 dark:bg-slate-600 ml-4" {
@@ -50,7 +69,11 @@ async fn parent_thread(tag: &str, post: &Post, pool: &SqlitePool) -> Result<Mark
                 ({
                     html! {
                         div data-postid=(parent.id) class="post truncate mb-2 p-3 rounded-lg shadow ml-4 bg-gray-100 dark:bg-slate-600" {
-                            (parent.content)
+                            @if filter.is_blocked(parent) {
+                                span class="text-gray-500" { "Post by a user you blocked" }
+                            } @else {
+                                (parent.content)
+                            }
                         }
                     }
                 })
@@ -59,8 +82,38 @@ async fn parent_thread(tag: &str, post: &Post, pool: &SqlitePool) -> Result<Mark
     })
 }
 
-async fn replies(tag: &str, post_id: i64, pool: &SqlitePool) -> Result<Markup> {
-    let replies = db::get_replies(tag, post_id, pool).await?;
+/// Backlinks to the posts that reference this one
+async fn referenced_by(
+    tag: &str,
+    post_id: i64,
+    filter: &ContentFilter,
+    repo: &dyn Repository,
+) -> Result<Markup> {
+    let referencing_posts = filter.posts(repo.get_referencing_posts(post_id).await?);
+
+    Ok(html! {
+        @if !referencing_posts.is_empty() {
+            div class="mb-5" {
+                h2 class="mt-4 ml-2 mb-2" { "Referenced by" }
+                @for post in referencing_posts.iter() {
+                    a href=(format!("/y/{}/post/{}", tag, post.id)) {
+                        div data-postid=(post.id) class="post truncate mb-2 p-3 rounded-lg shadow bg-gray-100 dark:bg-slate-600" {
+                            (post.content)
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+async fn replies(
+    tag: &str,
+    post_id: i64,
+    filter: &ContentFilter,
+    repo: &dyn Repository,
+) -> Result<Markup> {
+    let replies = filter.posts(repo.get_replies(tag, post_id).await?);
 
     Ok(html! {
         div {
@@ -1,14 +1,18 @@
-use axum::{Extension, Form};
-use common::auth;
+use axum::{
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use common::{
+    auth,
+    repository::{parse_tags, DynRepository},
+};
 use maud::{html, Markup};
-use regex;
-use sqlx::SqlitePool;
 use tower_cookies::Cookies;
 
-use crate::db;
 use crate::error::AppError;
+use crate::notifications::TopNotes;
 use crate::pages::components::tag_form;
-use regex::Regex;
+use crate::validation::{form_error, validate_tags, validate_vote};
 use serde::Deserialize;
 
 use anyhow::Result;
@@ -40,9 +44,20 @@ pub struct TagRequest {
 
 pub async fn vote_handler(
     cookies: Cookies,
-    Extension(pool): Extension<SqlitePool>,
+    Extension(repo): Extension<DynRepository>,
     Form(form_data): Form<VoteRequest>,
-) -> Result<Markup, AppError> {
+) -> Result<Response, AppError> {
+    if let Err(error) = validate_vote(
+        &form_data.tag,
+        form_data.post_id,
+        form_data.note_id,
+        repo.as_ref(),
+    )
+    .await
+    {
+        return form_error(error);
+    }
+
     // First, interpret the user intent based on the button pressed **and** the current state.
     let new_state = if form_data.direction == form_data.state {
         Neutral
@@ -50,23 +65,25 @@ pub async fn vote_handler(
         form_data.direction
     };
 
-    let user = auth::get_or_create_user(&cookies, &pool).await?;
-    db::vote(
+    let user = auth::get_or_create_user(&cookies, repo.as_ref()).await?;
+    let top_notes = TopNotes::load(&form_data.tag, form_data.post_id, repo.as_ref()).await;
+    repo.vote(
         user.id,
         form_data.tag.as_str(),
         form_data.post_id,
         form_data.note_id,
         new_state,
-        &pool,
     )
     .await?;
+    top_notes.notify_changes(repo.as_ref()).await;
 
     Ok(vote_buttons(
         form_data.tag.as_str(),
         form_data.post_id,
         form_data.note_id,
         new_state,
-    ))
+    )
+    .into_response())
 }
 
 pub fn vote_buttons(tag: &str, post_id: i64, note_id: Option<i64>, state: Direction) -> Markup {
@@ -103,22 +120,33 @@ pub fn vote_buttons(tag: &str, post_id: i64, note_id: Option<i64>, state: Direct
 
 pub async fn tag_handler(
     cookies: Cookies,
-    Extension(pool): Extension<SqlitePool>,
+    Extension(repo): Extension<DynRepository>,
     Form(form_data): Form<TagRequest>,
-) -> Result<Markup, AppError> {
-    let user = auth::get_or_create_user(&cookies, &pool).await?;
-    let re = Regex::new(r"[^\p{L}]+").unwrap(); // This regex matches one or more commas or spaces
-    let tags = re.split(form_data.tags.as_str());
-    for tag in tags {
-        db::vote(
+) -> Result<Response, AppError> {
+    let tags = parse_tags(&form_data.tags);
+    if let Err(error) = validate_tags(&tags) {
+        return form_error(error);
+    }
+    for tag in &tags {
+        if let Err(error) =
+            validate_vote(tag, form_data.post_id, form_data.note_id, repo.as_ref()).await
+        {
+            return form_error(error);
+        }
+    }
+
+    let user = auth::get_or_create_user(&cookies, repo.as_ref()).await?;
+    for tag in &tags {
+        let top_notes = TopNotes::load(tag, form_data.post_id, repo.as_ref()).await;
+        repo.vote(
             user.id,
             tag,
             form_data.post_id,
             form_data.note_id,
             Direction::Up,
-            &pool,
         )
         .await?;
+        top_notes.notify_changes(repo.as_ref()).await;
     }
-    Ok(tag_form(form_data.post_id, form_data.note_id))
+    Ok(tag_form(form_data.post_id, form_data.note_id).into_response())
 }
@@ -1,7 +1,9 @@
 use anyhow::Result;
+use common::repository::Repository;
+use common::structs::{InformedTally, Post, Tally};
 use itertools::Itertools;
-use sqlx::SqlitePool;
 use std::fmt;
+use tracing::debug;
 
 use std::collections::HashMap;
 
@@ -14,23 +16,6 @@ fn global_prior() -> BetaDistribution {
     }
 }
 
-#[derive(sqlx::FromRow, sqlx::Decode, Debug, Clone, Copy)]
-pub struct Tally {
-    pub upvotes: i64,
-    pub total: i64,
-}
-
-impl fmt::Display for Tally {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Tally({}/{})", self.upvotes, self.total)
-    }
-}
-
-const EMPTY_TALLY: Tally = Tally {
-    upvotes: 0,
-    total: 0,
-};
-
 #[derive(Debug, Clone)]
 pub struct BetaDistribution {
     pub average: f64,
@@ -63,94 +48,17 @@ fn bayesian_average(prior_average: f64, weight: f64, tally: Tally) -> f64 {
     (prior_average * weight + tally.upvotes as f64) / (weight + tally.total as f64)
 }
 
-#[derive(sqlx::FromRow, sqlx::Decode, Debug, Clone)]
-pub struct InformedTallyQueryResult {
-    pub post_id: i64,
-    pub note_id: i64,
-    upvotes_given_shown_this_note: i64,
-    votes_given_shown_this_note: i64,
-    upvotes_given_not_shown_this_note: i64,
-    votes_given_not_shown_this_note: i64,
-    upvotes_for_note: i64,
-    votes_for_note: i64,
-}
-
-impl InformedTallyQueryResult {
-    fn informed_tally(&self) -> InformedTally {
-        InformedTally {
-            post_id: self.post_id,
-            note_id: self.note_id,
-            given_not_shown_this_note: Tally {
-                upvotes: self.upvotes_given_not_shown_this_note,
-                total: self.votes_given_not_shown_this_note,
-            },
-            given_shown_this_note: Tally {
-                upvotes: self.upvotes_given_shown_this_note,
-                total: self.votes_given_shown_this_note,
-            },
-            for_note: Tally {
-                upvotes: self.upvotes_for_note,
-                total: self.votes_for_note,
-            },
-        }
-    }
-}
-
-#[derive(sqlx::FromRow, sqlx::Decode, Debug, Clone)]
-pub struct InformedTally {
-    pub post_id: i64,
-    pub note_id: i64,
-
-    given_not_shown_this_note: Tally,
-    given_shown_this_note: Tally,
-    for_note: Tally,
-}
-
-pub async fn find_top_note(post_id: i64, pool: &SqlitePool) -> Result<Option<(i64, f64, f64)>> {
-    // first, get table which has stats for this note, all subnotes, and all subnotes
-    let query = r#"
-        WITH children AS
-        (
-          SELECT
-              post_id
-            , note_id
-            , votes_given_shown_this_note
-            , upvotes_given_shown_this_note
-            , votes_given_not_shown_this_note
-            , upvotes_given_not_shown_this_note
-          FROM current_informed_tally p
-          WHERE post_id = ?
-          UNION ALL
-          SELECT 
-              p.post_id
-            , p.note_id
-            , p.votes_given_not_shown_this_note
-            , p.upvotes_given_not_shown_this_note
-            , p.votes_given_not_shown_this_note
-            , p.upvotes_given_not_shown_this_note
-          FROM children c
-          INNER JOIN current_informed_tally p ON p.post_id = c.note_id
-        )
-        SELECT 
-          children.*
-          , current_tally.votes as votes_for_note 
-          , current_tally.upvotes as upvotes_for_note
-        FROM children join current_tally on (current_tally.post_id = children.note_id);
-    "#;
-
-    // execute the query and get a vector of InformedTally
-    let tallies: Vec<InformedTally> = sqlx::query_as::<_, InformedTallyQueryResult>(query)
-        .bind(post_id)
-        .fetch_all(pool)
-        .await?
-        .iter()
-        .map(|result| result.informed_tally())
-        .collect();
+pub async fn find_top_note(
+    tag: &str,
+    post_id: i64,
+    repo: &dyn Repository,
+) -> Result<Option<(i64, f64, f64)>> {
+    let tallies: Vec<InformedTally> = repo.informed_tallies(tag, post_id).await?;
 
     let subnote_tallies: HashMap<i64, Vec<&InformedTally>> =
         tallies.iter().into_group_map_by(|tally| tally.post_id);
 
-    let t = current_tally(post_id, pool).await?;
+    let t = repo.current_tally(tag, post_id).await?;
 
     let (note_id, p, q) = find_top_note_given_tallies(post_id, t, &subnote_tallies);
 
@@ -161,6 +69,13 @@ pub async fn find_top_note(post_id: i64, pool: &SqlitePool) -> Result<Option<(i6
     })
 }
 
+pub async fn get_top_note(tag: &str, post_id: i64, repo: &dyn Repository) -> Result<Option<Post>> {
+    Ok(match find_top_note(tag, post_id, repo).await? {
+        None => None,
+        Some((note_id, _, _)) => repo.get_post(note_id).await?,
+    })
+}
+
 /// In the context of this function, we always have two posts in scope: A post along with a
 /// note that is attached to it. Here, we call those "A" and "B" (or "a" and "b" in variable
 /// and function names).
@@ -173,7 +88,7 @@ fn find_top_note_given_tallies(
 ) -> (i64, f64, f64) {
     let mut p_of_a_given_not_shown_top_note = global_prior().update(post_tally).average;
 
-    println!(
+    debug!(
         "p_of_a_given_not_shown_top_note for post {} = {}",
         post_id, p_of_a_given_not_shown_top_note
     );
@@ -184,7 +99,7 @@ fn find_top_note_given_tallies(
 
     let tallies = subnote_tallies.get(&post_id);
     if tallies.is_none() {
-        println!(
+        debug!(
             "top note for post {} is note {} with p={} and q={}",
             post_id, top_note_id, p_of_a_given_shown_top_note, p_of_a_given_not_shown_top_note
         );
@@ -213,7 +128,7 @@ fn find_top_note_given_tallies(
         let p_of_a_given_shown_this_note_and_top_subnote =
             p_of_a_given_not_shown_this_note + delta * support;
 
-        println!("\tFor post {} and note {}, p_of_a_given_shown_this_note={}, p_of_a_given_not_shown_this_note={}, delta={}, support={}", post_id, tally.note_id, p_of_a_given_shown_this_note, p_of_a_given_not_shown_this_note, delta, support);
+        debug!("For post {} and note {}, p_of_a_given_shown_this_note={}, p_of_a_given_not_shown_this_note={}, delta={}, support={}", post_id, tally.note_id, p_of_a_given_shown_this_note, p_of_a_given_not_shown_this_note, delta, support);
 
         if (p_of_a_given_shown_this_note_and_top_subnote - p_of_a_given_not_shown_this_note).abs()
             > (p_of_a_given_shown_top_note - p_of_a_given_not_shown_top_note).abs()
@@ -224,7 +139,7 @@ fn find_top_note_given_tallies(
         }
     }
 
-    println!(
+    debug!(
         "top note for post {} is note {} with p={} and q={}",
         post_id, top_note_id, p_of_a_given_shown_top_note, p_of_a_given_not_shown_top_note
     );
@@ -236,17 +151,63 @@ fn find_top_note_given_tallies(
     )
 }
 
-async fn current_tally(post_id: i64, pool: &SqlitePool) -> Result<Tally> {
-    // first, get table which has stats for this note, all subnotes, and all subnotes
-    let query = r#"
-        select upvotes, votes as total from current_tally where post_id = ? 
-    "#;
+#[cfg(test)]
+mod tests {
+    use common::repository::memory::MemoryRepository;
+    use common::structs::Direction;
+
+    use super::*;
 
-    // execute the query and get a vector of InformedTally
-    let tally: Option<Tally> = sqlx::query_as::<_, Tally>(query)
-        .bind(post_id)
-        .fetch_optional(pool)
-        .await?;
+    #[tokio::test]
+    async fn post_without_notes_has_no_top_note() {
+        let repo = MemoryRepository::new();
+        let author = repo.create_user("author").await.unwrap();
+        let post = repo
+            .create_post(&["test".to_string()], None, "post", author)
+            .await
+            .unwrap();
 
-    Ok(tally.unwrap_or(EMPTY_TALLY))
+        assert!(find_top_note("test", post, &repo).await.unwrap().is_none());
+        assert!(get_top_note("test", post, &repo).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn note_that_changes_votes_is_top_note() {
+        let repo = MemoryRepository::new();
+        let tags = ["test".to_string()];
+        let author = repo.create_user("author").await.unwrap();
+        let post = repo.create_post(&tags, None, "post", author).await.unwrap();
+        let note_author = repo.create_user("note author").await.unwrap();
+        let note = repo
+            .create_post(&tags, Some(post), "note", note_author)
+            .await
+            .unwrap();
+        let ignored_note = repo
+            .create_post(&tags, Some(post), "other note", note_author)
+            .await
+            .unwrap();
+
+        for i in 0..3 {
+            let voter = repo.create_user(&format!("voter {i}")).await.unwrap();
+            repo.vote(voter, "test", post, None, Direction::Down)
+                .await
+                .unwrap();
+            repo.vote(voter, "test", post, Some(note), Direction::Up)
+                .await
+                .unwrap();
+        }
+
+        let (top_note_id, p_shown, p_not_shown) =
+            find_top_note("test", post, &repo).await.unwrap().unwrap();
+        assert_eq!(top_note_id, note);
+        assert_ne!(top_note_id, ignored_note);
+        assert!(p_shown > p_not_shown);
+
+        let top_note = get_top_note("test", post, &repo).await.unwrap().unwrap();
+        assert_eq!(top_note.id, note);
+        assert_eq!(top_note.content, "note");
+
+        // the votes were in another tag
+        assert!(find_top_note("other", post, &repo).await.unwrap().is_none());
+    }
 }
@@ -0,0 +1,337 @@
+//! Token bucket rate limits for voting, posting and account creation
+//!
+//! Each request of a [RouteClass] takes a token from the bucket of its client IP and, if signed
+//! in, from the bucket of its user. Buckets hold a full period's budget and refill continuously.
+//! Pages that call `auth::get_or_create_user` create an account for requests without a session,
+//! so those requests also take a token from the account bucket of their IP. When a bucket is
+//! empty, the request is rejected with 429 and a `Retry-After` header. On the site, the limit runs
+//! inside of `error::error_pages`, which renders the rejection as a page or in the form.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, OriginalUri, State},
+    http::{Method, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use common::auth;
+use common::repository::DynRepository;
+use tracing::warn;
+
+use crate::command_line_args::RateLimitArgs;
+use crate::error::AppError;
+
+/// Above this many buckets, full ones are dropped. A full bucket behaves like a missing one.
+const CLEANUP_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RouteClass {
+    Vote,
+    Post,
+    Account,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    User(i64),
+}
+
+fn route_class(method: &Method, path: &str) -> Option<RouteClass> {
+    if method != Method::POST {
+        return None;
+    }
+    match path {
+        "/vote" | "/tag/" | "/api/v0/vote" => Some(RouteClass::Vote),
+        "/create_post" | "/api/v0/create_post" => Some(RouteClass::Post),
+        "/api/v0/user/create" => Some(RouteClass::Account),
+        _ => None,
+    }
+}
+
+/// Pages that create an account for requests without a session
+fn creates_account(path: &str) -> bool {
+    matches!(path, "/vote" | "/tag/" | "/create_post" | "/positions")
+}
+
+/// IPv6 clients usually get a whole /64, so it counts as one client
+fn client_network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    capacity: f64,
+    per_second: f64,
+}
+
+impl Budget {
+    /// None if `amount` is 0, which turns the limit off
+    fn new(amount: u32, period: Duration) -> Option<Budget> {
+        (amount > 0).then(|| Budget {
+            capacity: amount as f64,
+            per_second: amount as f64 / period.as_secs_f64(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    budget: Budget,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.budget.per_second).min(self.budget.capacity);
+        self.updated = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.budget.capacity
+    }
+}
+
+pub struct RateLimiter {
+    args: RateLimitArgs,
+    repo: DynRepository,
+    buckets: Mutex<HashMap<(RouteClass, Client), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(args: RateLimitArgs, repo: DynRepository) -> Arc<RateLimiter> {
+        Arc::new(RateLimiter {
+            args,
+            repo,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn budget(&self, class: RouteClass, client: Client) -> Option<Budget> {
+        let minute = Duration::from_secs(60);
+        let per_user = match class {
+            RouteClass::Vote => self.args.rate_limit_votes_per_minute,
+            RouteClass::Post => self.args.rate_limit_posts_per_minute,
+            RouteClass::Account => {
+                return Budget::new(
+                    self.args.rate_limit_accounts_per_hour,
+                    Duration::from_secs(60 * 60),
+                )
+            }
+        };
+        match client {
+            Client::User(_) => Budget::new(per_user, minute),
+            // several users can share an IP
+            Client::Ip(_) => Budget::new(per_user * self.args.rate_limit_ip_factor, minute),
+        }
+    }
+
+    /// Takes a token from each bucket, or from none of them and returns how long to wait
+    fn acquire(&self, keys: &[(RouteClass, Client)]) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+
+        if buckets.len() > CLEANUP_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+        }
+
+        let mut wait = Duration::ZERO;
+        for &(class, client) in keys {
+            let Some(budget) = self.budget(class, client) else {
+                continue;
+            };
+            let bucket = buckets.entry((class, client)).or_insert(Bucket {
+                budget,
+                tokens: budget.capacity,
+                updated: now,
+            });
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64(
+                    (1.0 - bucket.tokens) / budget.per_second,
+                ));
+            }
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+
+        for key in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    fn client_ip<B>(&self, request: &Request<B>, peer: SocketAddr) -> IpAddr {
+        let forwarded = self
+            .args
+            .client_ip_header
+            .as_ref()
+            .and_then(|header| request.headers().get(header))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<IpAddr>().ok());
+        client_network(forwarded.unwrap_or(peer.ip()))
+    }
+}
+
+/// Whole seconds for `Retry-After`, rounded up so that clients don't retry too early
+fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+/// Middleware, see the module documentation
+pub async fn rate_limit<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // the URI of the request lacks the prefix of nested routers like /api/v0
+    let path = uri.path().to_string();
+    let class = route_class(request.method(), &path);
+    let may_create_account = creates_account(&path);
+    if class.is_none() && !may_create_account {
+        return next.run(request).await;
+    }
+
+    let ip = limiter.client_ip(&request, peer);
+    // the request must not be borrowed across the await, its body is not Sync
+    let headers = request.headers().clone();
+    let user = match auth::user_from_headers(&headers, limiter.repo.as_ref()).await {
+        Ok(user) => user,
+        Err(error) => {
+            warn!("Rate limiting {path} without user: {error}");
+            None
+        }
+    };
+
+    let mut keys = vec![];
+    if let Some(class) = class {
+        keys.push((class, Client::Ip(ip)));
+        if let Some(user) = &user {
+            keys.push((class, Client::User(user.id)));
+        }
+    }
+    if may_create_account && user.is_none() {
+        keys.push((RouteClass::Account, Client::Ip(ip)));
+    }
+
+    match limiter.acquire(&keys) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => AppError::RateLimited {
+            retry_after: retry_after_secs(wait),
+        }
+        .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::repository::memory::MemoryRepository;
+
+    use super::*;
+
+    fn limiter(votes_per_minute: u32, ip_factor: u32) -> Arc<RateLimiter> {
+        let args = RateLimitArgs {
+            rate_limit_votes_per_minute: votes_per_minute,
+            rate_limit_posts_per_minute: 5,
+            rate_limit_accounts_per_hour: 30,
+            rate_limit_ip_factor: ip_factor,
+            client_ip_header: None,
+        };
+        RateLimiter::new(args, Arc::new(MemoryRepository::new()))
+    }
+
+    fn tokens(limiter: &RateLimiter, key: (RouteClass, Client)) -> f64 {
+        limiter.buckets.lock().unwrap()[&key].tokens
+    }
+
+    #[test]
+    fn zero_turns_a_limit_off() {
+        assert!(Budget::new(0, Duration::from_secs(60)).is_none());
+
+        let limiter = limiter(0, 10);
+        let ip = Client::Ip("192.0.2.1".parse().unwrap());
+        for _ in 0..1000 {
+            let keys = [(RouteClass::Vote, ip), (RouteClass::Vote, Client::User(1))];
+            assert!(limiter.acquire(&keys).is_ok());
+        }
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn acquire_takes_from_all_buckets_or_none() {
+        // one vote per user and two per IP
+        let limiter = limiter(1, 2);
+        let ip = (RouteClass::Vote, Client::Ip("192.0.2.1".parse().unwrap()));
+        let user = (RouteClass::Vote, Client::User(1));
+
+        assert!(limiter.acquire(&[ip, user]).is_ok());
+        assert!(limiter.acquire(&[ip, user]).is_err());
+        // the IP bucket had a token left, but the empty user bucket rejected the request
+        assert!(tokens(&limiter, ip) >= 1.0);
+        assert!(tokens(&limiter, user) < 1.0);
+
+        let other_user = (RouteClass::Vote, Client::User(2));
+        assert!(limiter.acquire(&[ip, other_user]).is_ok());
+        // and now the empty IP bucket rejects a user who has not voted yet
+        let third_user = (RouteClass::Vote, Client::User(3));
+        assert!(limiter.acquire(&[ip, third_user]).is_err());
+        assert!(tokens(&limiter, third_user) >= 1.0);
+    }
+
+    #[test]
+    fn wait_is_the_time_until_the_emptiest_bucket_has_a_token() {
+        // one vote per minute and user
+        let limiter = limiter(1, 10);
+        let user = (RouteClass::Vote, Client::User(1));
+        assert!(limiter.acquire(&[user]).is_ok());
+
+        let wait = limiter.acquire(&[user]).unwrap_err();
+        assert!(wait <= Duration::from_secs(60));
+        assert!(wait > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(retry_after_secs(Duration::from_nanos(1)), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(1500)), 2);
+        assert_eq!(retry_after_secs(Duration::from_secs(3)), 3);
+    }
+
+    #[test]
+    fn ipv6_clients_are_limited_per_64() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            client_network(ip("2001:db8:1:2:3:4:5:6")),
+            ip("2001:db8:1:2::"),
+        );
+        assert_eq!(
+            client_network(ip("2001:db8:1:2:ffff::1")),
+            client_network(ip("2001:db8:1:2::2")),
+        );
+        assert_ne!(
+            client_network(ip("2001:db8:1:2::1")),
+            client_network(ip("2001:db8:1:3::1")),
+        );
+        assert_eq!(client_network(ip("192.0.2.1")), ip("192.0.2.1"));
+    }
+}
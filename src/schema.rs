@@ -0,0 +1,206 @@
+//! Checks that the database schema matches this build
+
+use std::fmt;
+
+use anyhow::{bail, Result};
+use sqlx::migrate::Migrator;
+
+use crate::db_setup::Database;
+
+/// Tables the queries rely on. Keep in sync with the migrations.
+const EXPECTED_TABLES: [&str; 15] = [
+    "users",
+    "tags",
+    "posts",
+    "vote_history",
+    "vote_history_archive",
+    "post_references",
+    "sessions",
+    "device_links",
+    "api_tokens",
+    "tag_follows",
+    "user_follows",
+    "notifications",
+    "bookmarks",
+    "tag_mutes",
+    "user_blocks",
+];
+
+/// Views the queries rely on. Keep in sync with the migrations.
+const EXPECTED_VIEWS: [&str; 3] = ["current_vote", "current_tally", "current_informed_tally"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// The migration started, but did not finish
+    Failed,
+    /// The migration file was changed after it was applied
+    Modified,
+    /// Applied to the database, but not part of this build
+    Unknown,
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+#[derive(Debug)]
+pub struct SchemaReport {
+    pub migrations: Vec<MigrationStatus>,
+    pub missing_tables: Vec<&'static str>,
+    pub missing_views: Vec<&'static str>,
+}
+
+impl SchemaReport {
+    pub fn is_ok(&self) -> bool {
+        self.migrations
+            .iter()
+            .all(|migration| migration.state == MigrationState::Applied)
+            && self.missing_tables.is_empty()
+            && self.missing_views.is_empty()
+    }
+}
+
+impl fmt::Display for SchemaReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Migrations:")?;
+        for migration in &self.migrations {
+            let state = match migration.state {
+                MigrationState::Applied => "applied",
+                MigrationState::Pending => "pending",
+                MigrationState::Failed => "FAILED",
+                MigrationState::Modified => "MODIFIED after it was applied",
+                MigrationState::Unknown => "applied, but UNKNOWN to this build",
+            };
+            writeln!(
+                f,
+                "  {} {}: {state}",
+                migration.version, migration.description
+            )?;
+        }
+        if !self.missing_tables.is_empty() {
+            writeln!(f, "Missing tables: {}", self.missing_tables.join(", "))?;
+        }
+        if !self.missing_views.is_empty() {
+            writeln!(f, "Missing views: {}", self.missing_views.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Fails with the report if the schema does not match this build
+pub async fn verify_schema(database: &Database) -> Result<()> {
+    let report = schema_report(database).await?;
+    if !report.is_ok() {
+        bail!(
+            "The database schema does not match this build.\n{report}\
+             Apply the migrations with `y migrate run` (`just migrate`) or build with the \
+             `embed_migrations` feature."
+        );
+    }
+    Ok(())
+}
+
+pub async fn schema_report(database: &Database) -> Result<SchemaReport> {
+    // (name, is_view) of all tables and views
+    let relations: Vec<(String, bool)> = match database {
+        Database::Sqlite(pool) => {
+            sqlx::query_as(
+                r#"
+                    select name, type = 'view'
+                    from sqlite_master
+                    where type in ('table', 'view')
+                "#,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+        #[cfg(feature = "postgres")]
+        Database::Postgres(pool) => {
+            sqlx::query_as(
+                r#"
+                    select table_name::text, table_type = 'VIEW'
+                    from information_schema.tables
+                    where table_schema = current_schema()
+                "#,
+            )
+            .fetch_all(pool)
+            .await?
+        }
+    };
+    let has = |name: &str, view: bool| relations.iter().any(|r| r.0 == name && r.1 == view);
+
+    let applied: Vec<(i64, String, bool, Vec<u8>)> = if has("_sqlx_migrations", false) {
+        let query = r#"
+            select version, description, success, checksum
+            from _sqlx_migrations
+            order by version
+        "#;
+        match database {
+            Database::Sqlite(pool) => sqlx::query_as(query).fetch_all(pool).await?,
+            #[cfg(feature = "postgres")]
+            Database::Postgres(pool) => sqlx::query_as(query).fetch_all(pool).await?,
+        }
+    } else {
+        vec![]
+    };
+
+    Ok(SchemaReport {
+        migrations: migration_states(database.migrator(), &applied),
+        missing_tables: EXPECTED_TABLES
+            .into_iter()
+            .filter(|table| !has(table, false))
+            .collect(),
+        missing_views: EXPECTED_VIEWS
+            .into_iter()
+            .filter(|view| !has(view, true))
+            .collect(),
+    })
+}
+
+fn migration_states(
+    migrator: &Migrator,
+    applied: &[(i64, String, bool, Vec<u8>)],
+) -> Vec<MigrationStatus> {
+    let mut states: Vec<MigrationStatus> = migrator
+        .iter()
+        .map(|migration| {
+            let state = match applied
+                .iter()
+                .find(|(version, ..)| *version == migration.version)
+            {
+                None => MigrationState::Pending,
+                Some((_, _, false, _)) => MigrationState::Failed,
+                Some((_, _, true, checksum)) if checksum[..] != migration.checksum[..] => {
+                    MigrationState::Modified
+                }
+                Some(_) => MigrationState::Applied,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+            }
+        })
+        .collect();
+
+    for (version, description, ..) in applied {
+        if !migrator
+            .iter()
+            .any(|migration| migration.version == *version)
+        {
+            states.push(MigrationStatus {
+                version: *version,
+                description: description.clone(),
+                state: MigrationState::Unknown,
+            });
+        }
+    }
+    states.sort_by_key(|migration| migration.version);
+
+    states
+}
@@ -0,0 +1,473 @@
+//! Checks of user input, shared by the pages and `/api/v0`
+//!
+//! Failed checks are [ValidationError]s inside the returned `anyhow::Error`. `?` turns them
+//! into [AppError::Validation], htmx forms can also render them inline with [form_error].
+
+use anyhow::Result;
+use axum::response::Response;
+use common::pseudonym::is_pseudonym;
+use common::repository::Repository;
+use common::structs::ApiScope;
+
+use crate::error::{AppError, ErrorHtml};
+
+pub use common::error::ValidationError;
+
+/// In characters
+pub const MAX_POST_LENGTH: usize = 5000;
+/// In characters
+pub const MAX_TAG_LENGTH: usize = 50;
+pub const MAX_TAGS_PER_POST: usize = 10;
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 30;
+/// In characters
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// In characters, hashing very long passwords is slow
+pub const MAX_PASSWORD_LENGTH: usize = 200;
+/// In characters
+pub const MAX_API_TOKEN_NAME_LENGTH: usize = 100;
+/// In characters
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 40;
+
+/// For htmx form handlers: renders validation errors inline and passes all other errors on,
+/// converted like `?` does
+pub fn form_error(error: anyhow::Error) -> Result<Response, AppError> {
+    match error.downcast::<ValidationError>() {
+        Ok(validation_error) => Ok(AppError::Validation(validation_error).form_response()),
+        Err(error) => Err(AppError::from(error)),
+    }
+}
+
+fn invalid(field: &'static str, message: impl Into<String>) -> anyhow::Error {
+    ValidationError::Invalid {
+        field,
+        message: message.into(),
+    }
+    .into()
+}
+
+fn conflict(field: &'static str, message: impl Into<String>) -> anyhow::Error {
+    ValidationError::Conflict {
+        field,
+        message: message.into(),
+    }
+    .into()
+}
+
+fn not_found(field: &'static str, message: impl Into<String>) -> anyhow::Error {
+    ValidationError::NotFound {
+        field,
+        message: message.into(),
+    }
+    .into()
+}
+
+pub fn validate_content(content: &str) -> Result<()> {
+    if content.trim().is_empty() {
+        return Err(invalid("content", "Post content cannot be empty"));
+    }
+    let length = content.chars().count();
+    if length > MAX_POST_LENGTH {
+        return Err(invalid(
+            "content",
+            format!("Post content is {length} characters long, the limit is {MAX_POST_LENGTH}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Expects normalized tags, see [common::repository::normalize_tags]
+pub fn validate_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() {
+        return Err(invalid("tags", "Tags cannot be empty"));
+    }
+    if tag.chars().count() > MAX_TAG_LENGTH {
+        return Err(invalid(
+            "tags",
+            format!("#{tag} is longer than {MAX_TAG_LENGTH} characters"),
+        ));
+    }
+    if !tag
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(invalid(
+            "tags",
+            format!("#{tag} may only contain letters, digits, - and _"),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_tags(tags: &[String]) -> Result<()> {
+    if tags.is_empty() {
+        return Err(invalid("tags", "A post needs at least one tag"));
+    }
+    if tags.len() > MAX_TAGS_PER_POST {
+        return Err(invalid(
+            "tags",
+            format!("A post can have at most {MAX_TAGS_PER_POST} tags"),
+        ));
+    }
+    tags.iter().try_for_each(|tag| validate_tag(tag))
+}
+
+pub async fn validate_post_exists(
+    field: &'static str,
+    post_id: i64,
+    repo: &dyn Repository,
+) -> Result<()> {
+    match repo.get_post(post_id).await? {
+        Some(_) => Ok(()),
+        None => Err(not_found(field, format!("Post {post_id} does not exist"))),
+    }
+}
+
+/// Content, tags and parent of a new post
+pub async fn validate_new_post(
+    content: &str,
+    tags: &[String],
+    parent_id: Option<i64>,
+    repo: &dyn Repository,
+) -> Result<()> {
+    validate_content(content)?;
+    validate_tags(tags)?;
+    if let Some(parent_id) = parent_id {
+        validate_post_exists("parent_id", parent_id, repo).await?;
+    }
+    Ok(())
+}
+
+/// The post must exist, and the note must be a (possibly indirect) reply to it
+pub async fn validate_vote(
+    tag: &str,
+    post_id: i64,
+    note_id: Option<i64>,
+    repo: &dyn Repository,
+) -> Result<()> {
+    validate_tag(tag)?;
+    validate_post_exists("post_id", post_id, repo).await?;
+    if let Some(note_id) = note_id {
+        let note = repo
+            .get_post(note_id)
+            .await?
+            .ok_or_else(|| not_found("note_id", format!("Note {note_id} does not exist")))?;
+        let ancestors = repo.get_transitive_parents(&note).await?;
+        if !ancestors.iter().any(|ancestor| ancestor.id == post_id) {
+            return Err(ValidationError::BadRequest {
+                field: "note_id",
+                message: format!("Post {note_id} is not a reply to post {post_id}"),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Expects a normalized username, see [common::auth::normalize_username]. It must not be taken by
+/// another user than `user_id`.
+pub async fn validate_username(username: &str, user_id: i64, repo: &dyn Repository) -> Result<()> {
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(invalid(
+            "username",
+            format!("Usernames are {MIN_USERNAME_LENGTH} to {MAX_USERNAME_LENGTH} characters long"),
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+    {
+        return Err(invalid(
+            "username",
+            "Usernames may only contain letters, digits, - and _",
+        ));
+    }
+    if let Some((owner_id, _)) = repo.get_credentials(username).await? {
+        if owner_id != user_id {
+            return Err(conflict("username", format!("{username} is already taken")));
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<()> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(invalid(
+            "password",
+            format!("Passwords need at least {MIN_PASSWORD_LENGTH} characters"),
+        ));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(invalid(
+            "password",
+            format!("Passwords can have at most {MAX_PASSWORD_LENGTH} characters"),
+        ));
+    }
+    Ok(())
+}
+
+pub fn validate_api_token(name: &str, scopes: &[ApiScope]) -> Result<()> {
+    if name.trim().is_empty() {
+        return Err(invalid("name", "API tokens need a name"));
+    }
+    let length = name.chars().count();
+    if length > MAX_API_TOKEN_NAME_LENGTH {
+        return Err(invalid(
+            "name",
+            format!("Names can have at most {MAX_API_TOKEN_NAME_LENGTH} characters"),
+        ));
+    }
+    if scopes.is_empty() {
+        return Err(invalid("scopes", "Select at least one scope"));
+    }
+    Ok(())
+}
+
+/// Expects a trimmed name
+pub fn validate_display_name(display_name: &str) -> Result<()> {
+    let length = display_name.chars().count();
+    if length > MAX_DISPLAY_NAME_LENGTH {
+        return Err(invalid(
+            "display_name",
+            format!("Display names can have at most {MAX_DISPLAY_NAME_LENGTH} characters"),
+        ));
+    }
+    if display_name.chars().any(char::is_control) {
+        return Err(invalid(
+            "display_name",
+            "Display names cannot contain control characters",
+        ));
+    }
+    if is_pseudonym(display_name) {
+        return Err(invalid(
+            "display_name",
+            "This looks like a generated pseudonym, please choose another name",
+        ));
+    }
+    Ok(())
+}
+
+/// Users can follow and block anyone but themselves
+pub async fn validate_other_user(
+    user_id: i64,
+    other_user_id: i64,
+    repo: &dyn Repository,
+) -> Result<()> {
+    if user_id == other_user_id {
+        return Err(invalid("user_id", "This is your own account"));
+    }
+    if !repo.user_exists(other_user_id).await? {
+        return Err(not_found(
+            "user_id",
+            format!("User {other_user_id} does not exist"),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::pseudonym::pseudonym;
+    use common::repository::memory::MemoryRepository;
+    use http::StatusCode;
+
+    use super::*;
+
+    /// Field and status of the error that `result` failed with
+    fn failure(result: Result<()>) -> (&'static str, StatusCode) {
+        let error = result
+            .unwrap_err()
+            .downcast::<ValidationError>()
+            .expect("a validation error");
+        (error.field(), error.status())
+    }
+
+    #[test]
+    fn content_is_not_empty_and_within_the_limit() {
+        assert!(validate_content("hello").is_ok());
+        assert!(validate_content(&"ü".repeat(MAX_POST_LENGTH)).is_ok());
+        for content in ["", " \n\t", &"a".repeat(MAX_POST_LENGTH + 1)] {
+            assert_eq!(
+                failure(validate_content(content)),
+                ("content", StatusCode::UNPROCESSABLE_ENTITY)
+            );
+        }
+    }
+
+    #[test]
+    fn tags_are_words_within_the_limits() {
+        for tag in [
+            "science",
+            "über",
+            "rust-lang",
+            "c_2",
+            &"a".repeat(MAX_TAG_LENGTH),
+        ] {
+            assert!(validate_tag(tag).is_ok(), "{tag}");
+        }
+        for tag in ["", "c++", "a.b", "#tag", &"a".repeat(MAX_TAG_LENGTH + 1)] {
+            assert_eq!(
+                failure(validate_tag(tag)),
+                ("tags", StatusCode::UNPROCESSABLE_ENTITY),
+                "{tag}"
+            );
+        }
+
+        let tags = |count: usize| -> Vec<String> { (0..count).map(|i| format!("t{i}")).collect() };
+        assert!(validate_tags(&tags(1)).is_ok());
+        assert!(validate_tags(&tags(MAX_TAGS_PER_POST)).is_ok());
+        for tags in [
+            tags(0),
+            tags(MAX_TAGS_PER_POST + 1),
+            vec!["ok".to_string(), "not ok".to_string()],
+        ] {
+            assert_eq!(
+                failure(validate_tags(&tags)),
+                ("tags", StatusCode::UNPROCESSABLE_ENTITY),
+                "{tags:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn notes_are_replies_to_the_post() {
+        let repo = MemoryRepository::new();
+        let tags = ["test".to_string()];
+        let author = repo.create_user("author").await.unwrap();
+        let post = repo.create_post(&tags, None, "post", author).await.unwrap();
+        let note = repo
+            .create_post(&tags, Some(post), "note", author)
+            .await
+            .unwrap();
+        let subnote = repo
+            .create_post(&tags, Some(note), "subnote", author)
+            .await
+            .unwrap();
+        let other_post = repo
+            .create_post(&tags, None, "other", author)
+            .await
+            .unwrap();
+
+        assert!(validate_vote("test", post, None, &repo).await.is_ok());
+        assert!(validate_vote("test", post, Some(note), &repo).await.is_ok());
+        assert!(validate_vote("test", post, Some(subnote), &repo)
+            .await
+            .is_ok());
+
+        assert_eq!(
+            failure(validate_vote("test", post, Some(other_post), &repo).await),
+            ("note_id", StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            failure(validate_vote("test", note, Some(post), &repo).await),
+            ("note_id", StatusCode::BAD_REQUEST)
+        );
+        assert_eq!(
+            failure(validate_vote("test", post, Some(999), &repo).await),
+            ("note_id", StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            failure(validate_vote("test", 999, None, &repo).await),
+            ("post_id", StatusCode::NOT_FOUND)
+        );
+        assert_eq!(
+            failure(validate_vote("no tag", post, None, &repo).await),
+            ("tags", StatusCode::UNPROCESSABLE_ENTITY)
+        );
+    }
+
+    #[tokio::test]
+    async fn usernames_are_short_lowercase_and_not_taken() {
+        let repo = MemoryRepository::new();
+        let owner = repo.create_user("owner").await.unwrap();
+        let other = repo.create_user("other").await.unwrap();
+        repo.set_credentials(owner, "taken", "hash").await.unwrap();
+
+        for username in ["abc", "user_name-2", &"a".repeat(MAX_USERNAME_LENGTH)] {
+            assert!(validate_username(username, other, &repo).await.is_ok());
+        }
+        // users may keep their own username
+        assert!(validate_username("taken", owner, &repo).await.is_ok());
+
+        for username in [
+            "ab",
+            "Upper",
+            "with space",
+            "ü",
+            &"a".repeat(MAX_USERNAME_LENGTH + 1),
+        ] {
+            assert_eq!(
+                failure(validate_username(username, other, &repo).await),
+                ("username", StatusCode::UNPROCESSABLE_ENTITY),
+                "{username}"
+            );
+        }
+        assert_eq!(
+            failure(validate_username("taken", other, &repo).await),
+            ("username", StatusCode::CONFLICT)
+        );
+    }
+
+    #[test]
+    fn passwords_are_within_the_limits() {
+        let minimum = "ü".repeat(MIN_PASSWORD_LENGTH);
+        let maximum = "ü".repeat(MAX_PASSWORD_LENGTH);
+        assert!(validate_password(&minimum).is_ok());
+        assert!(validate_password(&maximum).is_ok());
+        for password in [
+            "a".repeat(MIN_PASSWORD_LENGTH - 1),
+            "a".repeat(MAX_PASSWORD_LENGTH + 1),
+        ] {
+            assert_eq!(
+                failure(validate_password(&password)),
+                ("password", StatusCode::UNPROCESSABLE_ENTITY)
+            );
+        }
+    }
+
+    #[test]
+    fn display_names_cannot_pass_as_pseudonyms() {
+        for name in ["Ada", "Ada Lovelace", &"ü".repeat(MAX_DISPLAY_NAME_LENGTH)] {
+            assert!(validate_display_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "a".repeat(MAX_DISPLAY_NAME_LENGTH + 1),
+            "Ada\u{7}".to_string(),
+            pseudonym(1),
+            pseudonym(2).to_lowercase(),
+        ] {
+            assert_eq!(
+                failure(validate_display_name(&name)),
+                ("display_name", StatusCode::UNPROCESSABLE_ENTITY),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn form_error_keeps_conflicts() {
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let mut connection = pool.acquire().await.unwrap();
+        sqlx::query("create table names (name text unique)")
+            .execute(&mut *connection)
+            .await
+            .unwrap();
+        let insert = "insert into names (name) values ('y')";
+        sqlx::query(insert).execute(&mut *connection).await.unwrap();
+        let unique_violation = sqlx::query(insert)
+            .execute(&mut *connection)
+            .await
+            .unwrap_err();
+
+        let result = form_error(unique_violation.into());
+        assert!(matches!(result, Err(AppError::Conflict(_))), "{result:?}");
+    }
+
+    #[test]
+    fn form_error_renders_validation_errors() {
+        let response = form_error(invalid("name", "Too long")).unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
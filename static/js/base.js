@@ -4,3 +4,24 @@ if (window.matchMedia && window.matchMedia('(prefers-color-scheme: dark)').match
     window.yDarkMode = true;
     console.log("Dark mode is: " + window.yDarkMode);
 }
+
+// htmx doesn't swap error responses. Boosted links and forms get an error page,
+// which is swapped like any other page. Validation errors (4xx) are shown in the
+// .form-errors element of the submitted form instead.
+document.addEventListener("htmx:beforeSwap", function (event) {
+    const status = event.detail.xhr.status;
+    if (status >= 400 && event.detail.boosted) {
+        event.detail.shouldSwap = true;
+        event.detail.isError = false;
+        return;
+    }
+    if (status < 400 || status >= 500) {
+        return;
+    }
+    const errors = event.detail.elt.querySelector(".form-errors");
+    if (errors) {
+        event.detail.shouldSwap = true;
+        event.detail.isError = false;
+        event.detail.target = errors;
+    }
+});